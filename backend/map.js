@@ -1,21 +1,31 @@
+// Shows where the isopod has been on a base map of the event site.  The base
+// maps and their calibration come from the backend's /maps endpoint, and the
+// map can be chosen with ?map=<id> in the page URL.  The track is loaded
+// from /track and then kept up to date from /live.
+
+const pageParams = new URLSearchParams(window.location.search);
+
+// The base map being shown, from the backend's map config
+let baseMap = null;
+let baseMapImage = null;
+
+// A list of lat-long pairs, each indicating a point on the path, the last
+// indicating the most-recent-known location.
+const isopodPath = [];
+
 // Render the base map.  Call the provided callback after the base map has loaded and drawn
 function renderBaseMap(callback) {
     // First, load the base map image
     const image = document.createElement("img");
-    image.src = "emf_base_map.png";
+    image.src = "maps/" + encodeURIComponent(baseMap.id) + "/image";
     // After the base map image loads...
     image.addEventListener("load", () => {
-        const imageWidth = image.width;
-        const imageHeight = image.height;
+        baseMapImage = image;
 
         // Resize the canvas to match the base map image
         const canvasElement = document.getElementById("canvas");
-        canvasElement.width = imageWidth;
-        canvasElement.height = imageHeight;
-
-        // Draw the image on the canvas
-        const context = canvasElement.getContext("2d");
-        context.drawImage(image, 0, 0, imageWidth, imageHeight);
+        canvasElement.width = image.width;
+        canvasElement.height = image.height;
 
         callback();
     });
@@ -23,11 +33,10 @@ function renderBaseMap(callback) {
 
 // Convert a decimal latitude and longitude to map pixel coordinates
 function latLongToPix(lat, long) {
-    // Calibration values worked out using google maps:
-    // Bottom left of map is:
-    const bottomLeft = [52.038889, -2.380556]; // Decimal degrees
-    const topRight = [52.044046, -2.374030];   // Decimal degrees
-    const mapSize = [994, 1275];               // Pixels
+    // Calibration values for the corners of the map, in decimal degrees
+    const bottomLeft = baseMap.bottom_left;
+    const topRight = baseMap.top_right;
+    const mapSize = [baseMapImage.width, baseMapImage.height]; // Pixels
 
     const xPixPerDegree = mapSize[0] / (topRight[1] - bottomLeft[1])
     const yPixPerDegree = mapSize[1] / (bottomLeft[0] - topRight[0])
@@ -40,16 +49,6 @@ function latLongToPix(lat, long) {
     return [xPix, yPix]
 }
 
-// A list of lat-long pairs, each indicating a point on the path, the last
-// indicating the most-recent-known location.
-const isopodPath = [
-    [52.041533, -2.378381],
-    [52.043078, -2.376879],
-    [52.043071, -2.375012],
-    [52.041368, -2.375227],
-    [52.039771, -2.375967],
-];
-
 function renderPath(isopodPath) {
     const canvasElement = document.getElementById("canvas");
     const context = canvasElement.getContext("2d");
@@ -95,8 +94,60 @@ function renderCurrentLocation(isopodLocation) {
     renderCross(context, locationPix[0], locationPix[1]);
 }
 
+// Redraw everything
+function render() {
+    const canvasElement = document.getElementById("canvas");
+    const context = canvasElement.getContext("2d");
+    context.drawImage(baseMapImage, 0, 0, baseMapImage.width, baseMapImage.height);
 
-renderBaseMap(() => {
     renderPath(isopodPath);
-    renderCurrentLocation(isopodPath[isopodPath.length - 1]);
-});
+    if (isopodPath.length > 0) {
+        renderCurrentLocation(isopodPath[isopodPath.length - 1]);
+    }
+}
+
+function showLastSeen(time) {
+    document.getElementById("status").textContent =
+        baseMap.name + ": last seen " + new Date(time).toLocaleString();
+}
+
+// Add a position from the backend to the path
+function addPosition(position) {
+    isopodPath.push([position.latitude, position.longitude]);
+    showLastSeen(position.time);
+}
+
+async function start() {
+    const config = await (await fetch("maps")).json();
+    const id = pageParams.get("map") || config.default;
+    if (!(id in config.maps)) {
+        document.getElementById("status").textContent = "No such map: " + id;
+        return;
+    }
+    baseMap = { id: id, ...config.maps[id] };
+
+    // Pass on the time range and device, if given
+    const query = new URLSearchParams();
+    for (const name of ["device", "from", "to"]) {
+        if (pageParams.has(name)) {
+            query.set(name, pageParams.get(name));
+        }
+    }
+
+    renderBaseMap(async () => {
+        const track = await (await fetch("track?" + query)).json();
+        track.forEach(addPosition);
+        render();
+
+        // Follow along live, unless we were asked for a fixed time range
+        if (!pageParams.has("to")) {
+            const live = new EventSource("live?" + query);
+            live.addEventListener("position", (event) => {
+                addPosition(JSON.parse(event.data));
+                render();
+            });
+        }
+    });
+}
+
+start();
@@ -1,19 +1,28 @@
-# Bottom left of map is:
-# Latitude:52°2'20"N Longitude:2°22'50"W
-# 52.038889, -2.380556
+# Convert latitude and longitude to pixel coordinates on a base map, using the
+# calibration in maps.json, the same way as map.js does.
 #
-# Top right of map is:
-# Latitude:52°2'39"N Longitude:2°22'26"W
-# 52.044046,-2.374030
-#
-# Base map is 994 pixels wide and 1275 pixels tall
+# Usage: python3 map_location.py [map id]
+
+import json
+import struct
+import sys
+
 
+def png_size(path):
+    with open(path, "rb") as f:
+        header = f.read(24)
+    width, height = struct.unpack(">II", header[16:24])
+    return width, height
 
-def latlong_to_pix(longitude, latitude):
-    x_pix_per_degree = 994 / (-2.374030 - -2.380556)
-    y_pix_per_degree = 1275 / (52.038889 - 52.044046)
-    x_degree_offset = -2.380556
-    y_degree_offset = 52.044046
+
+def latlong_to_pix(base_map, map_size, longitude, latitude):
+    bottom_left = base_map["bottom_left"]
+    top_right = base_map["top_right"]
+
+    x_pix_per_degree = map_size[0] / (top_right[1] - bottom_left[1])
+    y_pix_per_degree = map_size[1] / (bottom_left[0] - top_right[0])
+    x_degree_offset = bottom_left[1]
+    y_degree_offset = top_right[0]
 
     x_pix = (longitude - x_degree_offset) * x_pix_per_degree
     y_pix = (latitude - y_degree_offset) * y_pix_per_degree
@@ -21,7 +30,12 @@ def latlong_to_pix(longitude, latitude):
     return x_pix, y_pix
 
 
-# Test: The triangle next to first aid is at:
+with open("maps.json") as f:
+    config = json.load(f)
+base_map = config["maps"][sys.argv[1] if len(sys.argv) > 1 else config["default"]]
+map_size = png_size(base_map["image"])
+
+# Test: The triangle next to first aid at EMF 2022 is at:
 latitude = 52.04165543846885
 longitude = -2.377864785129338
-print(latlong_to_pix(longitude, latitude))
+print(latlong_to_pix(base_map, map_size, longitude, latitude))
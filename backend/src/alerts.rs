@@ -0,0 +1,480 @@
+//! Tells the crew when an isopod needs attention: when it leaves the site,
+//! its battery is running low, it's overheating, or it has gone quiet.  The
+//! rules are given in a JSON file like:
+//!
+//! ```json
+//! {
+//!     "rules": {
+//!         "geofence": [[52.0389, -2.3806], [52.0440, -2.3806], [52.0440, -2.3740]],
+//!         "min_soc": 20,
+//!         "max_temperature": 75,
+//!         "report_interval": 60,
+//!         "missed_reports": 5
+//!     },
+//!     "devices": {
+//!         "isopod2": { "min_soc": 30 }
+//!     },
+//!     "notifiers": [
+//!         { "type": "log" },
+//!         { "type": "webhook", "url": "https://example.com/hook" }
+//!     ]
+//! }
+//! ```
+//!
+//! All the rules are optional.  `geofence` is a polygon of `[latitude,
+//! longitude]` corners which the isopod should stay inside.  An isopod which
+//! goes `missed_reports` times `report_interval` seconds without reporting
+//! has gone quiet.  `devices` overrides rules for particular isopods.  See
+//! notifiers.rs for the notifiers, which default to just logging.
+//!
+//! Each alert is sent once when it starts and again when it's resolved,
+//! rather than on every report.
+
+use crate::notifiers::{Notifier, NotifierConfig};
+use crate::report::Packet;
+use chrono::{DateTime, Duration, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+/// Rules for when to alert.  Any which aren't given aren't checked.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    geofence: Option<Vec<[f64; 2]>>,
+    min_soc: Option<f32>,
+    max_temperature: Option<f32>,
+    report_interval: Option<u64>,
+    missed_reports: Option<u32>,
+}
+
+impl Rules {
+    /// These rules, with any given in `overrides` taking precedence
+    fn merged(&self, overrides: &Rules) -> Rules {
+        Rules {
+            geofence: overrides.geofence.clone().or_else(|| self.geofence.clone()),
+            min_soc: overrides.min_soc.or(self.min_soc),
+            max_temperature: overrides.max_temperature.or(self.max_temperature),
+            report_interval: overrides.report_interval.or(self.report_interval),
+            missed_reports: overrides.missed_reports.or(self.missed_reports),
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if matches!(self.geofence, Some(ref x) if x.len() < 3) {
+            return Err("geofence needs at least 3 corners".to_owned());
+        }
+        if self.missed_reports.is_some() && self.report_interval.is_none() {
+            return Err("missed_reports needs report_interval".to_owned());
+        }
+        Ok(())
+    }
+
+    /// How long an isopod can go without reporting before it has gone quiet
+    fn silence_limit(&self) -> Option<Duration> {
+        let interval = self.report_interval? as i64;
+        Some(Duration::seconds(interval * self.missed_reports.unwrap_or(1) as i64))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AlertConfig {
+    #[serde(default)]
+    rules: Rules,
+    #[serde(default)]
+    devices: BTreeMap<String, Rules>,
+    #[serde(default = "default_notifiers")]
+    notifiers: Vec<NotifierConfig>,
+}
+
+fn default_notifiers() -> Vec<NotifierConfig> {
+    vec![NotifierConfig::Log]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    Geofence,
+    LowBattery,
+    HighTemperature,
+    Silence,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    /// The problem has just started
+    Firing,
+    /// The problem has gone away
+    Resolved,
+}
+
+/// An alert as sent to the notifiers
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub device: String,
+    pub kind: AlertKind,
+    pub state: AlertState,
+    pub message: String,
+    pub time: DateTime<Utc>,
+}
+
+impl Alert {
+    /// One line description of the alert
+    pub fn summary(&self) -> String {
+        let state = match self.state {
+            AlertState::Firing => "",
+            AlertState::Resolved => "resolved: ",
+        };
+        format!("{} {}{}", self.device, state, self.message)
+    }
+}
+
+/// Whether a point is inside a polygon, both as `[latitude, longitude]`
+fn inside(point: [f64; 2], polygon: &[[f64; 2]]) -> bool {
+    // Count how many edges a line heading east from the point crosses
+    let [y, x] = point;
+    let mut inside = false;
+    let mut j = polygon.len() - 1;
+    for i in 0..polygon.len() {
+        let [yi, xi] = polygon[i];
+        let [yj, xj] = polygon[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+pub struct Alerter {
+    config: AlertConfig,
+    /// Alerts which are currently firing
+    firing: Mutex<BTreeSet<(String, AlertKind)>>,
+    /// When each device's last report arrived, by our clock
+    last_seen: Mutex<BTreeMap<String, DateTime<Utc>>>,
+    /// When each device's latest checked report was made, by its clock
+    latest_report: Mutex<BTreeMap<String, DateTime<Utc>>>,
+    /// Alerts waiting to go to the notifiers
+    tx: Mutex<mpsc::Sender<Alert>>,
+}
+
+impl Alerter {
+    /// Load the alert config and start the notifiers.  Without a config file
+    /// there are no rules, so no alerts.
+    pub fn start(path: &Path) -> Result<Self, String> {
+        let config = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("can't parse {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                println!("No {}, so no alerts", path.display());
+                AlertConfig {
+                    rules: Rules::default(),
+                    devices: BTreeMap::new(),
+                    notifiers: vec![],
+                }
+            }
+            Err(e) => return Err(format!("can't read {}: {}", path.display(), e)),
+        };
+        let notifiers = config.notifiers.iter().map(|x| x.build()).collect();
+        Self::new(config, notifiers)
+    }
+
+    /// Check the alert config and start the notifiers
+    fn new(config: AlertConfig, notifiers: Vec<Box<dyn Notifier>>) -> Result<Self, String> {
+        config.rules.check()?;
+        for (device, rules) in &config.devices {
+            // Overrides only need to make sense alongside the other rules
+            config
+                .rules
+                .merged(rules)
+                .check()
+                .map_err(|e| format!("device {:?}: {}", device, e))?;
+        }
+
+        // Notifying can be slow, so it's done on its own thread
+        let (tx, rx) = mpsc::channel::<Alert>();
+        thread::Builder::new()
+            .name("notifier".into())
+            .spawn(move || {
+                for alert in rx {
+                    for notifier in &notifiers {
+                        if let Err(e) = notifier.notify(&alert) {
+                            eprintln!("Failed to send alert: {}", e);
+                        }
+                    }
+                }
+            })
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            config,
+            firing: Mutex::new(BTreeSet::new()),
+            last_seen: Mutex::new(BTreeMap::new()),
+            latest_report: Mutex::new(BTreeMap::new()),
+            tx: Mutex::new(tx),
+        })
+    }
+
+    fn rules(&self, device: &str) -> Rules {
+        match self.config.devices.get(device) {
+            Some(overrides) => self.config.rules.merged(overrides),
+            None => self.config.rules.clone(),
+        }
+    }
+
+    /// Note whether an alert's problem is happening, and notify if that has
+    /// changed
+    fn update(&self, device: &str, kind: AlertKind, problem: bool, message: String) {
+        let key = (device.to_owned(), kind);
+        let mut firing = self.firing.lock().unwrap();
+        let state = match (problem, firing.contains(&key)) {
+            (true, false) => AlertState::Firing,
+            (false, true) => AlertState::Resolved,
+            _ => return,
+        };
+        if problem {
+            firing.insert(key);
+        } else {
+            firing.remove(&key);
+        }
+
+        let alert = Alert {
+            device: device.to_owned(),
+            kind,
+            state,
+            message,
+            time: Utc::now(),
+        };
+        // The notifier thread only stops if it panics
+        if self.tx.lock().unwrap().send(alert).is_err() {
+            eprintln!("Notifier has stopped, alert not sent");
+        }
+    }
+
+    /// Remember when a device's last report arrived, e.g. from the database
+    /// at start-up
+    pub fn seen(&self, device: &str, time: DateTime<Utc>) {
+        self.last_seen.lock().unwrap().insert(device.to_owned(), time);
+    }
+
+    /// Check the rules against a new report from a device.  Reports made
+    /// before one already checked are ignored, as they're out of date.
+    pub fn check_report(&self, device: &str, packet: &Packet) {
+        // Silence goes by when reports arrive, as the isopod's clock may be
+        // wrong
+        let now = Utc::now();
+        self.last_seen.lock().unwrap().insert(device.to_owned(), now);
+        let rules = self.rules(device);
+        if rules.silence_limit().is_some() {
+            self.update(device, AlertKind::Silence, false, "reporting again".to_owned());
+        }
+
+        // Reports queued on the isopod may arrive after newer ones.  A time
+        // in the future is wrong, and would make every later report look
+        // stale.
+        if let Some(time) = packet.time.filter(|x| *x <= now) {
+            let mut latest_report = self.latest_report.lock().unwrap();
+            if latest_report.get(device).is_some_and(|x| time < *x) {
+                return;
+            }
+            latest_report.insert(device.to_owned(), time);
+        }
+
+        if let (Some(geofence), Some(gps)) = (&rules.geofence, &packet.gps) {
+            let outside = !inside([gps.latitude, gps.longitude], geofence);
+            let message = format!(
+                "at {},{}, {} the geofence",
+                gps.latitude,
+                gps.longitude,
+                if outside { "outside" } else { "back inside" }
+            );
+            self.update(device, AlertKind::Geofence, outside, message);
+        }
+        if let Some(min_soc) = rules.min_soc {
+            let soc = packet.battery.soc;
+            let message = format!("battery at {:.0}%, alert below {:.0}%", soc, min_soc);
+            self.update(device, AlertKind::LowBattery, soc < min_soc, message);
+        }
+        if let (Some(max_temperature), Some(temperature)) = (rules.max_temperature, packet.temperature) {
+            let message = format!(
+                "temperature {:.1}C, alert above {:.1}C",
+                temperature, max_temperature
+            );
+            self.update(device, AlertKind::HighTemperature, temperature > max_temperature, message);
+        }
+    }
+
+    /// Check whether any devices have gone quiet
+    pub fn check_silence(&self) {
+        let last_seen = self.last_seen.lock().unwrap().clone();
+        for (device, time) in last_seen {
+            if let Some(limit) = self.rules(&device).silence_limit() {
+                if Utc::now() - time > limit {
+                    let message = format!("no reports since {}", time.format("%Y-%m-%d %H:%M:%S UTC"));
+                    self.update(&device, AlertKind::Silence, true, message);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report;
+    use serde_json::json;
+
+    /// Passes alerts back to the test
+    struct StubNotifier(Mutex<mpsc::Sender<Alert>>);
+
+    impl Notifier for StubNotifier {
+        fn notify(&self, alert: &Alert) -> Result<(), String> {
+            self.0.lock().unwrap().send(alert.clone()).map_err(|e| e.to_string())
+        }
+    }
+
+    const FIELD: [[f64; 2]; 4] = [[52.0, -2.4], [52.1, -2.4], [52.1, -2.3], [52.0, -2.3]];
+
+    fn alerter(config: serde_json::Value) -> (Alerter, mpsc::Receiver<Alert>) {
+        let (tx, rx) = mpsc::channel();
+        let config: AlertConfig = serde_json::from_value(config).unwrap();
+        let alerter = Alerter::new(config, vec![Box::new(StubNotifier(Mutex::new(tx)))]).unwrap();
+        (alerter, rx)
+    }
+
+    /// The alerts sent so far, as (device, kind, state)
+    fn sent(rx: &mpsc::Receiver<Alert>) -> Vec<(String, AlertKind, AlertState)> {
+        let mut alerts = Vec::new();
+        // Notifying happens on another thread
+        while let Ok(alert) = rx.recv_timeout(std::time::Duration::from_millis(200)) {
+            alerts.push((alert.device, alert.kind, alert.state));
+        }
+        alerts
+    }
+
+    fn packet(time: &str, latitude: f64, soc: f32) -> Packet {
+        let (packet, _) = report::parse(json!({
+            "version": 4,
+            "time": time,
+            "gps": { "latitude": latitude, "longitude": -2.35 },
+            "battery": { "voltage": 14.8, "current": -1.2, "soc": soc },
+            "temperature": 50.0,
+        }))
+        .unwrap();
+        packet
+    }
+
+    #[test]
+    fn point_in_polygon() {
+        assert!(inside([52.05, -2.35], &FIELD));
+        assert!(!inside([52.15, -2.35], &FIELD));
+        assert!(!inside([52.05, -2.45], &FIELD));
+        assert!(!inside([51.0, 0.0], &FIELD));
+
+        // A concave polygon, shaped like a C opening to the east
+        let c = [[0.0, 0.0], [3.0, 0.0], [3.0, 3.0], [2.0, 3.0], [2.0, 1.0], [1.0, 1.0], [1.0, 3.0], [0.0, 3.0]];
+        assert!(inside([0.5, 2.0], &c));
+        assert!(inside([1.5, 0.5], &c));
+        assert!(!inside([1.5, 2.0], &c));
+    }
+
+    #[test]
+    fn rule_overrides() {
+        let (alerter, _) = alerter(json!({
+            "rules": { "min_soc": 20, "max_temperature": 75, "report_interval": 60 },
+            "devices": { "isopod2": { "min_soc": 30, "missed_reports": 5 } },
+        }));
+        let rules = alerter.rules("isopod2");
+        assert_eq!(rules.min_soc, Some(30.0));
+        assert_eq!(rules.max_temperature, Some(75.0));
+        assert_eq!(rules.silence_limit(), Some(Duration::seconds(300)));
+        let rules = alerter.rules("isopod3");
+        assert_eq!(rules.min_soc, Some(20.0));
+        assert_eq!(rules.silence_limit(), Some(Duration::seconds(60)));
+    }
+
+    #[test]
+    fn bad_rules() {
+        let check = |config| Alerter::new(serde_json::from_value(config).unwrap(), vec![]).map(|_| ());
+        let geofence = [[52.0, -2.4], [52.1, -2.4]];
+        assert!(check(json!({ "devices": { "isopod2": { "geofence": geofence } } })).is_err());
+        assert!(check(json!({ "devices": { "isopod2": { "missed_reports": 3 } } })).is_err());
+
+        // Overrides are checked along with the rules they override
+        let config = json!({
+            "rules": { "report_interval": 60 },
+            "devices": { "isopod2": { "missed_reports": 3 } },
+        });
+        assert!(check(config).is_ok());
+
+        assert!(serde_json::from_value::<AlertConfig>(json!({ "rules": { "min_sco": 3 } })).is_err());
+    }
+
+    #[test]
+    fn firing_and_resolved() {
+        let (alerter, rx) = alerter(json!({ "rules": { "geofence": FIELD, "min_soc": 20 } }));
+        use AlertKind::*;
+        use AlertState::*;
+
+        alerter.check_report("isopod", &packet("2022-06-02T18:00:00Z", 52.05, 50.0));
+        assert_eq!(sent(&rx), []);
+
+        // Each problem is only sent once while it lasts
+        alerter.check_report("isopod", &packet("2022-06-02T18:01:00Z", 52.15, 10.0));
+        alerter.check_report("isopod", &packet("2022-06-02T18:02:00Z", 52.15, 10.0));
+        assert_eq!(
+            sent(&rx),
+            [("isopod".to_owned(), Geofence, Firing), ("isopod".to_owned(), LowBattery, Firing)]
+        );
+
+        alerter.check_report("isopod", &packet("2022-06-02T18:03:00Z", 52.05, 10.0));
+        assert_eq!(sent(&rx), [("isopod".to_owned(), Geofence, Resolved)]);
+
+        // Alerts are kept separately for each device
+        alerter.check_report("isopod2", &packet("2022-06-02T18:03:00Z", 52.05, 10.0));
+        assert_eq!(sent(&rx), [("isopod2".to_owned(), LowBattery, Firing)]);
+    }
+
+    #[test]
+    fn stale_reports_ignored() {
+        let (alerter, rx) = alerter(json!({ "rules": { "min_soc": 20 } }));
+        alerter.check_report("isopod", &packet("2022-06-02T18:05:00Z", 52.05, 50.0));
+        alerter.check_report("isopod", &packet("2022-06-02T18:00:00Z", 52.05, 10.0));
+        assert_eq!(sent(&rx), []);
+        let latest_report = alerter.latest_report.lock().unwrap()["isopod"];
+        assert_eq!(latest_report, packet("2022-06-02T18:05:00Z", 0.0, 0.0).time.unwrap());
+
+        // A report from the future doesn't hold back later ones.  The parser
+        // drops such times, so this one is set directly.
+        let mut future = packet("2022-06-02T18:05:00Z", 52.05, 50.0);
+        future.time = Some(Utc::now() + Duration::days(1));
+        alerter.check_report("isopod", &future);
+        alerter.check_report("isopod", &packet("2022-06-02T18:10:00Z", 52.05, 10.0));
+        assert_eq!(sent(&rx), [("isopod".to_owned(), AlertKind::LowBattery, AlertState::Firing)]);
+    }
+
+    #[test]
+    fn silence() {
+        let (alerter, rx) = alerter(json!({ "rules": { "report_interval": 60, "missed_reports": 5 } }));
+        use AlertKind::*;
+        use AlertState::*;
+
+        alerter.seen("isopod", Utc::now() - Duration::seconds(200));
+        alerter.check_silence();
+        assert_eq!(sent(&rx), []);
+
+        alerter.seen("isopod", Utc::now() - Duration::seconds(400));
+        alerter.check_silence();
+        alerter.check_silence();
+        assert_eq!(sent(&rx), [("isopod".to_owned(), Silence, Firing)]);
+
+        // Reports count when they arrive, even if the isopod's clock is
+        // behind
+        alerter.check_report("isopod", &packet("2022-06-02T18:00:00Z", 52.05, 50.0));
+        alerter.check_silence();
+        assert_eq!(sent(&rx), [("isopod".to_owned(), Silence, Resolved)]);
+    }
+}
@@ -0,0 +1,58 @@
+//! Commands for the isopods, queued by the crew with `POST /commands` and
+//! handed to each isopod in our reply to its next upload.  A command is
+//! handed over again with every upload until the isopod acknowledges it in a
+//! report.  These structs must be kept in step with the isopod's
+//! commands.rs.
+//!
+//! Queueing commands needs the crew's key, set with `ISOPOD_CREW_KEY` and
+//! sent as a bearer token.  Without it commands can't be queued at all.
+//! Listing them needs the crew's key or the isopod's own, see devices.rs.
+
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    Pattern { name: String },
+    Brightness { value: u8 },
+    /// Send a report straight away
+    StatusReport,
+    /// Report more often, to help find a lost isopod
+    LostMode { enabled: bool },
+}
+
+/// A command as handed to an isopod
+#[derive(Debug, Serialize)]
+pub struct QueuedCommand {
+    pub id: i64,
+    pub command: Command,
+}
+
+/// Our reply to an upload
+#[derive(Debug, Serialize)]
+pub struct UploadReply {
+    pub commands: Vec<QueuedCommand>,
+}
+
+/// An isopod's report of whether it carried out a command
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Ack {
+    pub id: i64,
+    pub ok: bool,
+    pub message: String,
+}
+
+/// A command and how it got on, for the crew
+#[derive(Debug, Serialize)]
+pub struct CommandStatus {
+    pub id: i64,
+    pub device: String,
+    pub command: Command,
+    pub queued: DateTime<Utc>,
+    /// When it was last handed to the isopod
+    pub sent: Option<DateTime<Utc>>,
+    pub acked: Option<DateTime<Utc>>,
+    pub ok: Option<bool>,
+    pub message: Option<String>,
+}
@@ -0,0 +1,82 @@
+//! The isopods allowed to report to us, and their API keys.  They're listed
+//! in a JSON file of device IDs and keys like:
+//!
+//! ```json
+//! {
+//!     "isopod": { "key": "a long random string" },
+//!     "isopod2": { "key": "another long random string" }
+//! }
+//! ```
+//!
+//! Each isopod sends its key as a bearer token, which tells us which device
+//! the reports are from.  Without the file anyone can report, and the device
+//! is whatever the report says it is.
+
+use crate::storage::DEFAULT_DEVICE;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Deserialize)]
+struct Device {
+    key: String,
+}
+
+pub struct Devices {
+    /// Registered devices, or None if anyone can report
+    devices: Option<BTreeMap<String, Device>>,
+}
+
+impl Devices {
+    /// Load the registered devices, if the file exists
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                println!("No {}, so accepting reports from anyone", path.display());
+                return Ok(Self { devices: None });
+            }
+            Err(e) => return Err(format!("can't read {}: {}", path.display(), e)),
+        };
+        let devices: BTreeMap<String, Device> = serde_json::from_str(&contents)
+            .map_err(|e| format!("can't parse {}: {}", path.display(), e))?;
+        for (id, device) in &devices {
+            if device.key.is_empty() {
+                return Err(format!("device {:?} has an empty key", id));
+            }
+        }
+        Ok(Self {
+            devices: Some(devices),
+        })
+    }
+
+    /// IDs of the registered devices
+    pub fn ids(&self) -> Vec<String> {
+        self.devices
+            .as_ref()
+            .map(|x| x.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether devices have keys, which they don't without a devices file
+    pub fn have_keys(&self) -> bool {
+        self.devices.is_some()
+    }
+
+    /// Work out which device is reporting, from the authorization header and
+    /// the device the report says it's from.  Returns None if it isn't
+    /// allowed to report as that device.
+    pub fn authenticate(&self, auth: Option<&str>, claimed: Option<&str>) -> Option<String> {
+        let devices = match self.devices {
+            Some(ref x) => x,
+            None => return Some(claimed.unwrap_or(DEFAULT_DEVICE).to_owned()),
+        };
+
+        let key = auth?.strip_prefix("Bearer ")?;
+        let (id, _) = devices.iter().find(|(_, device)| device.key == key)?;
+        match claimed {
+            Some(claimed) if claimed != id => None,
+            _ => Some(id.clone()),
+        }
+    }
+}
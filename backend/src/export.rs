@@ -0,0 +1,283 @@
+//! Exports a device's history as files to download: its track as GPX or
+//! GeoJSON, and all its telemetry as CSV.
+//!
+//! Tracks can be filtered with `min_satellites`, which drops positions from
+//! fixes with fewer satellites, and `min_distance`, which drops positions
+//! within that many metres of the last one kept, so an isopod sitting still
+//! for hours doesn't leave a pile of points behind.
+
+use crate::storage::TelemetryRow;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_derive::Deserialize;
+use std::fmt::Write;
+
+/// Mean radius of the earth in metres
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// Query parameters for filtering tracks
+#[derive(Debug, Default, Deserialize)]
+pub struct TrackFilter {
+    min_satellites: Option<i64>,
+    min_distance: Option<f64>,
+}
+
+/// A point on an exported track
+pub struct ExportPoint<'a> {
+    latitude: f64,
+    longitude: f64,
+    row: &'a TelemetryRow,
+}
+
+/// Distance in metres between two points, by the haversine formula
+fn distance(a: &ExportPoint, b: &ExportPoint) -> f64 {
+    let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_long = (b.longitude - a.longitude).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_long / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().asin()
+}
+
+/// The positions in some telemetry which pass the filter
+pub fn track<'a>(rows: &'a [TelemetryRow], filter: &TrackFilter) -> Vec<ExportPoint<'a>> {
+    let mut points: Vec<ExportPoint> = Vec::new();
+    for row in rows {
+        let (latitude, longitude) = match (row.latitude, row.longitude) {
+            (Some(latitude), Some(longitude)) => (latitude, longitude),
+            _ => continue,
+        };
+        if let Some(min_satellites) = filter.min_satellites {
+            if row.satellites.unwrap_or(0) < min_satellites {
+                continue;
+            }
+        }
+        let point = ExportPoint {
+            latitude,
+            longitude,
+            row,
+        };
+        if let (Some(min_distance), Some(last)) = (filter.min_distance, points.last()) {
+            if distance(last, &point) < min_distance {
+                continue;
+            }
+        }
+        points.push(point);
+    }
+    points
+}
+
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A track as a GPX 1.1 file
+pub fn gpx(device: &str, points: &[ExportPoint]) -> String {
+    // Writing to a String can't fail
+    let mut gpx = String::new();
+    gpx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    gpx.push_str("<gpx version=\"1.1\" creator=\"isopod_backend\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n");
+    let _ = writeln!(gpx, "  <trk>\n    <name>{}</name>\n    <trkseg>", xml_escape(device));
+    for point in points {
+        let _ = writeln!(gpx, "      <trkpt lat=\"{}\" lon=\"{}\">", point.latitude, point.longitude);
+        if let Some(altitude) = point.row.altitude {
+            let _ = writeln!(gpx, "        <ele>{}</ele>", altitude);
+        }
+        let _ = writeln!(gpx, "        <time>{}</time>", format_time(&point.row.time));
+        if let Some(satellites) = point.row.satellites {
+            let _ = writeln!(gpx, "        <sat>{}</sat>", satellites);
+        }
+        if let Some(hdop) = point.row.hdop {
+            let _ = writeln!(gpx, "        <hdop>{}</hdop>", hdop);
+        }
+        gpx.push_str("      </trkpt>\n");
+    }
+    gpx.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+    gpx
+}
+
+/// A track as a GeoJSON line, with the time of each point in the
+/// `coordinateProperties` as understood by most GeoJSON tools
+pub fn geojson(device: &str, points: &[ExportPoint]) -> serde_json::Value {
+    let coordinates: Vec<serde_json::Value> = points
+        .iter()
+        .map(|point| match point.row.altitude {
+            Some(altitude) => serde_json::json!([point.longitude, point.latitude, altitude]),
+            None => serde_json::json!([point.longitude, point.latitude]),
+        })
+        .collect();
+    let times: Vec<String> = points.iter().map(|point| format_time(&point.row.time)).collect();
+    serde_json::json!({
+        "type": "FeatureCollection",
+        "features": [{
+            "type": "Feature",
+            "geometry": { "type": "LineString", "coordinates": coordinates },
+            "properties": {
+                "device": device,
+                "coordinateProperties": { "times": times },
+            },
+        }],
+    })
+}
+
+/// Quote a CSV field if it needs it
+fn csv_field<T: ToString>(value: Option<T>) -> String {
+    let value = value.map(|x| x.to_string()).unwrap_or_default();
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// Quote a CSV text field, making sure spreadsheets don't take it for a
+/// formula
+fn csv_text(value: Option<&String>) -> String {
+    match value {
+        Some(x) if x.starts_with(['=', '+', '-', '@', '\t', '\r']) => csv_field(Some(format!("'{}", x))),
+        x => csv_field(x),
+    }
+}
+
+/// All of a device's telemetry as CSV, one report per line
+pub fn csv(rows: &[TelemetryRow]) -> String {
+    let mut csv = String::from(
+        "time,latitude,longitude,altitude,satellites,hdop,voltage,current,soc,\
+         temperature,wifi_signal,pattern,brightness,fps\n",
+    );
+    for row in rows {
+        let fields = [
+            csv_field(Some(format_time(&row.time))),
+            csv_field(row.latitude),
+            csv_field(row.longitude),
+            csv_field(row.altitude),
+            csv_field(row.satellites),
+            csv_field(row.hdop),
+            csv_field(Some(row.voltage)),
+            csv_field(Some(row.current)),
+            csv_field(Some(row.soc)),
+            csv_field(row.temperature),
+            csv_field(row.wifi_signal),
+            csv_text(row.pattern.as_ref()),
+            csv_field(row.brightness),
+            csv_field(row.fps),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Timelike};
+
+    fn row(minute: u32, position: Option<(f64, f64)>, satellites: Option<i64>) -> TelemetryRow {
+        TelemetryRow {
+            time: Utc.with_ymd_and_hms(2022, 6, 2, 18, minute, 0).unwrap(),
+            latitude: position.map(|x| x.0),
+            longitude: position.map(|x| x.1),
+            altitude: None,
+            satellites,
+            hdop: None,
+            voltage: 14.8,
+            current: -1.2,
+            soc: 76.5,
+            temperature: None,
+            wifi_signal: None,
+            pattern: None,
+            brightness: None,
+            fps: None,
+        }
+    }
+
+    fn minutes(points: &[ExportPoint]) -> Vec<u32> {
+        points.iter().map(|x| x.row.time.minute()).collect()
+    }
+
+    #[test]
+    fn haversine() {
+        let rows = [row(0, Some((52.0, -2.0)), None)];
+        let point = |latitude, longitude| ExportPoint {
+            latitude,
+            longitude,
+            row: &rows[0],
+        };
+        assert_eq!(distance(&point(52.0, -2.0), &point(52.0, -2.0)), 0.0);
+        // A degree of latitude is about 111km anywhere
+        let d = distance(&point(52.0, -2.0), &point(53.0, -2.0));
+        assert!((d - 111_195.0).abs() < 1.0, "{}", d);
+        // A degree of longitude is shorter away from the equator
+        let d = distance(&point(0.0, 0.0), &point(0.0, 1.0));
+        assert!((d - 111_195.0).abs() < 1.0, "{}", d);
+        let d = distance(&point(60.0, 0.0), &point(60.0, 1.0));
+        assert!((d - 55_597.0).abs() < 1.0, "{}", d);
+        // Across the antimeridian
+        let d = distance(&point(0.0, 179.5), &point(0.0, -179.5));
+        assert!((d - 111_195.0).abs() < 1.0, "{}", d);
+    }
+
+    #[test]
+    fn min_satellites() {
+        let rows = [
+            row(0, Some((52.0, -2.0)), Some(3)),
+            row(1, Some((52.0, -2.0)), Some(4)),
+            row(2, Some((52.0, -2.0)), None),
+            row(3, None, Some(9)),
+            row(4, Some((52.0, -2.0)), Some(8)),
+        ];
+        assert_eq!(minutes(&track(&rows, &TrackFilter::default())), [0, 1, 2, 4]);
+        let filter = TrackFilter {
+            min_satellites: Some(4),
+            ..TrackFilter::default()
+        };
+        assert_eq!(minutes(&track(&rows, &filter)), [1, 4]);
+    }
+
+    #[test]
+    fn min_distance() {
+        // About 11m apart in a line heading north
+        let rows: Vec<TelemetryRow> = (0..6).map(|x| row(x, Some((52.0 + x as f64 * 0.0001, -2.0)), None)).collect();
+        let filter = |min_distance| TrackFilter {
+            min_distance: Some(min_distance),
+            ..TrackFilter::default()
+        };
+        assert_eq!(minutes(&track(&rows, &filter(10.0))), [0, 1, 2, 3, 4, 5]);
+        // Distances are from the last point kept, not the last point seen
+        assert_eq!(minutes(&track(&rows, &filter(20.0))), [0, 2, 4]);
+        assert_eq!(minutes(&track(&rows, &filter(1000.0))), [0]);
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field(Some("glitch")), "glitch");
+        assert_eq!(csv_field(None::<String>), "");
+        assert_eq!(csv_field(Some(-1.5)), "-1.5");
+        assert_eq!(csv_field(Some("a,b")), "\"a,b\"");
+        assert_eq!(csv_field(Some("say \"hi\"")), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field(Some("a\nb")), "\"a\nb\"");
+    }
+
+    #[test]
+    fn csv_formulas() {
+        let text = |x: &str| csv_text(Some(&x.to_owned()));
+        assert_eq!(text("rainbow_swirl"), "rainbow_swirl");
+        assert_eq!(text("=1+1"), "'=1+1");
+        assert_eq!(text("+1"), "'+1");
+        assert_eq!(text("-1"), "'-1");
+        assert_eq!(text("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(text("=HYPERLINK(\"x\",\"y\")"), "\"'=HYPERLINK(\"\"x\"\",\"\"y\"\")\"");
+
+        let mut rows = vec![row(0, None, None)];
+        rows[0].pattern = Some("=cmd".to_owned());
+        let csv = csv(&rows);
+        let line = csv.lines().nth(1).unwrap();
+        assert_eq!(line, "2022-06-02T18:00:00Z,,,,,,14.8,-1.2,76.5,,,'=cmd,,");
+    }
+}
@@ -1,34 +1,549 @@
-use warp::Filter;
+//! Receives reports from a fleet of isopods, stores them, and answers queries
+//! about where they have been.  Configured with environment variables:
+//! * `ISOPOD_DEVICES`: the isopods allowed to report and their API keys, see
+//!   devices.rs, by default devices.json
+//! * `ISOPOD_DB`: the SQLite database to store reports in, by default
+//!   isopod.db
+//! * `ISOPOD_ASSETS`: directory containing the map page and base map images,
+//!   by default the current directory
+//! * `ISOPOD_MAPS`: the base map config, see maps.rs, by default maps.json
+//!   in the assets directory
+//! * `ISOPOD_ALERTS`: when to alert the crew and how, see alerts.rs, by
+//!   default alerts.json
+//! * `ISOPOD_CREW_KEY`: the key needed to queue commands, see commands.rs
+//!
+//! Endpoints:
+//! * `POST /isopod`: a report, or a batch of them.  The reply has any
+//!   commands for the isopod.
+//! * `POST /commands?device=...`: queue a command for an isopod
+//! * `GET /commands?device=...`: the commands queued for an isopod and
+//!   whether they've been carried out, given the isopod's key or the crew's.
+//!   Without a devices file only the crew's key will do.
+//! * `GET /`: a page showing the isopod's track on a map, for the map given
+//!   with `?map=...` or the default
+//! * `GET /maps`: the base maps and their calibration
+//! * `GET /maps/<id>/image`: a base map image
+//! * `GET /latest`: the latest report with a position
+//! * `GET /track?from=...&to=...`: positions between two times
+//! * `GET /battery?from=...&to=...`: battery readings between two times
+//! * `GET /live`: server-sent events with each new position as it arrives
+//! * `GET /fleet`: each isopod's last seen time, battery, position and
+//!   pattern
+//! * `GET /export/track.gpx`, `GET /export/track.geojson`: positions between
+//!   two times to download, see export.rs for filters
+//! * `GET /export/telemetry.csv`: all readings between two times to download
+//!
+//! Times are RFC 3339, e.g. `2022-06-02T18:00:00Z`, and either end of the
+//! range can be left out.  The page and the history endpoints take an
+//! optional `device`, by default "isopod".
+
+mod alerts;
+mod commands;
+mod devices;
+mod export;
+mod maps;
+mod notifiers;
+mod report;
+mod storage;
+
+use alerts::Alerter;
+use chrono::{DateTime, Utc};
+use commands::{Command, UploadReply};
+use devices::Devices;
+use export::TrackFilter;
+use futures_util::StreamExt;
+use maps::MapConfig;
+use report::Packet;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use storage::{Storage, TrackPoint, DEFAULT_DEVICE};
+use tokio::sync::broadcast;
+use warp::http::StatusCode;
+use warp::reply::{self, Reply};
+use warp::Filter;
+
+/// Number of positions to hold for each live client which falls behind
+const LIVE_BUFFER: usize = 64;
+
+/// How often to check for isopods which have gone quiet
+const SILENCE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A new position, as sent to live clients
+#[derive(Debug, Clone, Serialize)]
+struct LivePosition {
+    device: String,
+    #[serde(flatten)]
+    point: TrackPoint,
+}
+
+/// Start and end of a time range, either of which may be left open
+type TimeRange = (Option<DateTime<Utc>>, Option<DateTime<Utc>>);
+
+/// Query parameters for the history endpoints
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    device: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl HistoryQuery {
+    fn device(&self) -> &str {
+        self.device.as_deref().unwrap_or(DEFAULT_DEVICE)
+    }
+
+    /// Parse the time range, or describe what's wrong with it
+    fn range(&self) -> Result<TimeRange, String> {
+        let parse = |x: &Option<String>| {
+            x.as_deref()
+                .map(|x| {
+                    DateTime::parse_from_rfc3339(x)
+                        .map(|x| x.with_timezone(&Utc))
+                        .map_err(|e| format!("bad time {:?}: {}", x, e))
+                })
+                .transpose()
+        };
+        Ok((parse(&self.from)?, parse(&self.to)?))
+    }
+}
+
+/// Reply with the result of a query, or an error
+fn query_reply<T: serde::Serialize>(result: rusqlite::Result<T>) -> reply::Response {
+    match result {
+        Ok(x) => reply::json(&x).into_response(),
+        Err(e) => {
+            eprintln!("Query failed: {}", e);
+            reply::with_status("Query failed", StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        }
+    }
+}
+
+/// Export a device's history in one of the formats in export.rs
+fn export(storage: &Storage, file: &str, query: &HistoryQuery, filter: &TrackFilter) -> reply::Response {
+    let (from, to) = match query.range() {
+        Ok(x) => x,
+        Err(e) => return reply::with_status(e, StatusCode::BAD_REQUEST).into_response(),
+    };
+    let device = query.device();
+    let rows = match storage.telemetry(device, from, to) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Query failed: {}", e);
+            return reply::with_status("Query failed", StatusCode::INTERNAL_SERVER_ERROR).into_response();
+        }
+    };
+
+    let (body, content_type) = match file {
+        "track.gpx" => (export::gpx(device, &export::track(&rows, filter)), "application/gpx+xml"),
+        "track.geojson" => (
+            export::geojson(device, &export::track(&rows, filter)).to_string(),
+            "application/geo+json",
+        ),
+        "telemetry.csv" => (export::csv(&rows), "text/csv"),
+        _ => return reply::with_status("No such export", StatusCode::NOT_FOUND).into_response(),
+    };
+    // Devices are named by the crew, but keep the file name tame anyway
+    let name: String = device
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let disposition = format!("attachment; filename=\"{}-{}\"", name, file);
+    let reply = reply::with_header(body, "content-type", content_type);
+    reply::with_header(reply, "content-disposition", disposition).into_response()
+}
+
+/// Log and store a batch of reports, pass on new positions to live clients,
+/// check the latest report from each device for alerts, and reply with any
+/// commands waiting for the devices
+fn receive(
+    storage: &Storage,
+    live: &broadcast::Sender<LivePosition>,
+    devices: &Devices,
+    alerter: &Alerter,
+    auth: Option<&str>,
+    upload: serde_json::Value,
+) -> reply::Response {
+    let uploaded = match report::reports(upload) {
+        Some(x) => x,
+        None => {
+            return reply::with_status("Expected a report or a list of them", StatusCode::BAD_REQUEST).into_response()
+        }
+    };
+
+    // Check the whole batch is allowed before storing any of it
+    let mut claimed = Vec::new();
+    for report in uploaded {
+        match devices.authenticate(auth, report.get("device").and_then(|x| x.as_str())) {
+            Some(device) => claimed.push((device, report)),
+            None => return StatusCode::UNAUTHORIZED.into_response(),
+        }
+    }
+
+    // Reports we can't make sense of are dropped, as the isopod would only
+    // send them again
+    let mut reports = Vec::new();
+    for (device, report) in claimed {
+        match report::parse(report) {
+            Ok((packet, warnings)) => {
+                for warning in warnings {
+                    println!("Warning: report from {}: {}", device, warning);
+                }
+                reports.push((device, packet));
+            }
+            Err(e) => println!("Rejected report from {}: {}", device, e),
+        }
+    }
+
+    for (device, packet) in &reports {
+        println!("Rx from {}: {:#?}", device, packet);
+        if let Some(ref gps) = packet.gps {
+            println!("https://maps.google.com/?q={},{}", gps.latitude, gps.longitude);
+        }
+        println!();
+    }
+
+    let inserted = match storage.insert(&reports) {
+        Ok(x) => x,
+        Err(e) => {
+            // Let the isopod keep the reports and try again later
+            eprintln!("Failed to store reports: {}", e);
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    };
+
+    // Reports we already had were sent again because our reply went astray,
+    // and have already been passed on
+    let mut new_reports = Vec::new();
+    for ((device, packet), inserted) in reports.iter().zip(inserted) {
+        if !inserted.new {
+            println!("Already have the report from {} at {:?}", device, packet.time);
+            continue;
+        }
+        if let Some(point) = inserted.position {
+            // It doesn't matter if nobody is listening
+            let _ = live.send(LivePosition {
+                device: device.clone(),
+                point,
+            });
+        }
+
+        for ack in &packet.acks {
+            let result = if ack.ok { "done" } else { "failed" };
+            println!("Command {} for {} {}: {}", ack.id, device, result, ack.message);
+            match storage.ack_command(device, ack) {
+                Ok(true) => {}
+                Ok(false) => println!("Warning: command {} wasn't waiting for {}", ack.id, device),
+                Err(e) => eprintln!("Failed to store command ack: {}", e),
+            }
+        }
+        new_reports.push((device.as_str(), packet));
+    }
+
+    // Batches are oldest first, so older reports in them are already stale
+    let latest: BTreeMap<&str, &Packet> = new_reports.into_iter().collect();
+    for (device, packet) in latest {
+        alerter.check_report(device, packet);
+    }
+
+    // The reports are safely stored, so a failure here just means the
+    // commands wait for the next upload
+    let mut commands = Vec::new();
+    let batch_devices: BTreeSet<&str> = reports.iter().map(|(device, _)| device.as_str()).collect();
+    for device in batch_devices {
+        match storage.pending_commands(device) {
+            Ok(x) => {
+                if !x.is_empty() {
+                    println!("Sending {} commands to {}", x.len(), device);
+                }
+                commands.extend(x);
+            }
+            Err(e) => eprintln!("Failed to find commands for {}: {}", device, e),
+        }
+    }
+    reply::json(&UploadReply { commands }).into_response()
+}
+
+/// Queue a command from the crew
+fn queue_command(
+    storage: &Storage,
+    crew_key: Option<&str>,
+    auth: Option<&str>,
+    device: &str,
+    command: Command,
+) -> reply::Response {
+    let crew_key = match crew_key {
+        Some(x) => x,
+        None => {
+            return reply::with_status("Set ISOPOD_CREW_KEY to allow commands", StatusCode::FORBIDDEN)
+                .into_response()
+        }
+    };
+    if auth.and_then(|x| x.strip_prefix("Bearer ")) != Some(crew_key) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Packet {
-    long: f64,
-    lat: f64,
-    alt: f32,
-    time: String,
-    sats: usize,
-    voltage: f32,
-    current: f32,
-    soc: f32,
-    temp: String,
+    match storage.queue_command(device, &command) {
+        Ok(id) => {
+            println!("Queued command {} for {}: {:?}", id, device, command);
+            reply::json(&serde_json::json!({ "id": id })).into_response()
+        }
+        Err(e) => {
+            eprintln!("Failed to queue command: {}", e);
+            reply::with_status("Failed to queue command", StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        }
+    }
+}
+
+/// List the commands queued for a device, for the device itself or the crew.
+/// Without a devices file anyone could claim to be the device, so only the
+/// crew can.
+fn list_commands(
+    storage: &Storage,
+    devices: &Devices,
+    crew_key: Option<&str>,
+    auth: Option<&str>,
+    device: &str,
+) -> reply::Response {
+    let crew = crew_key.is_some() && auth.and_then(|x| x.strip_prefix("Bearer ")) == crew_key;
+    let own_device = devices.have_keys() && devices.authenticate(auth, Some(device)).is_some();
+    if !crew && !own_device {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    query_reply(storage.commands(device))
 }
 
 #[tokio::main]
 async fn main() {
+    let devices_path = std::env::var("ISOPOD_DEVICES").unwrap_or_else(|_| "devices.json".to_owned());
+    let devices = match Devices::load(devices_path.as_ref()) {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            eprintln!("Bad device list: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let fleet_devices = devices.ids();
+    let db_path = std::env::var("ISOPOD_DB").unwrap_or_else(|_| "isopod.db".to_owned());
+    let storage = match Storage::open(&db_path) {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            eprintln!("Can't open database {}: {}", db_path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let alerts_path = std::env::var("ISOPOD_ALERTS").unwrap_or_else(|_| "alerts.json".to_owned());
+    let alerter = match Alerter::start(alerts_path.as_ref()) {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            eprintln!("Bad alert config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // Devices which have reported before should carry on reporting
+    match storage.fleet(&fleet_devices) {
+        Ok(fleet) => {
+            for summary in fleet {
+                if let Some(last_seen) = summary.last_seen {
+                    alerter.seen(&summary.device, last_seen);
+                }
+            }
+        }
+        Err(e) => eprintln!("Can't find when devices were last seen: {}", e),
+    }
+    let silence_alerter = alerter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SILENCE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            silence_alerter.check_silence();
+        }
+    });
+
+    let with_storage = warp::any().map(move || storage.clone());
+
+    // An empty key would let anyone in
+    let crew_key = std::env::var("ISOPOD_CREW_KEY").ok().filter(|x| !x.is_empty());
+
+    let assets = PathBuf::from(std::env::var("ISOPOD_ASSETS").unwrap_or_else(|_| ".".to_owned()));
+    let maps_path = std::env::var("ISOPOD_MAPS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| assets.join("maps.json"));
+    let map_config = match MapConfig::load(&maps_path) {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            eprintln!("Bad map config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let with_maps = warp::any().map(move || map_config.clone());
+
+    let (live_tx, _) = broadcast::channel::<LivePosition>(LIVE_BUFFER);
+    let with_live = warp::any().map(move || live_tx.clone());
+
+    let command_devices = devices.clone();
+    let command_crew_key = crew_key.clone();
+
     let isopod = warp::post()
         .and(warp::path("isopod"))
-        // Only accept bodies smaller than 16kb
-        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::header::optional::<String>("authorization"))
+        // Only accept bodies smaller than 1MB, enough for a batch of reports
+        .and(warp::body::content_length_limit(1024 * 1024))
         .and(warp::body::json())
-        .map(|packet: Packet| {
-            println!("Rx: {:#?}", packet);
-            println!("https://maps.google.com/?q={},{}", packet.lat, packet.long);
-            println!("");
-            warp::reply()
+        .and(with_storage.clone())
+        .and(with_live.clone())
+        .map(move |auth: Option<String>, upload: serde_json::Value, storage: Arc<Storage>, live| {
+            receive(&storage, &live, &devices, &alerter, auth.as_deref(), upload)
         });
 
-    warp::serve(isopod).run(([0, 0, 0, 0], 1309)).await
+    let command_queue = warp::post()
+        .and(warp::path("commands"))
+        .and(warp::query::<HistoryQuery>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::json())
+        .and(with_storage.clone())
+        .map(
+            move |query: HistoryQuery, auth: Option<String>, command: Command, storage: Arc<Storage>| {
+                queue_command(&storage, crew_key.as_deref(), auth.as_deref(), query.device(), command)
+            },
+        );
 
-}
+    let command_list = warp::get()
+        .and(warp::path("commands"))
+        .and(warp::query::<HistoryQuery>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_storage.clone())
+        .map(move |query: HistoryQuery, auth: Option<String>, storage: Arc<Storage>| {
+            list_commands(
+                &storage,
+                &command_devices,
+                command_crew_key.as_deref(),
+                auth.as_deref(),
+                query.device(),
+            )
+        });
 
+    let latest = warp::get()
+        .and(warp::path("latest"))
+        .and(warp::query::<HistoryQuery>())
+        .and(with_storage.clone())
+        .map(|query: HistoryQuery, storage: Arc<Storage>| match storage.latest(query.device()) {
+            Ok(None) => reply::with_status("No position yet", StatusCode::NOT_FOUND).into_response(),
+            result => query_reply(result),
+        });
+
+    let track = warp::get()
+        .and(warp::path("track"))
+        .and(warp::query::<HistoryQuery>())
+        .and(with_storage.clone())
+        .map(|query: HistoryQuery, storage: Arc<Storage>| match query.range() {
+            Ok((from, to)) => query_reply(storage.track(query.device(), from, to)),
+            Err(e) => reply::with_status(e, StatusCode::BAD_REQUEST).into_response(),
+        });
+
+    let battery = warp::get()
+        .and(warp::path("battery"))
+        .and(warp::query::<HistoryQuery>())
+        .and(with_storage.clone())
+        .map(|query: HistoryQuery, storage: Arc<Storage>| match query.range() {
+            Ok((from, to)) => query_reply(storage.battery(query.device(), from, to)),
+            Err(e) => reply::with_status(e, StatusCode::BAD_REQUEST).into_response(),
+        });
+
+    let export = warp::get()
+        .and(warp::path!("export" / String))
+        .and(warp::query::<HistoryQuery>())
+        .and(warp::query::<TrackFilter>())
+        .and(with_storage.clone())
+        .map(|file: String, query: HistoryQuery, filter: TrackFilter, storage: Arc<Storage>| {
+            export(&storage, &file, &query, &filter)
+        });
+
+    let fleet = warp::get()
+        .and(warp::path("fleet"))
+        .and(with_storage)
+        .map(move |storage: Arc<Storage>| query_reply(storage.fleet(&fleet_devices)));
+
+    let map_page = warp::get()
+        .and(warp::path::end())
+        .and(warp::fs::file(assets.join("map.html")));
+    let map_js = warp::get()
+        .and(warp::path("map.js"))
+        .and(warp::fs::file(assets.join("map.js")));
+
+    let map_list = warp::get()
+        .and(warp::path("maps"))
+        .and(warp::path::end())
+        .and(with_maps.clone())
+        .map(|config: Arc<MapConfig>| reply::json(&*config));
+
+    let map_image = warp::get()
+        .and(warp::path!("maps" / String / "image"))
+        .and(with_maps)
+        .then(move |id: String, config: Arc<MapConfig>| {
+            let assets = assets.clone();
+            async move {
+                let map = match config.maps.get(&id) {
+                    Some(x) => x,
+                    None => return reply::with_status("No such map", StatusCode::NOT_FOUND).into_response(),
+                };
+                let path = assets.join(&map.image);
+                match tokio::fs::read(&path).await {
+                    Ok(image) => {
+                        reply::with_header(image, "content-type", maps::image_content_type(&path)).into_response()
+                    }
+                    Err(e) => {
+                        eprintln!("Can't read map image {}: {}", path.display(), e);
+                        reply::with_status("Can't read map image", StatusCode::INTERNAL_SERVER_ERROR)
+                            .into_response()
+                    }
+                }
+            }
+        });
+
+    let live = warp::get()
+        .and(warp::path("live"))
+        .and(warp::query::<HistoryQuery>())
+        .and(with_live)
+        .map(|query: HistoryQuery, live: broadcast::Sender<LivePosition>| {
+            let device = query.device().to_owned();
+            let positions = futures_util::stream::unfold(live.subscribe(), |mut rx| async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(position) => return Some((position, rx)),
+                        // Slow clients just miss some positions
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            });
+            let events = positions
+                .filter(move |position| std::future::ready(position.device == device))
+                .map(|position| {
+                    let event = warp::sse::Event::default().event("position").json_data(&position);
+                    Ok::<_, Infallible>(event.unwrap_or_default())
+                });
+            warp::sse::reply(warp::sse::keep_alive().stream(events))
+        });
+
+    let routes = isopod
+        .or(command_queue)
+        .or(command_list)
+        .or(map_page)
+        .or(map_js)
+        .or(map_list)
+        .or(map_image)
+        .or(latest)
+        .or(track)
+        .or(battery)
+        .or(live)
+        .or(export)
+        .or(fleet);
+    warp::serve(routes).run(([0, 0, 0, 0], 1309)).await
+
+}
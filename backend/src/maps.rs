@@ -0,0 +1,79 @@
+//! Base maps for the map page.  Each event gets an image of the site and the
+//! latitude and longitude of its corners, so the page can plot positions on
+//! it.  They're listed in a JSON file like:
+//!
+//! ```json
+//! {
+//!     "default": "emf2022",
+//!     "maps": {
+//!         "emf2022": {
+//!             "name": "Electromagnetic Field 2022",
+//!             "image": "emf_base_map.png",
+//!             "bottom_left": [52.038889, -2.380556],
+//!             "top_right": [52.044046, -2.374030]
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Image paths are relative to the assets directory.  Corners are
+//! `[latitude, longitude]` in decimal degrees, and the image is assumed to be
+//! north-up with no distortion, which is close enough over a festival site.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BaseMap {
+    /// Name to show on the page
+    pub name: String,
+    /// The image file, which isn't given out to the page
+    #[serde(skip_serializing)]
+    pub image: PathBuf,
+    pub bottom_left: [f64; 2],
+    pub top_right: [f64; 2],
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MapConfig {
+    /// ID of the map to show if the page doesn't ask for one
+    pub default: String,
+    pub maps: BTreeMap<String, BaseMap>,
+}
+
+impl MapConfig {
+    /// Load the map config, checking it makes sense
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let config: Self = serde_json::from_str(&contents)
+            .map_err(|e| format!("can't parse {}: {}", path.display(), e))?;
+
+        if !config.maps.contains_key(&config.default) {
+            return Err(format!("default map {:?} isn't in the list of maps", config.default));
+        }
+        for (id, map) in &config.maps {
+            let [bottom, left] = map.bottom_left;
+            let [top, right] = map.top_right;
+            if top <= bottom || right <= left {
+                return Err(format!(
+                    "map {:?} top_right must be north east of bottom_left",
+                    id
+                ));
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Content type to serve a map image with, from its file extension
+pub fn image_content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("svg") => "image/svg+xml",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,192 @@
+//! Ways of sending alerts to the crew.  Notifiers are listed in the alert
+//! config, see alerts.rs, each with a `type` and its own settings:
+//! * `{"type": "log"}`: print the alert to stdout
+//! * `{"type": "webhook", "url": "https://..."}`: POST the alert as JSON
+//! * `{"type": "email", "to": ["crew@example.com"]}`: send an email through
+//!   an SMTP server, by default the one on localhost port 25.  `smtp_host`,
+//!   `smtp_port` and `from` can also be given.  There's no authentication or
+//!   TLS, so this is meant for a local relay.
+
+use crate::alerts::Alert;
+use serde_derive::Deserialize;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub trait Notifier: Send {
+    fn notify(&self, alert: &Alert) -> Result<(), String>;
+}
+
+/// The notifier settings from the alert config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotifierConfig {
+    Log,
+    Webhook {
+        url: String,
+    },
+    Email {
+        #[serde(default = "default_smtp_host")]
+        smtp_host: String,
+        #[serde(default = "default_smtp_port")]
+        smtp_port: u16,
+        #[serde(default = "default_from")]
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+fn default_smtp_host() -> String {
+    "localhost".to_owned()
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+fn default_from() -> String {
+    "isopod@localhost".to_owned()
+}
+
+impl NotifierConfig {
+    pub fn build(&self) -> Box<dyn Notifier> {
+        match self.clone() {
+            NotifierConfig::Log => Box::new(LogNotifier),
+            NotifierConfig::Webhook { url } => Box::new(WebhookNotifier {
+                url,
+                agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build(),
+            }),
+            NotifierConfig::Email {
+                smtp_host,
+                smtp_port,
+                from,
+                to,
+            } => Box::new(EmailNotifier {
+                smtp_host,
+                smtp_port,
+                from,
+                to,
+            }),
+        }
+    }
+}
+
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify(&self, alert: &Alert) -> Result<(), String> {
+        println!("ALERT: {}", alert.summary());
+        Ok(())
+    }
+}
+
+pub struct WebhookNotifier {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, alert: &Alert) -> Result<(), String> {
+        self.agent
+            .post(&self.url)
+            .send_json(serde_json::to_value(alert).map_err(|e| e.to_string())?)
+            .map_err(|e| format!("webhook {} failed: {}", self.url, e))?;
+        Ok(())
+    }
+}
+
+pub struct EmailNotifier {
+    smtp_host: String,
+    smtp_port: u16,
+    from: String,
+    to: Vec<String>,
+}
+
+/// Make a string safe to put in an email header, where a line break would
+/// start a new header, e.g. from a device name in a report
+fn header_value(value: &str) -> String {
+    value.chars().filter(|x| !matches!(x, '\r' | '\n')).collect()
+}
+
+impl EmailNotifier {
+    /// Have a plain SMTP conversation with the server
+    fn send(&self, subject: &str, body: &str) -> std::io::Result<()> {
+        let stream = TcpStream::connect((self.smtp_host.as_str(), self.smtp_port))?;
+        stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(SMTP_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        // Read a possibly multi-line reply and check it has the expected code
+        let mut expect = |code: &str| -> std::io::Result<()> {
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                if !line.starts_with(code) {
+                    return Err(std::io::Error::other(format!(
+                        "SMTP server said {:?}, expected {}",
+                        line.trim_end(),
+                        code
+                    )));
+                }
+                // "250-" means more lines follow, "250 " is the last
+                if line.as_bytes().get(3) != Some(&b'-') {
+                    return Ok(());
+                }
+            }
+        };
+
+        expect("220")?;
+        write!(writer, "HELO isopod-backend\r\n")?;
+        expect("250")?;
+        write!(writer, "MAIL FROM:<{}>\r\n", self.from)?;
+        expect("250")?;
+        for to in &self.to {
+            write!(writer, "RCPT TO:<{}>\r\n", to)?;
+            expect("250")?;
+        }
+        write!(writer, "DATA\r\n")?;
+        expect("354")?;
+        write!(
+            writer,
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n",
+            self.from,
+            self.to.join(", "),
+            header_value(subject)
+        )?;
+        for line in body.lines() {
+            // Lines starting with a dot must have it doubled
+            if line.starts_with('.') {
+                write!(writer, ".")?;
+            }
+            write!(writer, "{}\r\n", line)?;
+        }
+        write!(writer, ".\r\n")?;
+        expect("250")?;
+        write!(writer, "QUIT\r\n")?;
+        Ok(())
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn notify(&self, alert: &Alert) -> Result<(), String> {
+        let subject = format!("ISOPOD alert: {}", alert.summary());
+        let body = serde_json::to_string_pretty(alert).map_err(|e| e.to_string())?;
+        self.send(&subject, &body)
+            .map_err(|e| format!("email via {}:{} failed: {}", self.smtp_host, self.smtp_port, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_values() {
+        assert_eq!(header_value("ISOPOD alert: isopod2 low battery"), "ISOPOD alert: isopod2 low battery");
+        assert_eq!(header_value("isopod\r\nBcc: someone@example.com"), "isopodBcc: someone@example.com");
+        assert_eq!(header_value("a\nb\rc"), "abc");
+    }
+}
@@ -0,0 +1,604 @@
+//! The reports sent by the isopod's reporter, and how we make sense of them.
+//!
+//! Reports come in several versions, given by their `version` field:
+//! * Version 1 had no `version`, and was a flat object of GPS, battery and
+//!   temperature readings.  The time was the GPS fix's, like
+//!   `2022-06-02 18:00:00` in UTC, and with no fix the position was 0,0.
+//! * Version 2 added the structured report, with `gps` null without a fix.
+//! * Version 3 added `device`.
+//! * Version 4 added `lost_mode` and `acks`.
+//! * Version 5 added `thermal`.
+//!
+//! Every version is turned into a Packet.  Apart from the battery readings
+//! everything is optional, and readings which don't make sense are dropped
+//! with a warning rather than rejecting the whole report.  The wire structs
+//! must be kept in step with the Report struct in the isopod's reporter.rs.
+
+use crate::commands::Ack;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Newest version of the report schema this understands
+pub const REPORT_VERSION: u32 = 5;
+
+/// Times before this are from an isopod whose clock hasn't been set, or a
+/// stubbed-out GPS fix
+const EARLIEST_TIME: i64 = 946_684_800; // 2000-01-01
+
+/// How far ahead of ours an isopod's clock can be, in seconds, before its
+/// times are taken to be wrong
+const MAX_CLOCK_AHEAD: i64 = 300;
+
+#[derive(Debug, Serialize)]
+pub struct GpsFix {
+    pub longitude: f64,
+    pub latitude: f64,
+    pub altitude: Option<f32>,
+    pub satellites: Option<usize>,
+    pub time: Option<DateTime<Utc>>,
+    pub hdop: Option<f32>,
+    pub fix_quality: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Battery {
+    pub voltage: f32,
+    pub current: f32,
+    pub soc: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WorkerStatus {
+    pub state: String,
+    pub last_heartbeat: f32,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Shock {
+    pub time: Option<DateTime<Utc>>,
+    pub magnitude: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PowerLimiting {
+    pub frames: u64,
+    pub limited_frames: u64,
+    pub min_scale: Option<f32>,
+}
+
+/// How far the isopod has throttled back to keep cool
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Throttling {
+    /// How many of its thermal levels the temperature has reached, 0 if
+    /// it's not throttling
+    pub level: usize,
+    pub fps: Option<u64>,
+    pub ws_json_disabled: bool,
+    pub max_brightness: Option<u8>,
+}
+
+/// A report of any version, as we understand it
+#[derive(Debug, Serialize)]
+pub struct Packet {
+    /// The version the report was sent as
+    pub version: u32,
+    /// Which isopod this is.  Reports before version 3 don't say.
+    pub device: Option<String>,
+    /// When the report was made, or None if we can't tell
+    pub time: Option<DateTime<Utc>>,
+    pub uptime: Option<f64>,
+    /// None without a fix
+    pub gps: Option<GpsFix>,
+    pub battery: Battery,
+    pub temperature: Option<f32>,
+    /// None before version 5
+    pub thermal: Option<Throttling>,
+    pub wifi_signal: Option<i32>,
+    pub pattern: Option<String>,
+    pub brightness: Option<u8>,
+    pub fps: Option<f32>,
+    pub frame_overruns: Option<u64>,
+    pub health: BTreeMap<String, WorkerStatus>,
+    pub motion: Option<String>,
+    pub shocks: Vec<Shock>,
+    pub power_limiting: Option<PowerLimiting>,
+    pub lost_mode: bool,
+    pub acks: Vec<Ack>,
+}
+
+/// A version 1 report
+#[derive(Debug, Deserialize)]
+struct FlatReport {
+    long: Option<f64>,
+    lat: Option<f64>,
+    alt: Option<f32>,
+    time: Option<String>,
+    sats: Option<usize>,
+    voltage: f32,
+    current: f32,
+    soc: f32,
+    /// Like "45.2°C", or "unknown"
+    temp: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WireGpsFix {
+    longitude: f64,
+    latitude: f64,
+    altitude: Option<f32>,
+    satellites: Option<usize>,
+    time: Option<String>,
+    hdop: Option<f32>,
+    fix_quality: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WireShock {
+    time: Option<String>,
+    magnitude: f32,
+}
+
+/// A report of version 2 or later
+#[derive(Debug, Deserialize)]
+struct WireReport {
+    version: u32,
+    device: Option<String>,
+    time: Option<String>,
+    uptime: Option<f64>,
+    gps: Option<WireGpsFix>,
+    battery: Battery,
+    temperature: Option<f32>,
+    thermal: Option<Throttling>,
+    wifi_signal: Option<i32>,
+    pattern: Option<String>,
+    brightness: Option<u8>,
+    fps: Option<f32>,
+    frame_overruns: Option<u64>,
+    #[serde(default)]
+    health: BTreeMap<String, WorkerStatus>,
+    motion: Option<String>,
+    #[serde(default)]
+    shocks: Vec<WireShock>,
+    power_limiting: Option<PowerLimiting>,
+    #[serde(default)]
+    lost_mode: bool,
+    #[serde(default)]
+    acks: Vec<Ack>,
+}
+
+/// Parse a time, which should be RFC 3339 but was plain UTC like
+/// `2022-06-02 18:00:00` in version 1.  Returns None for times which can't
+/// be right.
+fn parse_time(time: &str, warnings: &mut Vec<String>) -> Option<DateTime<Utc>> {
+    let parsed = DateTime::parse_from_rfc3339(time)
+        .map(|x| x.with_timezone(&Utc))
+        .or_else(|_| NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").map(|x| Utc.from_utc_datetime(&x)));
+    match parsed {
+        Ok(x) if x.timestamp() < EARLIEST_TIME => {
+            warnings.push(format!("time {:?} is before the clock was set", time));
+            None
+        }
+        Ok(x) if x.timestamp() > Utc::now().timestamp() + MAX_CLOCK_AHEAD => {
+            warnings.push(format!("time {:?} is in the future", time));
+            None
+        }
+        Ok(x) => Some(x),
+        Err(e) => {
+            warnings.push(format!("bad time {:?}: {}", time, e));
+            None
+        }
+    }
+}
+
+/// Check a position is somewhere real.  0,0 is what older isopods sent
+/// without a fix.
+fn check_position(latitude: f64, longitude: f64) -> Result<(), String> {
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Err(format!("position {},{} is out of range", latitude, longitude));
+    }
+    if latitude == 0.0 && longitude == 0.0 {
+        return Err("position 0,0 means no fix".to_owned());
+    }
+    Ok(())
+}
+
+/// Make sense of a version 1 report
+fn from_flat(report: FlatReport, warnings: &mut Vec<String>) -> Packet {
+    let time = report.time.as_deref().and_then(|x| parse_time(x, warnings));
+    let gps = match (report.lat, report.long) {
+        (Some(latitude), Some(longitude)) => match check_position(latitude, longitude) {
+            Ok(()) => Some(GpsFix {
+                longitude,
+                latitude,
+                altitude: report.alt,
+                satellites: report.sats,
+                time,
+                hdop: None,
+                fix_quality: None,
+            }),
+            Err(e) => {
+                warnings.push(e);
+                None
+            }
+        },
+        _ => None,
+    };
+    let temperature = report.temp.as_deref().and_then(|x| {
+        // "unknown" meant the isopod couldn't read it
+        let degrees = x.trim_end_matches("°C").parse().ok();
+        if degrees.is_none() && x != "unknown" {
+            warnings.push(format!("bad temperature {:?}", x));
+        }
+        degrees
+    });
+
+    Packet {
+        version: 1,
+        device: None,
+        // The only time was the fix's, which is the best guess we have
+        time,
+        uptime: None,
+        gps,
+        battery: Battery {
+            voltage: report.voltage,
+            current: report.current,
+            soc: report.soc,
+        },
+        temperature,
+        thermal: None,
+        wifi_signal: None,
+        pattern: None,
+        brightness: None,
+        fps: None,
+        frame_overruns: None,
+        health: BTreeMap::new(),
+        motion: None,
+        shocks: Vec::new(),
+        power_limiting: None,
+        lost_mode: false,
+        acks: Vec::new(),
+    }
+}
+
+/// Make sense of a version 2 or later report
+fn from_wire(report: WireReport, warnings: &mut Vec<String>) -> Packet {
+    let gps = report.gps.and_then(|fix| {
+        if fix.fix_quality.as_deref() == Some("invalid") {
+            warnings.push("GPS fix is invalid".to_owned());
+            return None;
+        }
+        if let Err(e) = check_position(fix.latitude, fix.longitude) {
+            warnings.push(e);
+            return None;
+        }
+        Some(GpsFix {
+            longitude: fix.longitude,
+            latitude: fix.latitude,
+            altitude: fix.altitude,
+            satellites: fix.satellites,
+            time: fix.time.as_deref().and_then(|x| parse_time(x, warnings)),
+            hdop: fix.hdop,
+            fix_quality: fix.fix_quality,
+        })
+    });
+    let shocks = report
+        .shocks
+        .into_iter()
+        .map(|x| Shock {
+            time: x.time.as_deref().and_then(|x| parse_time(x, warnings)),
+            magnitude: x.magnitude,
+        })
+        .collect();
+
+    Packet {
+        version: report.version,
+        device: report.device,
+        time: report.time.as_deref().and_then(|x| parse_time(x, warnings)),
+        uptime: report.uptime,
+        gps,
+        battery: report.battery,
+        temperature: report.temperature,
+        thermal: report.thermal,
+        wifi_signal: report.wifi_signal,
+        pattern: report.pattern,
+        brightness: report.brightness,
+        fps: report.fps,
+        frame_overruns: report.frame_overruns,
+        health: report.health,
+        motion: report.motion,
+        shocks,
+        power_limiting: report.power_limiting,
+        lost_mode: report.lost_mode,
+        acks: report.acks,
+    }
+}
+
+/// Make sense of a report of any version.  Returns the report with
+/// warnings about anything which was dropped, or why the report is no use
+/// at all.
+pub fn parse(report: serde_json::Value) -> Result<(Packet, Vec<String>), String> {
+    let mut warnings = Vec::new();
+    let packet = match report.get("version") {
+        None => from_flat(serde_json::from_value(report).map_err(|e| e.to_string())?, &mut warnings),
+        Some(version) => match version.as_u64() {
+            Some(version) if version >= 2 => {
+                if version > REPORT_VERSION as u64 {
+                    warnings.push(format!(
+                        "version {} is newer than {}, some readings may be ignored",
+                        version, REPORT_VERSION
+                    ));
+                }
+                from_wire(serde_json::from_value(report).map_err(|e| e.to_string())?, &mut warnings)
+            }
+            _ => return Err(format!("unknown version {}", version)),
+        },
+    };
+
+    let battery = &packet.battery;
+    if !(battery.voltage.is_finite() && battery.current.is_finite() && battery.soc.is_finite()) {
+        return Err("battery readings aren't numbers".to_owned());
+    }
+    if !(0.0..=100.0).contains(&battery.soc) {
+        warnings.push(format!("state of charge {}% is out of range", battery.soc));
+    }
+    Ok((packet, warnings))
+}
+
+/// The reports in an upload, which is a batch of reports queued while the
+/// isopod was offline, or a single report from older firmware
+pub fn reports(upload: serde_json::Value) -> Option<Vec<serde_json::Value>> {
+    match upload {
+        serde_json::Value::Array(x) => Some(x),
+        x @ serde_json::Value::Object(_) => Some(vec![x]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn battery() -> serde_json::Value {
+        json!({ "voltage": 14.8, "current": -1.2, "soc": 76.5 })
+    }
+
+    #[test]
+    fn version_1() {
+        let (packet, warnings) = parse(json!({
+            "lat": 52.0416,
+            "long": -2.3778,
+            "sats": 7,
+            "alt": 105.5,
+            "time": "2022-06-02 18:00:00",
+            "voltage": 14.8,
+            "current": -1.2,
+            "soc": 76.5,
+            "temp": "45.2°C",
+        }))
+        .unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(packet.version, 1);
+        assert_eq!(packet.device, None);
+        let time = Utc.with_ymd_and_hms(2022, 6, 2, 18, 0, 0).unwrap();
+        assert_eq!(packet.time, Some(time));
+        let gps = packet.gps.unwrap();
+        assert_eq!((gps.latitude, gps.longitude), (52.0416, -2.3778));
+        assert_eq!(gps.satellites, Some(7));
+        assert_eq!(gps.time, Some(time));
+        assert_eq!(packet.battery.soc, 76.5);
+        assert_eq!(packet.temperature, Some(45.2));
+    }
+
+    #[test]
+    fn version_1_without_fix() {
+        // What older isopods sent before their first fix
+        let (packet, warnings) = parse(json!({
+            "lat": 0.0,
+            "long": 0.0,
+            "sats": 0,
+            "alt": 0.0,
+            "time": "1970-01-01 00:00:00",
+            "voltage": 14.8,
+            "current": -1.2,
+            "soc": 76.5,
+            "temp": "unknown",
+        }))
+        .unwrap();
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(packet.gps.is_none());
+        assert_eq!(packet.time, None);
+        assert_eq!(packet.temperature, None);
+    }
+
+    #[test]
+    fn version_1_missing_fields() {
+        let (packet, warnings) = parse(json!({ "voltage": 14.8, "current": -1.2, "soc": 76.5 })).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert!(packet.gps.is_none());
+        assert_eq!(packet.temperature, None);
+    }
+
+    #[test]
+    fn version_2() {
+        let (packet, warnings) = parse(json!({
+            "version": 2,
+            "time": "2022-06-02T18:00:05.123Z",
+            "uptime": 3600.5,
+            "gps": {
+                "longitude": -2.3778,
+                "latitude": 52.0416,
+                "altitude": 105.5,
+                "satellites": 7,
+                "time": "2022-06-02T18:00:00Z",
+            },
+            "battery": battery(),
+            "temperature": 45.2,
+            "wifi_signal": -60,
+            "pattern": "glitch",
+            "brightness": 80,
+            "fps": 29.8,
+            "frame_overruns": 3,
+            "health": {
+                "gps": { "state": "running", "last_heartbeat": 0.1, "restarts": 0, "last_error": null },
+            },
+            "motion": "still",
+            "shocks": [],
+            "power_limiting": { "frames": 100, "limited_frames": 0, "min_scale": null },
+        }))
+        .unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(packet.version, 2);
+        assert_eq!(packet.device, None);
+        assert_eq!(packet.time.unwrap().timestamp_millis(), 1654192805123);
+        assert_eq!(packet.gps.unwrap().altitude, Some(105.5));
+        assert_eq!(packet.pattern.as_deref(), Some("glitch"));
+        assert_eq!(packet.health["gps"].state, "running");
+    }
+
+    #[test]
+    fn version_3() {
+        let (packet, warnings) = parse(json!({
+            "version": 3,
+            "device": "isopod2",
+            "time": "2022-06-02T19:00:05+01:00",
+            "gps": {
+                "longitude": -2.3778,
+                "latitude": 52.0416,
+                "altitude": 105.5,
+                "satellites": 7,
+                "time": "2022-06-02T18:00:00Z",
+                "hdop": 0.9,
+                "fix_quality": "gps",
+            },
+            "battery": battery(),
+            "shocks": [{ "time": "2022-06-02T17:59:00Z", "magnitude": 25.0 }],
+        }))
+        .unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(packet.device.as_deref(), Some("isopod2"));
+        assert_eq!(packet.time, Some(Utc.with_ymd_and_hms(2022, 6, 2, 18, 0, 5).unwrap()));
+        assert_eq!(packet.gps.unwrap().hdop, Some(0.9));
+        assert_eq!(packet.shocks[0].magnitude, 25.0);
+        assert!(!packet.lost_mode);
+        assert!(packet.acks.is_empty());
+    }
+
+    #[test]
+    fn version_4() {
+        let (packet, warnings) = parse(json!({
+            "version": 4,
+            "device": "isopod",
+            "time": "2022-06-02T18:00:05Z",
+            "gps": null,
+            "battery": battery(),
+            "lost_mode": true,
+            "acks": [{ "id": 3, "ok": true, "message": "lost mode on" }],
+        }))
+        .unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert!(packet.gps.is_none());
+        assert!(packet.lost_mode);
+        assert_eq!(packet.acks[0].id, 3);
+        assert!(packet.thermal.is_none());
+    }
+
+    #[test]
+    fn version_5() {
+        let (packet, warnings) = parse(json!({
+            "version": 5,
+            "device": "isopod",
+            "time": "2022-06-02T18:00:05Z",
+            "gps": null,
+            "battery": battery(),
+            "temperature": 76.5,
+            "thermal": { "level": 2, "fps": 30, "ws_json_disabled": true, "max_brightness": 70 },
+        }))
+        .unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        let thermal = packet.thermal.unwrap();
+        assert_eq!(thermal.level, 2);
+        assert_eq!(thermal.fps, Some(30));
+        assert!(thermal.ws_json_disabled);
+        assert_eq!(thermal.max_brightness, Some(70));
+    }
+
+    #[test]
+    fn newer_version() {
+        let (packet, warnings) = parse(json!({ "version": 99, "battery": battery(), "new_thing": 1 })).unwrap();
+        assert_eq!(packet.version, 99);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    }
+
+    #[test]
+    fn bad_readings_dropped() {
+        let (packet, warnings) = parse(json!({
+            "version": 4,
+            "time": "yesterday",
+            "gps": {
+                "longitude": -2.3778,
+                "latitude": 152.0,
+                "time": "2022-06-02T18:00:00Z",
+            },
+            "battery": { "voltage": 14.8, "current": -1.2, "soc": 120.0 },
+        }))
+        .unwrap();
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert_eq!(packet.time, None);
+        assert!(packet.gps.is_none());
+        assert_eq!(packet.battery.soc, 120.0);
+    }
+
+    #[test]
+    fn future_times_dropped() {
+        let future = (Utc::now() + chrono::Duration::days(1)).to_rfc3339();
+        let (packet, warnings) = parse(json!({
+            "version": 4,
+            "time": future,
+            "battery": battery(),
+        }))
+        .unwrap();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert_eq!(packet.time, None);
+
+        // A clock a little ahead is fine
+        let (packet, warnings) = parse(json!({
+            "version": 4,
+            "time": (Utc::now() + chrono::Duration::seconds(10)).to_rfc3339(),
+            "battery": battery(),
+        }))
+        .unwrap();
+        assert_eq!(warnings, Vec::<String>::new());
+        assert!(packet.time.is_some());
+    }
+
+    #[test]
+    fn invalid_fix_dropped() {
+        let (packet, _) = parse(json!({
+            "version": 3,
+            "gps": { "longitude": -2.3778, "latitude": 52.0416, "fix_quality": "invalid" },
+            "battery": battery(),
+        }))
+        .unwrap();
+        assert!(packet.gps.is_none());
+    }
+
+    #[test]
+    fn rejected() {
+        assert!(parse(json!({ "version": 3 })).is_err());
+        assert!(parse(json!({ "version": 1, "battery": battery() })).is_err());
+        assert!(parse(json!({ "version": "3", "battery": battery() })).is_err());
+        assert!(parse(json!({ "lat": 52.0, "long": -2.3 })).is_err());
+        assert!(parse(json!("hello")).is_err());
+    }
+
+    #[test]
+    fn uploads() {
+        assert_eq!(reports(json!([{ "version": 4 }, { "version": 4 }])).unwrap().len(), 2);
+        assert_eq!(reports(json!({ "version": 4 })).unwrap().len(), 1);
+        assert!(reports(json!(4)).is_none());
+    }
+}
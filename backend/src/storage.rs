@@ -0,0 +1,610 @@
+//! Keeps every report in an SQLite database so the history survives restarts,
+//! and answers queries about it.  Also keeps the commands queued for the
+//! isopods.
+
+use crate::commands::{Ack, Command, CommandStatus, QueuedCommand};
+use crate::report::Packet;
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Device ID used for reports from isopods which don't identify themselves,
+/// and the device to query if none is given
+pub const DEFAULT_DEVICE: &str = "isopod";
+
+/// Maximum number of rows to return from a history query
+const MAX_ROWS: i64 = 100_000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS reports (
+        id INTEGER PRIMARY KEY,
+        device TEXT NOT NULL,
+        -- When we received the report, in milliseconds since the epoch
+        received INTEGER NOT NULL,
+        -- When the isopod made the report, by its clock, or NULL if it couldn't
+        -- tell, in which case the report is sorted by when we received it
+        time INTEGER,
+        latitude REAL,
+        longitude REAL,
+        altitude REAL,
+        voltage REAL NOT NULL,
+        current REAL NOT NULL,
+        soc REAL NOT NULL,
+        -- The whole report as JSON
+        report TEXT NOT NULL
+    );
+    -- A device makes one report at a time, so a batch sent again because the
+    -- reply was lost doesn't store its reports twice.  Reports without a time
+    -- can't be told apart, but the index allows any number of NULLs.
+    CREATE UNIQUE INDEX IF NOT EXISTS reports_device_time_unique ON reports (device, time);
+    CREATE INDEX IF NOT EXISTS reports_device_sort ON reports (device, COALESCE(time, received));
+
+    CREATE TABLE IF NOT EXISTS commands (
+        id INTEGER PRIMARY KEY,
+        device TEXT NOT NULL,
+        -- The command as JSON
+        command TEXT NOT NULL,
+        -- Times in milliseconds since the epoch
+        queued INTEGER NOT NULL,
+        sent INTEGER,
+        acked INTEGER,
+        ok INTEGER,
+        message TEXT
+    );
+    CREATE INDEX IF NOT EXISTS commands_device ON commands (device, acked);
+";
+
+/// Databases from before reports could be without a time filed them under
+/// when they were received, and may have duplicate reports.  The table is
+/// rebuilt to allow NULL times, and the duplicates removed before the unique
+/// index is added.
+const UPGRADE_NULL_TIMES: &str = "
+    BEGIN;
+    ALTER TABLE reports RENAME TO old_reports;
+    DROP INDEX IF EXISTS reports_device_time;
+    DROP INDEX IF EXISTS reports_device_time_unique;
+    CREATE TABLE reports (
+        id INTEGER PRIMARY KEY,
+        device TEXT NOT NULL,
+        received INTEGER NOT NULL,
+        time INTEGER,
+        latitude REAL,
+        longitude REAL,
+        altitude REAL,
+        voltage REAL NOT NULL,
+        current REAL NOT NULL,
+        soc REAL NOT NULL,
+        report TEXT NOT NULL
+    );
+    INSERT INTO reports SELECT id, device, received,
+            CASE WHEN json_extract(report, '$.time') IS NULL THEN NULL ELSE time END,
+            latitude, longitude, altitude, voltage, current, soc, report
+        FROM old_reports;
+    DROP TABLE old_reports;
+    DELETE FROM reports WHERE time IS NOT NULL
+        AND id NOT IN (SELECT MIN(id) FROM reports WHERE time IS NOT NULL GROUP BY device, time);
+    COMMIT;
+";
+
+pub struct Storage {
+    conn: Mutex<Connection>,
+}
+
+/// A report as stored
+#[derive(Debug, Serialize)]
+pub struct StoredReport {
+    pub device: String,
+    pub received: DateTime<Utc>,
+    pub report: serde_json::Value,
+}
+
+/// A report from a batch, after storing it
+#[derive(Debug)]
+pub struct Inserted {
+    /// False if we already had the report, because the batch was sent again
+    pub new: bool,
+    /// The position in the report, if it has one
+    pub position: Option<TrackPoint>,
+}
+
+/// A point on a device's track
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackPoint {
+    pub time: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f32>,
+}
+
+/// Battery readings at a point in time
+#[derive(Debug, Serialize)]
+pub struct BatteryPoint {
+    pub time: DateTime<Utc>,
+    pub voltage: f32,
+    pub current: f32,
+    pub soc: f32,
+}
+
+/// The readings from a report, for exports
+#[derive(Debug)]
+pub struct TelemetryRow {
+    pub time: DateTime<Utc>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub altitude: Option<f32>,
+    pub satellites: Option<i64>,
+    pub hdop: Option<f32>,
+    pub voltage: f32,
+    pub current: f32,
+    pub soc: f32,
+    pub temperature: Option<f32>,
+    pub wifi_signal: Option<i64>,
+    pub pattern: Option<String>,
+    pub brightness: Option<i64>,
+    pub fps: Option<f32>,
+}
+
+/// A device's latest state, for the fleet overview
+#[derive(Debug, Serialize)]
+pub struct DeviceSummary {
+    pub device: String,
+    /// When we last received a report, or None if we never have
+    pub last_seen: Option<DateTime<Utc>>,
+    pub battery: Option<BatteryPoint>,
+    pub pattern: Option<String>,
+    /// The latest position, which may be older than the latest report
+    pub position: Option<TrackPoint>,
+}
+
+fn to_millis(time: &DateTime<Utc>) -> i64 {
+    time.timestamp_millis()
+}
+
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    // Only ever called with times we stored, which are always valid
+    Utc.timestamp_millis_opt(millis).unwrap()
+}
+
+fn parse_command(json: String) -> rusqlite::Result<Command> {
+    serde_json::from_str(&json)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+fn range_start(from: Option<DateTime<Utc>>) -> i64 {
+    from.as_ref().map_or(i64::MIN, to_millis)
+}
+
+fn range_end(to: Option<DateTime<Utc>>) -> i64 {
+    to.as_ref().map_or(i64::MAX, to_millis)
+}
+
+impl Storage {
+    /// Open the database, creating it if needed
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        let time_required: Option<bool> = conn
+            .query_row(
+                "SELECT \"notnull\" FROM pragma_table_info('reports') WHERE name = 'time'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if time_required == Some(true) {
+            conn.execute_batch(UPGRADE_NULL_TIMES)?;
+        }
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Store a batch of reports, as (device, report), all together or not at
+    /// all.  Reports we already have are skipped.
+    pub fn insert(&self, reports: &[(String, Packet)]) -> rusqlite::Result<Vec<Inserted>> {
+        let received = Utc::now();
+        let mut conn = self.conn.lock().unwrap();
+        let transaction = conn.transaction()?;
+        let mut inserted = Vec::new();
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT OR IGNORE INTO reports
+                    (device, received, time, latitude, longitude, altitude, voltage, current, soc, report)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            for (device, packet) in reports {
+                let report = serde_json::to_string(packet).expect("reports can always be serialised");
+                let changed = statement.execute(params![
+                    device,
+                    to_millis(&received),
+                    packet.time.as_ref().map(to_millis),
+                    packet.gps.as_ref().map(|x| x.latitude),
+                    packet.gps.as_ref().map(|x| x.longitude),
+                    packet.gps.as_ref().and_then(|x| x.altitude),
+                    packet.battery.voltage,
+                    packet.battery.current,
+                    packet.battery.soc,
+                    report,
+                ])?;
+                inserted.push(Inserted {
+                    new: changed > 0,
+                    position: packet.gps.as_ref().map(|x| TrackPoint {
+                        // Reports without a time we can trust are placed by
+                        // when they arrived
+                        time: packet.time.unwrap_or(received),
+                        latitude: x.latitude,
+                        longitude: x.longitude,
+                        altitude: x.altitude,
+                    }),
+                });
+            }
+        }
+        transaction.commit()?;
+        Ok(inserted)
+    }
+
+    /// The latest report from a device which has a position, if any
+    pub fn latest(&self, device: &str) -> rusqlite::Result<Option<StoredReport>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT device, received, report FROM reports
+                    WHERE device = ? AND latitude IS NOT NULL
+                    ORDER BY COALESCE(time, received) DESC LIMIT 1",
+                params![device],
+                |row| {
+                    let report: String = row.get(2)?;
+                    Ok(StoredReport {
+                        device: row.get(0)?,
+                        received: from_millis(row.get(1)?),
+                        report: serde_json::from_str(&report).unwrap_or_default(),
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// The latest state of every device which has reported, plus any others
+    /// given which haven't
+    pub fn fleet(&self, registered: &[String]) -> rusqlite::Result<Vec<DeviceSummary>> {
+        let conn = self.conn.lock().unwrap();
+        let mut last_seen: BTreeMap<String, Option<DateTime<Utc>>> =
+            registered.iter().map(|x| (x.clone(), None)).collect();
+        {
+            let mut statement =
+                conn.prepare_cached("SELECT device, MAX(received) FROM reports GROUP BY device")?;
+            let rows = statement.query_map([], |row| Ok((row.get(0)?, from_millis(row.get(1)?))))?;
+            for row in rows {
+                let (device, received) = row?;
+                last_seen.insert(device, Some(received));
+            }
+        }
+
+        let mut fleet = Vec::new();
+        for (device, last_seen) in last_seen {
+            let latest = conn
+                .query_row(
+                    "SELECT COALESCE(time, received), voltage, current, soc, report FROM reports
+                        WHERE device = ? ORDER BY COALESCE(time, received) DESC LIMIT 1",
+                    params![device],
+                    |row| {
+                        let report: String = row.get(4)?;
+                        let report: serde_json::Value = serde_json::from_str(&report).unwrap_or_default();
+                        let battery = BatteryPoint {
+                            time: from_millis(row.get(0)?),
+                            voltage: row.get(1)?,
+                            current: row.get(2)?,
+                            soc: row.get(3)?,
+                        };
+                        Ok((battery, report["pattern"].as_str().map(str::to_owned)))
+                    },
+                )
+                .optional()?;
+            let position = conn
+                .query_row(
+                    "SELECT COALESCE(time, received), latitude, longitude, altitude FROM reports
+                        WHERE device = ? AND latitude IS NOT NULL
+                        ORDER BY COALESCE(time, received) DESC LIMIT 1",
+                    params![device],
+                    |row| {
+                        Ok(TrackPoint {
+                            time: from_millis(row.get(0)?),
+                            latitude: row.get(1)?,
+                            longitude: row.get(2)?,
+                            altitude: row.get(3)?,
+                        })
+                    },
+                )
+                .optional()?;
+
+            let (battery, pattern) = match latest {
+                Some((battery, pattern)) => (Some(battery), pattern),
+                None => (None, None),
+            };
+            fleet.push(DeviceSummary {
+                device,
+                last_seen,
+                battery,
+                pattern,
+                position,
+            });
+        }
+        Ok(fleet)
+    }
+
+    /// A device's positions between two times, if given, oldest first
+    pub fn track(
+        &self,
+        device: &str,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> rusqlite::Result<Vec<TrackPoint>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(
+            "SELECT COALESCE(time, received), latitude, longitude, altitude FROM reports
+                WHERE device = ? AND COALESCE(time, received) BETWEEN ? AND ? AND latitude IS NOT NULL
+                ORDER BY COALESCE(time, received) LIMIT ?",
+        )?;
+        let rows = statement.query_map(
+            params![device, range_start(from), range_end(to), MAX_ROWS],
+            |row| {
+                Ok(TrackPoint {
+                    time: from_millis(row.get(0)?),
+                    latitude: row.get(1)?,
+                    longitude: row.get(2)?,
+                    altitude: row.get(3)?,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
+    /// A device's battery readings between two times, if given, oldest first
+    pub fn battery(
+        &self,
+        device: &str,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> rusqlite::Result<Vec<BatteryPoint>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(
+            "SELECT COALESCE(time, received), voltage, current, soc FROM reports
+                WHERE device = ? AND COALESCE(time, received) BETWEEN ? AND ?
+                ORDER BY COALESCE(time, received) LIMIT ?",
+        )?;
+        let rows = statement.query_map(
+            params![device, range_start(from), range_end(to), MAX_ROWS],
+            |row| {
+                Ok(BatteryPoint {
+                    time: from_millis(row.get(0)?),
+                    voltage: row.get(1)?,
+                    current: row.get(2)?,
+                    soc: row.get(3)?,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
+    /// All of a device's readings between two times, if given, oldest first
+    pub fn telemetry(
+        &self,
+        device: &str,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> rusqlite::Result<Vec<TelemetryRow>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(
+            "SELECT COALESCE(time, received), latitude, longitude, altitude,
+                    json_extract(report, '$.gps.satellites'), json_extract(report, '$.gps.hdop'),
+                    voltage, current, soc,
+                    json_extract(report, '$.temperature'), json_extract(report, '$.wifi_signal'),
+                    json_extract(report, '$.pattern'), json_extract(report, '$.brightness'),
+                    json_extract(report, '$.fps')
+                FROM reports WHERE device = ? AND COALESCE(time, received) BETWEEN ? AND ?
+                ORDER BY COALESCE(time, received) LIMIT ?",
+        )?;
+        let rows = statement.query_map(
+            params![device, range_start(from), range_end(to), MAX_ROWS],
+            |row| {
+                Ok(TelemetryRow {
+                    time: from_millis(row.get(0)?),
+                    latitude: row.get(1)?,
+                    longitude: row.get(2)?,
+                    altitude: row.get(3)?,
+                    satellites: row.get(4)?,
+                    hdop: row.get(5)?,
+                    voltage: row.get(6)?,
+                    current: row.get(7)?,
+                    soc: row.get(8)?,
+                    temperature: row.get(9)?,
+                    wifi_signal: row.get(10)?,
+                    pattern: row.get(11)?,
+                    brightness: row.get(12)?,
+                    fps: row.get(13)?,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
+    /// Queue a command for a device.  Returns its ID.
+    pub fn queue_command(&self, device: &str, command: &Command) -> rusqlite::Result<i64> {
+        let command = serde_json::to_string(command).expect("commands can always be serialised");
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO commands (device, command, queued) VALUES (?, ?, ?)",
+            params![device, command, to_millis(&Utc::now())],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Commands for a device which it hasn't acknowledged yet, oldest first,
+    /// noting that they're being sent
+    pub fn pending_commands(&self, device: &str) -> rusqlite::Result<Vec<QueuedCommand>> {
+        let conn = self.conn.lock().unwrap();
+        let commands = conn
+            .prepare_cached("SELECT id, command FROM commands WHERE device = ? AND acked IS NULL ORDER BY id")?
+            .query_map(params![device], |row| {
+                Ok(QueuedCommand {
+                    id: row.get(0)?,
+                    command: parse_command(row.get(1)?)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if !commands.is_empty() {
+            conn.execute(
+                "UPDATE commands SET sent = ? WHERE device = ? AND acked IS NULL",
+                params![to_millis(&Utc::now()), device],
+            )?;
+        }
+        Ok(commands)
+    }
+
+    /// Record a device's acknowledgement of a command.  Returns false if it
+    /// isn't one of the device's commands waiting to be acknowledged.
+    pub fn ack_command(&self, device: &str, ack: &Ack) -> rusqlite::Result<bool> {
+        let updated = self.conn.lock().unwrap().execute(
+            "UPDATE commands SET acked = ?, ok = ?, message = ?
+                WHERE id = ? AND device = ? AND acked IS NULL",
+            params![to_millis(&Utc::now()), ack.ok, ack.message, ack.id, device],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// The commands queued for a device, newest first
+    pub fn commands(&self, device: &str) -> rusqlite::Result<Vec<CommandStatus>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(
+            "SELECT id, device, command, queued, sent, acked, ok, message FROM commands
+                WHERE device = ? ORDER BY id DESC LIMIT ?",
+        )?;
+        let rows = statement.query_map(params![device, MAX_ROWS], |row| {
+            Ok(CommandStatus {
+                id: row.get(0)?,
+                device: row.get(1)?,
+                command: parse_command(row.get(2)?)?,
+                queued: from_millis(row.get(3)?),
+                sent: row.get::<_, Option<i64>>(4)?.map(from_millis),
+                acked: row.get::<_, Option<i64>>(5)?.map(from_millis),
+                ok: row.get(6)?,
+                message: row.get(7)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn report(time: &str) -> Packet {
+        let (packet, _) = crate::report::parse(json!({
+            "version": 4,
+            "device": "isopod2",
+            "time": time,
+            "gps": { "latitude": 52.0416, "longitude": -2.3778 },
+            "battery": { "voltage": 14.8, "current": -1.2, "soc": 76.5 },
+        }))
+        .unwrap();
+        packet
+    }
+
+    fn count(storage: &Storage) -> i64 {
+        let conn = storage.conn.lock().unwrap();
+        conn.query_row("SELECT COUNT(*) FROM reports", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn batch_sent_again() {
+        let storage = Storage::open(":memory:").unwrap();
+        let batch = vec![
+            ("isopod2".to_owned(), report("2022-06-02T18:00:00Z")),
+            ("isopod2".to_owned(), report("2022-06-02T18:01:00Z")),
+        ];
+        let inserted = storage.insert(&batch[..1]).unwrap();
+        assert!(inserted[0].new);
+        assert!(inserted[0].position.is_some());
+
+        // Only the report we didn't have is new
+        let inserted = storage.insert(&batch).unwrap();
+        assert_eq!(inserted.iter().map(|x| x.new).collect::<Vec<_>>(), [false, true]);
+        assert_eq!(count(&storage), 2);
+
+        // Another device can report at the same time
+        let inserted = storage.insert(&[("isopod3".to_owned(), report("2022-06-02T18:00:00Z"))]).unwrap();
+        assert!(inserted[0].new);
+        assert_eq!(count(&storage), 3);
+    }
+
+    #[test]
+    fn reports_without_times() {
+        let storage = Storage::open(":memory:").unwrap();
+        let batch = vec![
+            ("isopod2".to_owned(), report("yesterday")),
+            ("isopod2".to_owned(), report("yesterday")),
+            ("isopod2".to_owned(), report("2022-06-02T18:00:00Z")),
+            ("isopod2".to_owned(), report("yesterday")),
+        ];
+        let inserted = storage.insert(&batch).unwrap();
+        assert!(inserted.iter().all(|x| x.new));
+        assert_eq!(count(&storage), 4);
+
+        // They're placed by when they arrived, after the report with a time
+        let track = storage.track("isopod2", None, None).unwrap();
+        assert_eq!(track.len(), 4);
+        assert_eq!(track[0].time.to_rfc3339(), "2022-06-02T18:00:00+00:00");
+        assert!(storage.latest("isopod2").unwrap().unwrap().report["time"].is_null());
+    }
+
+    #[test]
+    fn upgrade_removes_duplicates() {
+        let path = std::env::temp_dir().join(format!("isopod-upgrade-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE reports (
+                    id INTEGER PRIMARY KEY, device TEXT NOT NULL, received INTEGER NOT NULL,
+                    time INTEGER NOT NULL, latitude REAL, longitude REAL, altitude REAL,
+                    voltage REAL NOT NULL, current REAL NOT NULL, soc REAL NOT NULL, report TEXT NOT NULL
+                );
+                CREATE INDEX reports_device_time ON reports (device, time);
+                INSERT INTO reports (device, received, time, voltage, current, soc, report)
+                    VALUES ('isopod', 1, 1, 14.8, -1.2, 76.5, '{\"time\":\"x\"}'),
+                        ('isopod', 2, 1, 14.8, -1.2, 76.5, '{\"time\":\"x\"}'),
+                        ('isopod', 3, 2, 14.8, -1.2, 76.5, '{\"time\":\"x\"}'),
+                        ('isopod', 3, 3, 14.8, -1.2, 76.5, '{\"time\":null}'),
+                        ('isopod', 3, 3, 14.8, -1.2, 76.5, '{}');",
+            )
+            .unwrap();
+        }
+
+        // Reports without a time are kept, and no longer filed under when
+        // they were received
+        let storage = Storage::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(count(&storage), 4);
+        let (received, untimed): (i64, i64) = {
+            let conn = storage.conn.lock().unwrap();
+            (
+                conn.query_row("SELECT received FROM reports WHERE time = 1", [], |row| row.get(0))
+                    .unwrap(),
+                conn.query_row("SELECT COUNT(*) FROM reports WHERE time IS NULL", [], |row| row.get(0))
+                    .unwrap(),
+            )
+        };
+        assert_eq!(received, 1);
+        assert_eq!(untimed, 2);
+        drop(storage);
+
+        // Opening again doesn't upgrade again
+        let storage = Storage::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(count(&storage), 4);
+        drop(storage);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+//! Commands from the crew, queued on the backend and handed to us in its
+//! replies to the reporter.  Each command has an ID, and once it has been
+//! carried out we acknowledge it in the next report, which is sent straight
+//! away.  The backend keeps handing over a command until it has been
+//! acknowledged, so we remember which we've already carried out, in a file
+//! so that we still know after a restart.
+//!
+//! Commands are only carried out with `remote_commands = true` in
+//! settings.toml, which needs the backend to be reached over HTTPS with a
+//! token.
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::control_server::{self, CONTROLS};
+use crate::settings;
+
+/// How many command IDs to remember, so we don't repeat commands whose
+/// acknowledgement hasn't reached the backend yet
+const REMEMBERED_COMMANDS: usize = 100;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    Pattern { name: String },
+    Brightness { value: u8 },
+    /// Send a report now rather than waiting for the next one
+    StatusReport,
+    /// Report more often, to help find a lost isopod
+    LostMode { enabled: bool },
+}
+
+/// A command as handed over by the backend
+#[derive(Debug, Deserialize)]
+pub struct QueuedCommand {
+    pub id: i64,
+    pub command: Command,
+}
+
+/// The backend's reply to an upload
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct UploadReply {
+    pub commands: Vec<QueuedCommand>,
+}
+
+/// Whether a command was carried out, as sent back in a report
+#[derive(Debug, Clone, Serialize)]
+pub struct Ack {
+    pub id: i64,
+    pub ok: bool,
+    pub message: String,
+}
+
+lazy_static! {
+    /// IDs of recently carried out commands, oldest first
+    static ref DONE: Mutex<VecDeque<i64>> = Mutex::new(VecDeque::new());
+    /// Acknowledgements waiting to go in a report
+    static ref ACKS: Mutex<Vec<Ack>> = Mutex::new(Vec::new());
+}
+
+/// Set when a report should be sent without waiting for the next interval
+static REPORT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Carry out a command, returning a description of what happened
+fn execute(command: &Command) -> Result<String, String> {
+    let apply = |key: &str, value: String| {
+        control_server::apply_command(&HashMap::from([(key.to_owned(), value)]));
+    };
+    match command {
+        Command::Pattern { name } => {
+            if !control_server::allowed_patterns().contains(name) {
+                return Err(format!("unknown pattern {:?}", name));
+            }
+            apply("pattern", name.clone());
+            Ok(format!("pattern set to {}", name))
+        }
+        Command::Brightness { value } => {
+            if *value > 100 {
+                return Err(format!("brightness {} is over 100", value));
+            }
+            apply("brightness", value.to_string());
+            Ok(format!("brightness set to {}", value))
+        }
+        // Every command gets a report sent, so there's nothing else to do
+        Command::StatusReport => Ok("report sent".to_owned()),
+        Command::LostMode { enabled } => {
+            apply("lost_mode", enabled.to_string());
+            Ok(format!("lost mode {}", if *enabled { "on" } else { "off" }))
+        }
+    }
+}
+
+/// Load the IDs of commands carried out before we last restarted
+fn load_done(path: &str) -> VecDeque<i64> {
+    match fs::read_to_string(path) {
+        Ok(contents) => match serde_json::from_str(&contents) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Can't parse {}, commands may be repeated: {}", path, e);
+                VecDeque::new()
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+        Err(e) => {
+            error!("Can't read {}, commands may be repeated: {}", path, e);
+            VecDeque::new()
+        }
+    }
+}
+
+/// Save the IDs of the commands carried out
+fn save_done(path: &str, done: &VecDeque<i64>) -> Result<()> {
+    // Write to a temporary file first so we never leave a partial list
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, serde_json::to_string(done)?).with_context(|| format!("Failed to write {}", tmp_path))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to replace {}", path))?;
+    Ok(())
+}
+
+/// Load the IDs of the commands carried out before we last restarted, before
+/// the reporter starts handing over commands
+pub fn start() {
+    *DONE.lock().unwrap() = load_done(&settings::get().remote_commands_file);
+}
+
+/// Carry out the commands in a reply from the backend
+pub fn handle(commands: Vec<QueuedCommand>) {
+    if commands.is_empty() {
+        return;
+    }
+    let settings = settings::get();
+    if !settings.remote_commands {
+        info!("Ignoring {} remote commands, remote_commands is off", commands.len());
+        return;
+    }
+
+    let mut done = DONE.lock().unwrap();
+    let mut changed = false;
+    for queued in commands {
+        if done.contains(&queued.id) {
+            continue;
+        }
+        let result = execute(&queued.command);
+        info!("Remote command {} {:?}: {:?}", queued.id, queued.command, result);
+        let (ok, message) = match result {
+            Ok(x) => (true, x),
+            Err(x) => (false, x),
+        };
+        ACKS.lock().unwrap().push(Ack {
+            id: queued.id,
+            ok,
+            message,
+        });
+        done.push_back(queued.id);
+        if done.len() > REMEMBERED_COMMANDS {
+            done.pop_front();
+        }
+        changed = true;
+        REPORT_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    if changed {
+        if let Err(e) = save_done(&settings.remote_commands_file, &done) {
+            error!("Failed to save remote commands carried out: {:#}", e);
+        }
+    }
+}
+
+/// Take the acknowledgements to go in the next report
+pub fn take_acks() -> Vec<Ack> {
+    std::mem::take(&mut *ACKS.lock().unwrap())
+}
+
+/// Whether a report should be sent now, clearing the request
+pub fn take_report_request() -> bool {
+    REPORT_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// Whether lost mode is on
+pub fn lost_mode() -> bool {
+    CONTROLS.read().unwrap().lost_mode
+}
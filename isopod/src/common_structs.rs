@@ -6,10 +6,11 @@
 
 use crate::patterns::geometry::Vector3d;
 use crate::{LEDS_PER_SPINE, SPINES};
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 
 /// Represents the data captured in a momentary GPS fix
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
 pub struct GpsFix {
     /// Longitude of the fix location in signed decimal degrees
     pub longitude: f64,
@@ -21,18 +22,25 @@ pub struct GpsFix {
     pub satellites: usize,
     /// The time, in UTC, of the fix
     pub time: DateTime<Utc>,
+    /// Horizontal dilution of precision, lower is better
+    pub hdop: Option<f32>,
+    /// How the fix was obtained, if the GPS module said
+    pub fix_quality: Option<FixQuality>,
 }
 
-impl std::default::Default for GpsFix {
-    fn default() -> Self {
-        Self {
-            longitude: 0.0, // Welcome to null island
-            latitude: 0.0,
-            altitude: 0.0,
-            satellites: 0,
-            time: Utc.ymd(1970, 1, 1).and_hms(0, 0, 0),
-        }
-    }
+/// How a GPS fix was obtained, as given in the NMEA GGA sentence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FixQuality {
+    Invalid,
+    Gps,
+    Dgps,
+    Pps,
+    Rtk,
+    FloatRtk,
+    Estimated,
+    Manual,
+    Simulation,
 }
 
 #[derive(Debug, Clone)]
@@ -49,7 +57,7 @@ impl Default for LedUpdate {
 }
 
 /// Represents the sensor data captured from the IMU at a given instant
-#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
 pub struct ImuReadings {
     /// Accelerometer X-axis reading in m/s/s
     pub xa: f32,
@@ -126,7 +134,7 @@ impl std::ops::Div<f32> for ImuReadings {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, Serialize)]
 pub struct BatteryReadings {
     /// Pack voltage in volts
     pub voltage: f32,
@@ -135,3 +143,16 @@ pub struct BatteryReadings {
     /// Estimated state-of-charge as a percentage
     pub soc: f32,
 }
+
+/// How much the LED power limiter has had to step in over a number of frames
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PowerLimiting {
+    /// Number of frames sent to the LEDs
+    pub frames: u64,
+    /// Number of those frames which had to be dimmed to stay within the
+    /// current limit
+    pub limited_frames: u64,
+    /// The strongest scaling applied, e.g. 0.5 if a frame had to be dimmed to
+    /// half brightness, or None if no frames were limited
+    pub min_scale: Option<f32>,
+}
@@ -1,8 +1,26 @@
-use std::sync::RwLock;
-use warp::Filter;
-use std::collections::HashMap;
+use crate::effects;
+use crate::health;
+use crate::logging;
+use crate::mapping_wizard;
+use crate::metrics;
+use crate::settings;
+use crate::ws_server;
+use anyhow::Result;
 use lazy_static::lazy_static;
+use log::{debug, error, info, warn, LevelFilter};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+use warp::Filter;
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Controls {
     // Soft brightness as a proportion of the value in settings.toml.  Expected
     // to be 0-100 inclusive, taken as a %
@@ -10,6 +28,17 @@ pub struct Controls {
 
     // Current pattern name
     pub pattern: String,
+
+    // Patterns to cycle through, in order.  If empty then we just stay on the
+    // current pattern.
+    pub playlist: Vec<String>,
+
+    // Pattern parameters which override the corresponding values in
+    // settings.toml, e.g. "rainbow_swirl_speed"
+    pub params: BTreeMap<String, f64>,
+
+    // Report more often to help find a lost isopod, see commands.rs
+    pub lost_mode: bool,
 }
 
 impl Default for Controls {
@@ -17,13 +46,41 @@ impl Default for Controls {
         Self {
             brightness: 100,
             pattern: "colour_wipes".to_owned(),
+            playlist: Vec::new(),
+            params: BTreeMap::new(),
+            lost_mode: false,
         }
     }
 }
 
+impl Controls {
+    /// Throw away anything in a restored state file which we wouldn't have
+    /// accepted from the control panel.
+    fn sanitise(&mut self) {
+        let default = Controls::default();
+        if self.brightness > 100 {
+            self.brightness = default.brightness;
+        }
+        if !ALLOWED_PATTERNS.contains(&self.pattern) {
+            self.pattern = default.pattern;
+        }
+        self.playlist.retain(|x| ALLOWED_PATTERNS.contains(x));
+        let params = self.params.clone();
+        self.params.retain(|name, x| param_valid(name, *x, &params));
+    }
+}
+
+/// Query parameters for viewing the logs
+#[derive(Debug, Deserialize)]
+struct LogsQuery {
+    level: Option<LevelFilter>,
+    module: Option<String>,
+    lines: Option<usize>,
+}
+
 lazy_static! {
     pub static ref CONTROLS: RwLock<Controls> = RwLock::new(Controls::default());
-    static ref ALLOWED_PATTERNS: [String; 7] = [
+    static ref ALLOWED_PATTERNS: [String; 10] = [
         "colourfield".to_owned(),
         "colour_wipes".to_owned(),
         "glitch".to_owned(),
@@ -31,9 +88,247 @@ lazy_static! {
         "sparkles".to_owned(),
         "starfield".to_owned(),
         "wormholes".to_owned(),
+        "rainbow_swirl".to_owned(),
+        "blue_swirl".to_owned(),
+        "rave".to_owned(),
     ];
+
+    // Held while writing the state file so concurrent requests can't
+    // interleave their writes to the temporary file.
+    static ref STATE_FILE_LOCK: Mutex<()> = Mutex::new(());
+
+    // When each client last triggered an effect, so people can't spam them
+    static ref TRIGGER_TIMES: Mutex<HashMap<IpAddr, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Names of the patterns which can be chosen from the control panel
+pub fn allowed_patterns() -> &'static [String] {
+    &ALLOWED_PATTERNS[..]
+}
+
+/// Get a pattern parameter set from the control panel, if there is one.
+/// Patterns should fall back to settings.toml if this returns None.
+pub fn get_param(name: &str) -> Option<f64> {
+    CONTROLS.read().unwrap().params.get(name).copied()
+}
+
+/// Check a pattern parameter from the control panel.  Only parameters which
+/// patterns actually read are accepted, within the same limits as the
+/// corresponding settings.  `params` are the parameters it would be set
+/// alongside.
+fn param_valid(name: &str, value: f64, params: &BTreeMap<String, f64>) -> bool {
+    if !value.is_finite() {
+        return false;
+    }
+    let whole = value.fract() == 0.0 && value >= 0.0 && value <= u32::MAX as f64;
+    match name {
+        "rainbow_swirl_radial_smear" | "rainbow_swirl_speed" => true,
+        "donk_rate" => whole && value >= 1.0,
+        "donk_len" => {
+            let donk_rate = params
+                .get("donk_rate")
+                .copied()
+                .unwrap_or(settings::get().donk_rate as f64);
+            whole && value <= donk_rate
+        }
+        _ => false,
+    }
+}
+
+fn state_file_path() -> PathBuf {
+    settings::get().state_file.clone().into()
+}
+
+/// Write the given controls to the state file.  The state is written to a
+/// temporary file which is then renamed over the old one, so a power cut
+/// part-way through leaves either the old or the new state but never a
+/// truncated file.  STATE_FILE_LOCK must be held.
+fn save_state(controls: &Controls) -> Result<()> {
+    let path = state_file_path();
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(serde_json::to_string_pretty(controls)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+
+    Ok(())
+}
+
+/// Load the control state saved by a previous run, if there is one.  Must be
+/// called before the pattern manager is created so that we start up in the
+/// saved pattern.  Errors are non-fatal: we just start with the defaults.
+pub fn restore_state() {
+    let path = state_file_path();
+    let buf = match fs::read_to_string(&path) {
+        Ok(buf) => buf,
+        Err(_) => {
+            info!("No saved control state, using defaults.");
+            return;
+        }
+    };
+
+    match serde_json::from_str::<Controls>(&buf) {
+        Ok(mut controls) => {
+            controls.sanitise();
+            info!("Restored control state: {:?}", controls);
+            *CONTROLS.write().unwrap() = controls;
+        }
+        Err(e) => warn!(
+            "Ignoring corrupt control state file {}: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// Change the controls.  Changes are given as key-value pairs in the same form
+/// as the control panel sends to /command, e.g. `pattern=glitch` or
+/// `param_rainbow_swirl_speed=-3.0`.  Invalid values are ignored.  The new
+/// state is saved so it survives a restart.
+pub fn apply_command(p: &HashMap<String, String>) {
+    update_controls(p);
+    save_controls();
+}
+
+/// Change the controls like apply_command(), but without saving them.  For
+/// inputs which change rapidly, which should call save_controls() once
+/// things have settled down.
+pub fn update_controls(p: &HashMap<String, String>) {
+    let mut controls = CONTROLS.write().unwrap();
+
+    if let Some(x) = p.get("brightness") {
+        if let Ok(x) = x.parse::<u8>() {
+            if x <= 100 {
+                controls.brightness = x;
+            }
+        }
+    }
+
+    if let Some(x) = p.get("pattern") {
+        if ALLOWED_PATTERNS.contains(x) {
+            controls.pattern = x.clone();
+        }
+    }
+
+    if let Some(x) = p.get("lost_mode") {
+        if let Ok(x) = x.parse::<bool>() {
+            controls.lost_mode = x;
+        }
+    }
+
+    // Comma-separated list of pattern names, or empty to clear
+    if let Some(x) = p.get("playlist") {
+        controls.playlist = x
+            .split(',')
+            .map(|name| name.trim().to_owned())
+            .filter(|name| ALLOWED_PATTERNS.contains(name))
+            .collect();
+    }
+
+    // Parameters are given as e.g. param_rainbow_swirl_speed=-3.0, or with
+    // an empty value to go back to settings.toml
+    let mut params = controls.params.clone();
+    let mut changed = Vec::new();
+    for (key, value) in p.iter() {
+        if let Some(name) = key.strip_prefix("param_") {
+            if value.is_empty() {
+                params.remove(name);
+            } else if let Ok(x) = value.parse::<f64>() {
+                params.insert(name.to_owned(), x);
+                changed.push(name);
+            }
+        }
+    }
+    // Check the changes against each other, since e.g. donk_len depends on
+    // donk_rate, and leave the old value alone if they're invalid
+    let new_params = params.clone();
+    for name in changed {
+        let x = new_params[name];
+        if !param_valid(name, x, &new_params) {
+            warn!("Ignoring invalid pattern parameter {} = {}", name, x);
+            match controls.params.get(name) {
+                Some(&old) => params.insert(name.to_owned(), old),
+                None => params.remove(name),
+            };
+        }
+    }
+    controls.params = params;
 }
 
+/// Save the current controls to the state file
+pub fn save_controls() {
+    // Take the snapshot with the file lock held, so an older snapshot can't
+    // be written over a newer one or a factory reset.  It's a copy so we
+    // don't hold up the controls during file IO.
+    let _guard = STATE_FILE_LOCK.lock().unwrap();
+    let snapshot = CONTROLS.read().unwrap().clone();
+
+    if let Err(e) = save_state(&snapshot) {
+        error!("Failed to save control state: {}", e);
+    }
+}
+
+/// Go back to the default controls and forget the saved state
+fn factory_reset() {
+    info!("Factory reset: restoring default controls.");
+    let _guard = STATE_FILE_LOCK.lock().unwrap();
+    *CONTROLS.write().unwrap() = Controls::default();
+    let path = state_file_path();
+    if let Err(e) = fs::remove_file(&path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove state file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Handle an audience trigger request from the given client.  Returns the
+/// HTTP status to reply with.
+fn audience_trigger(addr: Option<SocketAddr>, effect: Option<&String>) -> StatusCode {
+    let effect = match effect {
+        Some(x) => x,
+        None => return StatusCode::BAD_REQUEST,
+    };
+    if effects::effect_by_name(effect).is_none() {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let cooldown = Duration::from_secs(settings::get().trigger_cooldown);
+
+    // Clients without a known address all share one cooldown
+    let ip = addr
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::from([0, 0, 0, 0]));
+
+    let mut trigger_times = TRIGGER_TIMES.lock().unwrap();
+    // Forget clients whose cooldown has expired so this doesn't grow forever
+    trigger_times.retain(|_, last| last.elapsed() < cooldown);
+    if trigger_times.contains_key(&ip) {
+        return StatusCode::TOO_MANY_REQUESTS;
+    }
+    trigger_times.insert(ip, Instant::now());
+    drop(trigger_times);
+
+    info!("Audience trigger from {}: {}", ip, effect);
+    effects::trigger(effect);
+    StatusCode::OK
+}
+
+/// Turn the result of a settings change or reload into a JSON reply, with
+/// a 400 status if the new settings were rejected.
+fn settings_reply(result: Result<Vec<String>>) -> warp::reply::WithStatus<warp::reply::Json> {
+    match result {
+        Ok(warnings) => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "warnings": warnings })),
+            StatusCode::OK,
+        ),
+        Err(e) => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": format!("{:#}", e) })),
+            StatusCode::BAD_REQUEST,
+        ),
+    }
+}
 
 async fn control_server() {
 
@@ -54,41 +349,180 @@ async fn control_server() {
         .and(warp::body::content_length_limit(4096))
         .and(warp::body::form())
         .map(|p: HashMap<String, String>| {
-            eprintln!("Got query strings: {:?}", p);
+            debug!("Got query strings: {:?}", p);
 
-            let mut controls = CONTROLS.write().unwrap();
+            apply_command(&p);
+            warp::reply()
+        });
 
-            if let Some(x) = p.get("brightness") {
-                if let Ok(x) = x.parse::<u8>() {
-                    if x <= 100 {
-                        controls.brightness = x;
-                    }
-                }
-            }
+    let reset = warp::post()
+        .and(warp::path("factory_reset"))
+        .and(warp::path::end())
+        .map(|| {
+            factory_reset();
+            warp::reply()
+        });
+
+    // The page linked from the QR code, and the endpoint its buttons use
+    let trigger_page = warp::get()
+        .and(warp::path("trigger"))
+        .and(warp::path::end())
+        .and(warp::fs::file("./trigger.html"));
+
+    let trigger = warp::post()
+        .and(warp::path("trigger"))
+        .and(warp::path::end())
+        .and(warp::addr::remote())
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::form())
+        .map(|addr: Option<SocketAddr>, p: HashMap<String, String>| {
+            let status = audience_trigger(addr, p.get("effect"));
+            warp::reply::with_status(warp::reply(), status)
+        });
+
+    // Admin API for viewing, editing and reloading settings.toml.  Secrets
+    // are left out, and credentials can't be changed, see settings.rs.
+    let settings_get = warp::get()
+        .and(warp::path("settings"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&settings::redacted()));
+
+    let settings_update = warp::put()
+        .and(warp::path("settings"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::json())
+        .map(|changes: serde_json::Value| settings_reply(settings::update(changes)));
+
+    let settings_reload = warp::post()
+        .and(warp::path!("settings" / "reload"))
+        .map(|| settings_reply(settings::reload()));
+
+    // LED spine mapping wizard
+    let mapping_page = warp::get()
+        .and(warp::path("mapping"))
+        .and(warp::path::end())
+        .and(warp::fs::file("./mapping.html"));
+
+    let mapping_status = warp::get()
+        .and(warp::path!("mapping" / "status"))
+        .map(|| warp::reply::json(&mapping_wizard::status()));
+
+    let mapping_start = warp::post()
+        .and(warp::path!("mapping" / "start"))
+        .map(|| {
+            mapping_wizard::start();
+            warp::reply::json(&mapping_wizard::status())
+        });
 
-            if let Some(x) = p.get("pattern") {
-                if ALLOWED_PATTERNS.contains(x) {
-                    controls.pattern = x.clone();
-                }
+    let mapping_cancel = warp::post()
+        .and(warp::path!("mapping" / "cancel"))
+        .map(|| {
+            mapping_wizard::cancel();
+            warp::reply::json(&mapping_wizard::status())
+        });
+
+    let mapping_identify = warp::post()
+        .and(warp::path!("mapping" / "identify"))
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::form())
+        .map(|p: HashMap<String, String>| {
+            let result = p
+                .get("spine")
+                .and_then(|x| x.parse::<usize>().ok())
+                .ok_or_else(|| anyhow::anyhow!("spine position missing"))
+                .and_then(mapping_wizard::identify);
+            match result {
+                Ok(saved) => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({
+                        "status": mapping_wizard::status(),
+                        "saved": saved,
+                    })),
+                    StatusCode::OK,
+                ),
+                Err(e) => warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({ "error": format!("{:#}", e) })),
+                    StatusCode::BAD_REQUEST,
+                ),
             }
+        });
+
+    // Health of all the worker threads.  Returns 503 if any are unhealthy
+    // so it can be polled by simple monitoring tools.
+    let health = warp::get()
+        .and(warp::path("health"))
+        .and(warp::path::end())
+        .map(|| {
+            let healthy = health::all_ok();
+            let status = if healthy {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "healthy": healthy,
+                    "workers": health::status(),
+                })),
+                status,
+            )
+        });
 
-            // Ensure this drops ASAP
-            drop(controls);
+    // Who's connected to the visualiser websocket and how they're doing
+    let ws_clients = warp::get()
+        .and(warp::path("ws_clients"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&ws_server::client_stats()));
 
-            warp::reply()
+    // Metrics for Prometheus to scrape
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .map(|| warp::reply::with_header(metrics::render(), "content-type", "text/plain; version=0.0.4"));
+
+    // Recent log lines, optionally only those at `level` or above, from
+    // within `module`, and the last `lines` of them
+    let logs = warp::get()
+        .and(warp::path("logs"))
+        .and(warp::path::end())
+        .and(warp::query::<LogsQuery>())
+        .map(|query: LogsQuery| {
+            let lines = logging::recent(
+                query.level.unwrap_or(LevelFilter::Trace),
+                query.module.as_deref(),
+                query.lines.unwrap_or(usize::MAX),
+            );
+            lines.iter().map(|line| format!("{}\n", line)).collect::<String>()
         });
 
     let routes = index
         .or(index2)
         .or(bootstrap)
-        .or(command);
+        .or(command)
+        .or(reset)
+        .or(trigger_page)
+        .or(trigger)
+        .or(settings_get)
+        .or(settings_update)
+        .or(settings_reload)
+        .or(mapping_page)
+        .or(mapping_status)
+        .or(mapping_start)
+        .or(mapping_cancel)
+        .or(mapping_identify)
+        .or(health)
+        .or(ws_clients)
+        .or(metrics)
+        .or(logs);
 
     warp::serve(routes).run(([0, 0, 0, 0], 80)).await;
 }
 
 pub fn start_server() {
-    std::thread::spawn(move || {
-        println!("Starting control server...");
-        tokio::runtime::Runtime::new().unwrap().block_on(control_server());
+    // The server sits waiting for requests so doesn't send heartbeats
+    health::spawn_supervised("control server", None, || {
+        info!("Starting control server...");
+        tokio::runtime::Runtime::new()?.block_on(control_server());
+        Ok(())
     });
 }
@@ -0,0 +1,515 @@
+//! Receives DMX from lighting desks over sACN (E1.31) and Art-Net, so a
+//! lighting operator can take over ISOPOD.  There are two modes, chosen with
+//! `dmx_mode`:
+//! * `pixels`: DMX channels drive the LEDs directly, as RGB triplets starting
+//!   at `dmx_channel`.  With `dmx_universe_per_spine`, spine position 1 is in
+//!   universe `dmx_universe`, spine position 2 in the next universe and so
+//!   on.  Otherwise the 708 pixels are packed spine by spine into as few
+//!   universes as they'll fit.  This works just like the OPC and DDP input in
+//!   external_input.rs: the "external" pattern takes over while data is
+//!   arriving.
+//! * `control`: three channels starting at `dmx_channel` in universe
+//!   `dmx_universe` select the pattern, brightness and the speed of the
+//!   rainbow_swirl and blue_swirl patterns, just like the control panel.  A
+//!   value of 0 leaves that control alone.  The controls are saved once the
+//!   desk stops changing them.
+//!
+//! Like the patterns, DMX addresses spines by their physical position on the
+//! icosahedron and led_spine_mapping takes care of which connector each one
+//! is wired to, so the operator doesn't need to know the wiring.
+//!
+//! Art-Net numbers universes from 0 and sACN from 1, and `dmx_universe` is
+//! used as-is for both, so patch the desk accordingly.  The DMX settings can
+//! be changed on the fly, and we join the multicast groups for the new
+//! universes.
+
+use crate::control_server::{self, allowed_patterns, Controls, CONTROLS};
+use crate::external_input;
+use crate::health;
+use crate::settings::{self, DmxMode, Settings};
+use crate::{LEDS_PER_SPINE, SPINES};
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const SACN_PORT: u16 = 5568;
+const ARTNET_PORT: u16 = 6454;
+
+/// Number of channels in a DMX universe
+const UNIVERSE_SIZE: usize = 512;
+
+/// Identifies an E1.31 packet, at offset 4
+const SACN_PACKET_ID: &[u8] = b"ASC-E1.17\0\0\0";
+const SACN_VECTOR_ROOT_DATA: u32 = 0x0000_0004;
+const SACN_VECTOR_FRAMING_DATA: u32 = 0x0000_0002;
+/// Offset of the DMX start code in an E1.31 data packet
+const SACN_DMX_OFFSET: usize = 125;
+/// E1.31 framing options flags
+const SACN_OPTION_PREVIEW: u8 = 0x80;
+const SACN_OPTION_TERMINATED: u8 = 0x40;
+
+/// Identifies an Art-Net packet
+const ARTNET_ID: &[u8] = b"Art-Net\0";
+const ARTNET_OP_DMX: u16 = 0x5000;
+/// Offset of the DMX data in an ArtDmx packet
+const ARTNET_DMX_OFFSET: usize = 18;
+
+/// The most negative rainbow_swirl_speed the speed channel can select
+const MAX_SPEED: f64 = -12.0;
+
+/// Save the controls once the desk hasn't changed them for this long
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+/// The control channels as last applied
+struct ControlChannels {
+    /// The values, so we only change things when the desk does and the
+    /// control panel can still be used in between
+    values: [u8; 3],
+    /// When the values last changed, if the controls haven't been saved since
+    changed: Option<Instant>,
+}
+
+lazy_static! {
+    static ref LAST_CONTROLS: Mutex<ControlChannels> = Mutex::new(ControlChannels {
+        values: [0; 3],
+        changed: None,
+    });
+}
+
+/// Number of universes we listen to with these settings
+pub fn universes(settings: &Settings) -> usize {
+    match (settings.dmx_mode, settings.dmx_universe_per_spine) {
+        (DmxMode::Control, _) => 1,
+        (DmxMode::Pixels, true) => SPINES,
+        (DmxMode::Pixels, false) => {
+            let per_universe = (UNIVERSE_SIZE - (settings.dmx_channel - 1)) / 3;
+            (SPINES * LEDS_PER_SPINE).div_ceil(per_universe)
+        }
+    }
+}
+
+/// What a DMX universe's channels are for
+#[derive(Debug, PartialEq)]
+enum DmxData<'a> {
+    /// RGB data starting at a pixel
+    Pixels(usize, &'a [u8]),
+    /// The pattern, brightness and speed control channels
+    Controls([u8; 3]),
+}
+
+/// Work out what the channels of a DMX universe are for, if anything.
+/// `data[0]` is channel 1.
+fn dmx_data<'a>(settings: &Settings, universe: u16, data: &'a [u8]) -> Option<DmxData<'a>> {
+    if universe < settings.dmx_universe {
+        return None;
+    }
+    let index = (universe - settings.dmx_universe) as usize;
+    let data = data.get(settings.dmx_channel - 1..).unwrap_or(&[]);
+
+    match settings.dmx_mode {
+        DmxMode::Pixels if settings.dmx_universe_per_spine => {
+            let len = data.len().min(LEDS_PER_SPINE * 3);
+            (index < SPINES).then(|| DmxData::Pixels(index * LEDS_PER_SPINE, &data[..len]))
+        }
+        DmxMode::Pixels => {
+            let per_universe = (UNIVERSE_SIZE - (settings.dmx_channel - 1)) / 3;
+            let len = data.len().min(per_universe * 3);
+            (index * per_universe < SPINES * LEDS_PER_SPINE)
+                .then(|| DmxData::Pixels(index * per_universe, &data[..len]))
+        }
+        DmxMode::Control if index == 0 && data.len() >= 3 => {
+            Some(DmxData::Controls([data[0], data[1], data[2]]))
+        }
+        DmxMode::Control => None,
+    }
+}
+
+/// Handle the channels of a DMX universe
+fn handle_dmx(universe: u16, data: &[u8]) {
+    match dmx_data(&settings::get(), universe, data) {
+        Some(DmxData::Pixels(start, rgb)) => external_input::set_pixels(start, rgb),
+        Some(DmxData::Controls(values)) => apply_controls(values),
+        None => {}
+    }
+}
+
+/// Apply the pattern, brightness and speed control channels
+fn apply_controls(values: [u8; 3]) {
+    let mut last = LAST_CONTROLS.lock().unwrap();
+    if last.values == values {
+        return;
+    }
+    let mut controls = CONTROLS.write().unwrap();
+    change_controls(&mut controls, last.values, values, allowed_patterns());
+
+    info!(
+        "DMX control: pattern {}, brightness {}%",
+        controls.pattern, controls.brightness
+    );
+    last.values = values;
+    last.changed = Some(Instant::now());
+}
+
+/// Change the controls for the control channels which have changed since
+/// `previous`
+fn change_controls(
+    controls: &mut Controls,
+    previous: [u8; 3],
+    values: [u8; 3],
+    patterns: &[String],
+) {
+    let [pattern, brightness, speed] = values;
+    if pattern != previous[0] && pattern > 0 {
+        // Share the values out evenly between the patterns
+        let idx = (pattern as usize - 1) * patterns.len() / 255;
+        controls.pattern = patterns[idx].clone();
+    }
+    if brightness != previous[1] && brightness > 0 {
+        controls.brightness = (brightness as u32 * 100 / 255) as u8;
+    }
+    if speed != previous[2] {
+        if speed > 0 {
+            let speed = MAX_SPEED * speed as f64 / 255.0;
+            controls
+                .params
+                .insert("rainbow_swirl_speed".to_owned(), speed);
+        } else {
+            controls.params.remove("rainbow_swirl_speed");
+        }
+    }
+}
+
+/// Save the controls if the desk has changed them and then left them alone
+/// for long enough.  Desks send their channels many times a second, so we
+/// don't want to save every change as a fader moves.
+fn save_if_settled() {
+    let mut last = LAST_CONTROLS.lock().unwrap();
+    if last.changed.is_some_and(|x| x.elapsed() >= SAVE_DELAY) {
+        last.changed = None;
+        drop(last);
+        control_server::save_controls();
+    }
+}
+
+/// Receive a DMX packet, saving the controls in between if they've settled.
+/// Returns None if nothing arrived for a while.
+fn receive(socket: &UdpSocket, buf: &mut [u8]) -> Result<Option<(usize, SocketAddr)>> {
+    let received = socket.recv_from(buf);
+    save_if_settled();
+    match received {
+        Ok(x) => Ok(Some(x)),
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Find the universe and DMX data in an sACN packet.  Returns None for
+/// packets which aren't DMX data we should use.
+fn sacn_packet(packet: &[u8]) -> Result<Option<(u16, &[u8])>> {
+    if packet.len() < SACN_DMX_OFFSET + 1 || &packet[4..16] != SACN_PACKET_ID {
+        return Err(anyhow!("not an E1.31 packet"));
+    }
+    let root_vector = u32::from_be_bytes([packet[18], packet[19], packet[20], packet[21]]);
+    let framing_vector = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]);
+    if root_vector != SACN_VECTOR_ROOT_DATA || framing_vector != SACN_VECTOR_FRAMING_DATA {
+        // Synchronisation or discovery packets, which we don't need
+        return Ok(None);
+    }
+
+    let options = packet[112];
+    let universe = u16::from_be_bytes([packet[113], packet[114]]);
+    let count = u16::from_be_bytes([packet[123], packet[124]]) as usize;
+    let start_code = packet[SACN_DMX_OFFSET];
+    if options & (SACN_OPTION_PREVIEW | SACN_OPTION_TERMINATED) != 0 || start_code != 0 {
+        return Ok(None);
+    }
+
+    // The count includes the start code
+    let data = packet
+        .get(SACN_DMX_OFFSET + 1..SACN_DMX_OFFSET + count.max(1))
+        .ok_or_else(|| anyhow!("E1.31 packet shorter than its header says"))?;
+    Ok(Some((universe, data)))
+}
+
+/// sACN's multicast group for a universe
+fn sacn_group(universe: u16) -> Ipv4Addr {
+    let [hi, lo] = universe.to_be_bytes();
+    Ipv4Addr::new(239, 255, hi, lo)
+}
+
+/// Join the multicast groups for the universes we listen to, and leave those
+/// for universes we no longer listen to since the settings changed
+fn join_universes(socket: &UdpSocket, joined: &mut Vec<u16>) -> Result<()> {
+    let settings = settings::get();
+    let wanted: Vec<u16> = (settings.dmx_universe..)
+        .take(universes(&settings))
+        .collect();
+    if *joined == wanted {
+        return Ok(());
+    }
+    for universe in joined.iter().filter(|x| !wanted.contains(x)) {
+        socket.leave_multicast_v4(&sacn_group(*universe), &Ipv4Addr::UNSPECIFIED)?;
+    }
+    for universe in wanted.iter().filter(|x| !joined.contains(x)) {
+        socket.join_multicast_v4(&sacn_group(*universe), &Ipv4Addr::UNSPECIFIED)?;
+    }
+    info!("Listening for sACN universes {:?}", wanted);
+    *joined = wanted;
+    Ok(())
+}
+
+fn sacn_server() -> Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", SACN_PORT))?;
+
+    // sACN is usually multicast, with a group for each universe
+    let mut joined = Vec::new();
+    join_universes(&socket, &mut joined)?;
+
+    info!("Listening for sACN on port {}", SACN_PORT);
+    socket.set_read_timeout(Some(SAVE_DELAY))?;
+    let mut buf = [0u8; 1500];
+    loop {
+        let received = receive(&socket, &mut buf)?;
+        join_universes(&socket, &mut joined)?;
+        let (len, peer) = match received {
+            Some(x) => x,
+            None => continue,
+        };
+        match sacn_packet(&buf[..len]) {
+            Ok(Some((universe, data))) => handle_dmx(universe, data),
+            Ok(None) => {}
+            Err(e) => warn!("Bad sACN packet from {}: {:#}", peer, e),
+        }
+    }
+}
+
+/// Find the universe and DMX data in an Art-Net packet.  Returns None for
+/// packets which aren't DMX data.
+fn artnet_packet(packet: &[u8]) -> Result<Option<(u16, &[u8])>> {
+    if packet.len() < ARTNET_DMX_OFFSET || &packet[..8] != ARTNET_ID {
+        return Err(anyhow!("not an Art-Net packet"));
+    }
+    if u16::from_le_bytes([packet[8], packet[9]]) != ARTNET_OP_DMX {
+        // Polls and other housekeeping, which we don't need
+        return Ok(None);
+    }
+
+    let universe = u16::from_le_bytes([packet[14], packet[15]]) & 0x7fff;
+    let len = u16::from_be_bytes([packet[16], packet[17]]) as usize;
+    let data = packet
+        .get(ARTNET_DMX_OFFSET..ARTNET_DMX_OFFSET + len)
+        .ok_or_else(|| anyhow!("Art-Net packet shorter than its header says"))?;
+    Ok(Some((universe, data)))
+}
+
+fn artnet_server() -> Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", ARTNET_PORT))?;
+    info!("Listening for Art-Net on port {}", ARTNET_PORT);
+    socket.set_read_timeout(Some(SAVE_DELAY))?;
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, peer) = match receive(&socket, &mut buf)? {
+            Some(x) => x,
+            None => continue,
+        };
+        match artnet_packet(&buf[..len]) {
+            Ok(Some((universe, data))) => handle_dmx(universe, data),
+            Ok(None) => {}
+            Err(e) => warn!("Bad Art-Net packet from {}: {:#}", peer, e),
+        }
+    }
+}
+
+/// Start listening for DMX, if enabled in the settings
+pub fn start() {
+    if !settings::get().dmx_input {
+        return;
+    }
+
+    health::spawn_supervised("sACN input", None, sacn_server);
+    health::spawn_supervised("Art-Net input", None, artnet_server);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sacn(universe: u16, options: u8, start_code: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; SACN_DMX_OFFSET];
+        packet[4..16].copy_from_slice(SACN_PACKET_ID);
+        packet[18..22].copy_from_slice(&SACN_VECTOR_ROOT_DATA.to_be_bytes());
+        packet[40..44].copy_from_slice(&SACN_VECTOR_FRAMING_DATA.to_be_bytes());
+        packet[112] = options;
+        packet[113..115].copy_from_slice(&universe.to_be_bytes());
+        packet[123..125].copy_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+        packet.push(start_code);
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    fn artnet(opcode: u16, universe: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = ARTNET_ID.to_vec();
+        packet.extend_from_slice(&opcode.to_le_bytes());
+        packet.extend_from_slice(&[0, 14, 0, 0]);
+        packet.extend_from_slice(&universe.to_le_bytes());
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    fn settings(mode: DmxMode, universe: u16, channel: usize, per_spine: bool) -> Settings {
+        Settings {
+            dmx_mode: mode,
+            dmx_universe: universe,
+            dmx_channel: channel,
+            dmx_universe_per_spine: per_spine,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn sacn_packets() {
+        let data = [1, 2, 3];
+        assert_eq!(
+            sacn_packet(&sacn(5, 0, 0, &data)).unwrap(),
+            Some((5, &data[..]))
+        );
+
+        // Data after the count in the header is ignored
+        let mut packet = sacn(5, 0, 0, &data);
+        packet.extend_from_slice(&[9; 3]);
+        assert_eq!(sacn_packet(&packet).unwrap(), Some((5, &data[..])));
+
+        // Not DMX data we should use
+        assert_eq!(
+            sacn_packet(&sacn(5, SACN_OPTION_PREVIEW, 0, &data)).unwrap(),
+            None
+        );
+        assert_eq!(
+            sacn_packet(&sacn(5, SACN_OPTION_TERMINATED, 0, &data)).unwrap(),
+            None
+        );
+        assert_eq!(sacn_packet(&sacn(5, 0, 0xdd, &data)).unwrap(), None);
+        let mut packet = sacn(5, 0, 0, &data);
+        packet[40..44].copy_from_slice(&1u32.to_be_bytes());
+        assert_eq!(sacn_packet(&packet).unwrap(), None);
+    }
+
+    #[test]
+    fn sacn_bad_packets() {
+        let packet = sacn(5, 0, 0, &[1, 2, 3]);
+        assert!(sacn_packet(&packet[..SACN_DMX_OFFSET]).is_err());
+        assert!(sacn_packet(&packet[..packet.len() - 1]).is_err());
+        let mut packet = packet;
+        packet[4] = b'X';
+        assert!(sacn_packet(&packet).is_err());
+    }
+
+    #[test]
+    fn artnet_packets() {
+        let data = [1, 2, 3];
+        assert_eq!(
+            artnet_packet(&artnet(ARTNET_OP_DMX, 5, &data)).unwrap(),
+            Some((5, &data[..]))
+        );
+        // The top bit of the universe isn't part of it
+        assert_eq!(
+            artnet_packet(&artnet(ARTNET_OP_DMX, 0x8005, &data)).unwrap(),
+            Some((5, &data[..]))
+        );
+        // A poll
+        assert_eq!(artnet_packet(&artnet(0x2000, 5, &data)).unwrap(), None);
+
+        let packet = artnet(ARTNET_OP_DMX, 5, &data);
+        assert!(artnet_packet(&packet[..ARTNET_DMX_OFFSET - 1]).is_err());
+        assert!(artnet_packet(&packet[..packet.len() - 1]).is_err());
+        assert!(artnet_packet(&packet[1..]).is_err());
+    }
+
+    #[test]
+    fn universe_per_spine() {
+        let settings = settings(DmxMode::Pixels, 3, 4, true);
+        let data = [7; UNIVERSE_SIZE];
+        assert_eq!(dmx_data(&settings, 2, &data), None);
+        assert_eq!(
+            dmx_data(&settings, 3, &data),
+            Some(DmxData::Pixels(0, &data[3..3 + LEDS_PER_SPINE * 3]))
+        );
+        assert_eq!(
+            dmx_data(&settings, 3 + SPINES as u16 - 1, &data[..9]),
+            Some(DmxData::Pixels((SPINES - 1) * LEDS_PER_SPINE, &data[3..9]))
+        );
+        assert_eq!(dmx_data(&settings, 3 + SPINES as u16, &data), None);
+        assert_eq!(universes(&settings), SPINES);
+    }
+
+    #[test]
+    fn packed_universes() {
+        let settings = settings(DmxMode::Pixels, 1, 1, false);
+        let data = [7; UNIVERSE_SIZE];
+        assert_eq!(
+            dmx_data(&settings, 1, &data),
+            Some(DmxData::Pixels(0, &data[..510]))
+        );
+        assert_eq!(
+            dmx_data(&settings, 2, &data),
+            Some(DmxData::Pixels(170, &data[..510]))
+        );
+        assert_eq!(
+            dmx_data(&settings, 5, &data),
+            Some(DmxData::Pixels(680, &data[..510]))
+        );
+        assert_eq!(dmx_data(&settings, 6, &data), None);
+        assert_eq!(universes(&settings), 5);
+    }
+
+    #[test]
+    fn control_channels() {
+        let settings = settings(DmxMode::Control, 2, 10, true);
+        let mut data = [0; 12];
+        data[9..12].copy_from_slice(&[1, 2, 3]);
+        assert_eq!(
+            dmx_data(&settings, 2, &data),
+            Some(DmxData::Controls([1, 2, 3]))
+        );
+        assert_eq!(dmx_data(&settings, 2, &data[..11]), None);
+        assert_eq!(dmx_data(&settings, 3, &data), None);
+        assert_eq!(universes(&settings), 1);
+    }
+
+    #[test]
+    fn controls_scaled() {
+        let patterns = ["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        let mut controls = Controls::default();
+        change_controls(&mut controls, [0; 3], [1, 255, 255], &patterns);
+        assert_eq!(controls.pattern, "a");
+        assert_eq!(controls.brightness, 100);
+        assert_eq!(controls.params["rainbow_swirl_speed"], MAX_SPEED);
+
+        change_controls(&mut controls, [1, 255, 255], [128, 128, 0], &patterns);
+        assert_eq!(controls.pattern, "b");
+        assert_eq!(controls.brightness, 50);
+        assert!(!controls.params.contains_key("rainbow_swirl_speed"));
+
+        change_controls(&mut controls, [128, 128, 0], [255, 128, 0], &patterns);
+        assert_eq!(controls.pattern, "c");
+    }
+
+    #[test]
+    fn controls_left_alone() {
+        let patterns = ["a".to_owned(), "b".to_owned()];
+        let mut controls = Controls::default();
+        controls.pattern = "b".to_owned();
+        controls.brightness = 30;
+
+        // Channels at 0, or which haven't changed, leave the control panel's
+        // settings alone
+        change_controls(&mut controls, [0; 3], [0, 0, 0], &patterns);
+        change_controls(&mut controls, [1, 255, 0], [1, 255, 0], &patterns);
+        assert_eq!(controls.pattern, "b");
+        assert_eq!(controls.brightness, 30);
+    }
+}
@@ -0,0 +1,61 @@
+//! "burst" effect: a random colour bursts out from the core along every
+//! spine, then fades away
+
+use crate::common_structs::LedUpdate;
+use crate::effects::{blend, Effect};
+use crate::LEDS_PER_SPINE;
+use color_space::{Hsv, Rgb};
+use rand::Rng;
+
+/// How fast the burst moves out along the spines, in pixels per frame
+const SPEED: f32 = 2.0;
+
+/// How long the burst takes to fade once it reaches the tips, in frames
+const FADE_FRAMES: usize = 30;
+
+pub struct Burst {
+    /// How far along the spines the burst has reached, in pixels
+    position: f32,
+
+    /// Frames spent fading out since the burst reached the tips
+    fade: usize,
+
+    colour: [u8; 3],
+}
+
+impl Burst {
+    pub const NAME: &'static str = "burst";
+}
+
+impl Effect for Burst {
+    fn new() -> Box<dyn Effect> {
+        let mut rng = rand::thread_rng();
+        let rgb = Rgb::from(Hsv::new(rng.gen::<f64>() * 360.0, 1.0, 1.0));
+        Box::new(Self {
+            position: 0.0,
+            fade: 0,
+            colour: [rgb.r as u8, rgb.g as u8, rgb.b as u8],
+        })
+    }
+
+    fn step(&mut self, leds: &mut LedUpdate) -> bool {
+        let amount = 1.0 - self.fade as f32 / FADE_FRAMES as f32;
+        let lit = usize::min(self.position as usize, LEDS_PER_SPINE);
+        for spine in leds.spines.iter_mut() {
+            for led in spine[..lit].iter_mut() {
+                blend(led, self.colour, amount);
+            }
+        }
+
+        if lit < LEDS_PER_SPINE {
+            self.position += SPEED;
+        } else {
+            self.fade += 1;
+        }
+        self.fade < FADE_FRAMES
+    }
+
+    fn get_name(&self) -> &'static str {
+        Self::NAME
+    }
+}
@@ -0,0 +1,38 @@
+//! "flash" effect: light everything up white then fade back to the pattern
+
+use crate::common_structs::LedUpdate;
+use crate::effects::{blend, Effect};
+
+/// How long the flash takes to fade out, in frames
+const FADE_FRAMES: usize = 20;
+
+pub struct Flash {
+    /// Frames since the effect was triggered
+    i: usize,
+}
+
+impl Flash {
+    pub const NAME: &'static str = "flash";
+}
+
+impl Effect for Flash {
+    fn new() -> Box<dyn Effect> {
+        Box::new(Self { i: 0 })
+    }
+
+    fn step(&mut self, leds: &mut LedUpdate) -> bool {
+        let amount = 1.0 - self.i as f32 / FADE_FRAMES as f32;
+        for spine in leds.spines.iter_mut() {
+            for led in spine.iter_mut() {
+                blend(led, [255, 255, 255], amount);
+            }
+        }
+
+        self.i += 1;
+        self.i < FADE_FRAMES
+    }
+
+    fn get_name(&self) -> &'static str {
+        Self::NAME
+    }
+}
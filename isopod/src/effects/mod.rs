@@ -0,0 +1,134 @@
+//! One-shot effects which are drawn over the top of whatever pattern is
+//! currently playing, e.g. when a member of the audience presses a button on
+//! the control server.  Effects are independent of the pattern manager, so
+//! they carry on through pattern transitions.
+
+use crate::common_structs::LedUpdate;
+
+use lazy_static::lazy_static;
+use log::info;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub mod burst;
+pub mod flash;
+pub mod ripple;
+
+/// How many effects can be running at once.  Further triggers are ignored
+/// until one of the running effects finishes.
+const MAX_ACTIVE_EFFECTS: usize = 4;
+
+/// Interface implemented by all one-shot effects
+pub trait Effect {
+    /// Create a new instance of the effect.  This is called each time the
+    /// effect is triggered.
+    #[allow(clippy::new_ret_no_self)]
+    fn new() -> Box<dyn Effect>
+    where
+        Self: Sized;
+
+    /// Draw the next frame of the effect over the top of the provided LED
+    /// state.  Called once per frame.  Returns false once the effect has
+    /// finished, after which it will be dropped.
+    fn step(&mut self, leds: &mut LedUpdate) -> bool;
+
+    /// Get the name of this effect.  Used to trigger it from the control
+    /// server.
+    fn get_name(&self) -> &'static str;
+}
+
+/// Function which creates a new instance of an effect
+type EffectConstructor = fn() -> Box<dyn Effect>;
+
+lazy_static! {
+    static ref EFFECTS: HashMap<&'static str, EffectConstructor> = HashMap::from([
+        (
+            flash::Flash::NAME,
+            flash::Flash::new as fn() -> Box<dyn Effect>
+        ),
+        (
+            ripple::Ripple::NAME,
+            ripple::Ripple::new as fn() -> Box<dyn Effect>
+        ),
+        (
+            burst::Burst::NAME,
+            burst::Burst::new as fn() -> Box<dyn Effect>
+        ),
+    ]);
+
+    /// Effects which have been triggered but not yet picked up by the main
+    /// loop
+    static ref PENDING: Mutex<Vec<EffectConstructor>> = Mutex::new(Vec::new());
+}
+
+/// Get the constructor for an effect from its name
+pub fn effect_by_name(name: &str) -> Option<EffectConstructor> {
+    EFFECTS.get(name).copied()
+}
+
+/// Request that an effect is started on the next frame.  Can be called from
+/// any thread.  Returns false if there is no effect with this name.
+pub fn trigger(name: &str) -> bool {
+    match effect_by_name(name) {
+        Some(cons) => {
+            let mut pending = PENDING.lock().unwrap();
+            if pending.len() < MAX_ACTIVE_EFFECTS {
+                pending.push(cons);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Runs the currently active effects and composites them over the pattern
+/// output.
+pub struct EffectManager {
+    active: Vec<Box<dyn Effect>>,
+
+    /// The pattern output with effects drawn over the top.  Only used when
+    /// at least one effect is running.
+    leds: LedUpdate,
+}
+
+impl EffectManager {
+    pub fn new() -> Self {
+        Self {
+            active: Vec::new(),
+            leds: LedUpdate::default(),
+        }
+    }
+
+    /// Draw any active effects over the top of the provided pattern output.
+    /// If no effects are running then the pattern output is passed straight
+    /// through without copying it.
+    pub fn apply<'a>(&'a mut self, pattern_leds: &'a LedUpdate) -> &'a LedUpdate {
+        for cons in PENDING.lock().unwrap().drain(..) {
+            if self.active.len() < MAX_ACTIVE_EFFECTS {
+                let effect = cons();
+                info!("Starting effect {}", effect.get_name());
+                self.active.push(effect);
+            }
+        }
+
+        if self.active.is_empty() {
+            return pattern_leds;
+        }
+
+        self.leds.clone_from(pattern_leds);
+        let leds = &mut self.leds;
+        self.active.retain_mut(|effect| effect.step(leds));
+
+        &self.leds
+    }
+}
+
+/// Mix `colour` into `led` by `amount` (0.0 leaves the LED untouched, 1.0
+/// replaces it entirely).  Shared by the effects for blending themselves over
+/// the pattern.
+pub fn blend(led: &mut [u8; 3], colour: [u8; 3], amount: f32) {
+    let amount = amount.clamp(0.0, 1.0);
+    for (sub, target) in led.iter_mut().zip(colour) {
+        *sub = f32::round(*sub as f32 * (1.0 - amount) + target as f32 * amount) as u8;
+    }
+}
@@ -0,0 +1,68 @@
+//! "ripple" effect: a ring of colour spreads out across the sculpture from
+//! the tip of a randomly chosen spine
+
+use crate::common_structs::LedUpdate;
+use crate::effects::{blend, Effect};
+use crate::patterns::geometry::SPINE_DIRECTIONS;
+use crate::LEDS_PER_SPINE;
+use color_space::{Hsv, Rgb};
+use rand::Rng;
+
+/// How far the ring travels each frame, in units of spine length
+const SPEED: f32 = 0.04;
+
+/// Thickness of the ring, in units of spine length
+const WIDTH: f32 = 0.25;
+
+/// The ring has finished once it's this big, which is past the far side of
+/// the sculpture
+const MAX_RADIUS: f32 = 2.0 + WIDTH;
+
+pub struct Ripple {
+    /// The spine the ripple starts from
+    origin: usize,
+
+    /// Current radius of the ring, in units of spine length
+    radius: f32,
+
+    colour: [u8; 3],
+}
+
+impl Ripple {
+    pub const NAME: &'static str = "ripple";
+}
+
+impl Effect for Ripple {
+    fn new() -> Box<dyn Effect> {
+        let mut rng = rand::thread_rng();
+        let rgb = Rgb::from(Hsv::new(rng.gen::<f64>() * 360.0, 1.0, 1.0));
+        Box::new(Self {
+            origin: rng.gen_range(0..SPINE_DIRECTIONS.len()),
+            radius: 0.0,
+            colour: [rgb.r as u8, rgb.g as u8, rgb.b as u8],
+        })
+    }
+
+    fn step(&mut self, leds: &mut LedUpdate) -> bool {
+        let origin = SPINE_DIRECTIONS[self.origin].as_vector3d().clone();
+
+        for (spine_idx, spine) in leds.spines.iter_mut().enumerate() {
+            let direction = SPINE_DIRECTIONS[spine_idx].as_vector3d();
+            for (led_idx, led) in spine.iter_mut().enumerate() {
+                let position = direction.scale(led_idx as f32 / LEDS_PER_SPINE as f32);
+                let distance = (position - origin.clone()).magnitude();
+                let amount = 1.0 - (distance - self.radius).abs() / WIDTH;
+                if amount > 0.0 {
+                    blend(led, self.colour, amount);
+                }
+            }
+        }
+
+        self.radius += SPEED;
+        self.radius < MAX_RADIUS
+    }
+
+    fn get_name(&self) -> &'static str {
+        Self::NAME
+    }
+}
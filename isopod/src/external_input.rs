@@ -0,0 +1,362 @@
+//! Lets external software drive the LEDs over the network, using Open Pixel
+//! Control over TCP or DDP over UDP.  When a stream starts, ISOPOD switches to
+//! the "external" pattern which plays the incoming frames, and when the
+//! stream stops for longer than `external_timeout` it switches back to
+//! whatever was playing before.
+//!
+//! Pixels are numbered spine by spine, so pixel 0 is the LED nearest the
+//! centre on spine position 1 and pixel 59 is the first LED on spine position
+//! 2, giving 708 pixels in all.  Spine positions are the physical positions on
+//! the icosahedron, as used by the patterns; led_spine_mapping takes care of
+//! which connector they're wired to.
+//!
+//! OPC: each message is a 4-byte header (channel, command, length as a
+//! big-endian u16) followed by `length` bytes of data.  Only command 0 (set
+//! pixel colours) is supported.  Channel 0 addresses all 708 pixels, and
+//! channels 1-12 address the 59 pixels on a single spine position.
+//!
+//! DDP: each packet is a 10-byte header, or 14 bytes if the timecode flag is
+//! set, followed by RGB data to write starting at the byte offset given in the
+//! header.  Query, reply and non-display packets are ignored.
+
+use crate::common_structs::LedUpdate;
+use crate::control_server::CONTROLS;
+use crate::health;
+use crate::settings;
+use crate::{LEDS_PER_SPINE, SPINES};
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::io::Read;
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// OPC command to set pixel colours
+const OPC_SET_PIXELS: u8 = 0;
+
+/// DDP header flags
+const DDP_FLAG_TIMECODE: u8 = 0x10;
+const DDP_FLAG_REPLY: u8 = 0x04;
+const DDP_FLAG_QUERY: u8 = 0x02;
+
+/// DDP destination IDs from here up are for status and config rather than
+/// pixel data
+const DDP_FIRST_RESERVED_ID: u8 = 246;
+
+/// The latest pixels received from external software
+struct ExternalFrame {
+    leds: LedUpdate,
+    /// When pixels were last received, or None if they never have been
+    received: Option<Instant>,
+    /// The pattern chosen in the controls when the external input took over,
+    /// or None if it hasn't.  The takeover isn't written to the controls, so
+    /// it's never saved, and it ends if someone chooses another pattern.
+    taken_over_from: Option<String>,
+}
+
+impl ExternalFrame {
+    fn taken_over(&self) -> bool {
+        self.taken_over_from.as_ref() == Some(&CONTROLS.read().unwrap().pattern)
+    }
+}
+
+lazy_static! {
+    static ref FRAME: Mutex<ExternalFrame> = Mutex::new(ExternalFrame {
+        leds: LedUpdate::default(),
+        received: None,
+        taken_over_from: None,
+    });
+}
+
+fn timeout() -> Duration {
+    Duration::from_secs(settings::get().external_timeout)
+}
+
+/// Write RGB data into `leds`, starting at the given pixel.  Data beyond the
+/// last pixel is ignored.
+fn write_pixels(leds: &mut LedUpdate, start: usize, rgb: &[u8]) {
+    for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+        let n = start + i;
+        if n >= SPINES * LEDS_PER_SPINE {
+            break;
+        }
+        leds.spines[n / LEDS_PER_SPINE][n % LEDS_PER_SPINE] = [pixel[0], pixel[1], pixel[2]];
+    }
+}
+
+/// Write RGB data into the external frame, starting at the given pixel.  Data
+/// beyond the last pixel is ignored.  If this is the start of a new stream
+/// then the external pattern takes over.
+pub fn set_pixels(start: usize, rgb: &[u8]) {
+    let mut frame = FRAME.lock().unwrap();
+    write_pixels(&mut frame.leds, start, rgb);
+
+    let stream_starting = frame
+        .received
+        .map(|x| x.elapsed() > timeout())
+        .unwrap_or(true);
+    frame.received = Some(Instant::now());
+
+    // Only take over when a stream starts, so that if someone picks another
+    // pattern from the control panel while a stream is running, we let them.
+    if stream_starting && !frame.taken_over() {
+        let pattern = CONTROLS.read().unwrap().pattern.clone();
+        info!("External input started, taking over from {}", pattern);
+        frame.taken_over_from = Some(pattern);
+    }
+}
+
+/// Whether the external pattern should be playing instead of the pattern
+/// chosen in the controls
+pub fn taken_over() -> bool {
+    FRAME.lock().unwrap().taken_over()
+}
+
+/// Copy the latest external pixels into `leds`.  Returns false if the stream
+/// has stopped.
+pub fn get_frame(leds: &mut LedUpdate) -> bool {
+    let frame = FRAME.lock().unwrap();
+    leds.clone_from(&frame.leds);
+    frame
+        .received
+        .map(|x| x.elapsed() <= timeout())
+        .unwrap_or(false)
+}
+
+/// Switch back to the pattern which was playing before the external input
+/// took over
+pub fn give_back() {
+    let mut frame = FRAME.lock().unwrap();
+    if frame.taken_over() {
+        info!("External input stopped, going back to {}", CONTROLS.read().unwrap().pattern);
+    }
+    frame.taken_over_from = None;
+}
+
+/// Read one OPC message into `data`, returning its channel and command, or
+/// None if the client closed the connection between messages
+fn opc_read(stream: &mut impl Read, data: &mut Vec<u8>) -> Result<Option<(u8, u8)>> {
+    let mut header = [0u8; 4];
+    match stream.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let [channel, command, len_hi, len_lo] = header;
+    data.resize(u16::from_be_bytes([len_hi, len_lo]) as usize, 0);
+    stream.read_exact(data)?;
+    Ok(Some((channel, command)))
+}
+
+/// Work out which pixels an OPC message sets, as the first pixel and the RGB
+/// data for it and the following pixels.  Returns None for messages we
+/// ignore.
+fn opc_pixels(channel: u8, command: u8, data: &[u8]) -> Option<(usize, &[u8])> {
+    if command != OPC_SET_PIXELS {
+        return None;
+    }
+    match channel as usize {
+        0 => Some((0, data)),
+        spine @ 1..=SPINES => {
+            let len = data.len().min(LEDS_PER_SPINE * 3);
+            Some(((spine - 1) * LEDS_PER_SPINE, &data[..len]))
+        }
+        _ => None,
+    }
+}
+
+/// Handle one OPC client until it disconnects
+fn opc_client(mut stream: TcpStream) -> Result<()> {
+    let mut data = Vec::new();
+    while let Some((channel, command)) = opc_read(&mut stream, &mut data)? {
+        if let Some((start, rgb)) = opc_pixels(channel, command, &data) {
+            set_pixels(start, rgb);
+        }
+    }
+    Ok(())
+}
+
+fn opc_server(port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    info!("Listening for OPC on port {}", port);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr().ok();
+        info!("OPC client connected from {:?}", peer);
+        thread::Builder::new()
+            .name("ISOPOD OPC client".into())
+            .spawn(move || {
+                if let Err(e) = opc_client(stream) {
+                    warn!("OPC client {:?} failed: {:#}", peer, e);
+                }
+                info!("OPC client {:?} disconnected", peer);
+            })?;
+    }
+    Ok(())
+}
+
+/// Work out which pixels a DDP packet sets, as the first pixel and the RGB
+/// data for it and the following pixels.  Returns None for packets we
+/// ignore.
+fn ddp_pixels(packet: &[u8]) -> Result<Option<(usize, &[u8])>> {
+    if packet.len() < 10 {
+        return Err(anyhow!("DDP packet too short"));
+    }
+    let flags = packet[0];
+    let destination = packet[3];
+    if flags & (DDP_FLAG_QUERY | DDP_FLAG_REPLY) != 0 || destination >= DDP_FIRST_RESERVED_ID {
+        return Ok(None);
+    }
+
+    let offset = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]) as usize;
+    let len = u16::from_be_bytes([packet[8], packet[9]]) as usize;
+    let header_len = if flags & DDP_FLAG_TIMECODE != 0 { 14 } else { 10 };
+    let data = packet
+        .get(header_len..header_len + len)
+        .ok_or_else(|| anyhow!("DDP packet shorter than its header says"))?;
+
+    // Offsets are in bytes, but we can only start on a pixel boundary
+    let (pixel, remainder) = (offset / 3, offset % 3);
+    if remainder != 0 {
+        return Err(anyhow!("DDP offset {} isn't a whole pixel", offset));
+    }
+    Ok(Some((pixel, data)))
+}
+
+fn ddp_server(port: u16) -> Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    info!("Listening for DDP on port {}", port);
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf)?;
+        match ddp_pixels(&buf[..len]) {
+            Ok(Some((start, rgb))) => set_pixels(start, rgb),
+            Ok(None) => {}
+            Err(e) => warn!("Bad DDP packet from {}: {:#}", peer, e),
+        }
+    }
+}
+
+/// Start listening for external pixel data, if enabled in the settings
+pub fn start() {
+    let settings = settings::get();
+    if !settings.external_input {
+        return;
+    }
+
+    let opc_port = settings.opc_port;
+    health::spawn_supervised("OPC input", None, move || opc_server(opc_port));
+    let ddp_port = settings.ddp_port;
+    health::spawn_supervised("DDP input", None, move || ddp_server(ddp_port));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opc_message(channel: u8, command: u8, data: &[u8]) -> Vec<u8> {
+        let mut message = vec![channel, command];
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(data);
+        message
+    }
+
+    fn ddp_packet(flags: u8, destination: u8, offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![flags, 0, 1, destination];
+        packet.extend_from_slice(&offset.to_be_bytes());
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        if flags & DDP_FLAG_TIMECODE != 0 {
+            packet.extend_from_slice(&[0; 4]);
+        }
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn opc_read_messages() {
+        let mut stream = opc_message(3, OPC_SET_PIXELS, &[1, 2, 3]);
+        stream.extend(opc_message(0, 255, &[]));
+        let mut stream = &stream[..];
+        let mut data = Vec::new();
+        assert_eq!(opc_read(&mut stream, &mut data).unwrap(), Some((3, OPC_SET_PIXELS)));
+        assert_eq!(data, [1, 2, 3]);
+        assert_eq!(opc_read(&mut stream, &mut data).unwrap(), Some((0, 255)));
+        assert!(data.is_empty());
+        assert_eq!(opc_read(&mut stream, &mut data).unwrap(), None);
+    }
+
+    #[test]
+    fn opc_read_truncated() {
+        let mut data = Vec::new();
+        // Truncated header
+        assert!(opc_read(&mut &[0, 0, 0][..], &mut data).is_ok_and(|x| x.is_none()));
+        // Truncated data
+        let message = opc_message(0, OPC_SET_PIXELS, &[1, 2, 3, 4, 5, 6]);
+        assert!(opc_read(&mut &message[..5], &mut data).is_err());
+    }
+
+    #[test]
+    fn opc_channels() {
+        let data = vec![7; SPINES * LEDS_PER_SPINE * 3 + 6];
+        assert_eq!(opc_pixels(0, OPC_SET_PIXELS, &data), Some((0, &data[..])));
+        assert_eq!(opc_pixels(1, OPC_SET_PIXELS, &data), Some((0, &data[..LEDS_PER_SPINE * 3])));
+        assert_eq!(
+            opc_pixels(SPINES as u8, OPC_SET_PIXELS, &data[..6]),
+            Some(((SPINES - 1) * LEDS_PER_SPINE, &data[..6]))
+        );
+        assert_eq!(opc_pixels(SPINES as u8 + 1, OPC_SET_PIXELS, &data), None);
+        assert_eq!(opc_pixels(0, 255, &data), None);
+    }
+
+    #[test]
+    fn ddp_headers() {
+        let data = [1, 2, 3, 4, 5, 6];
+        assert_eq!(ddp_pixels(&ddp_packet(0x41, 1, 0, &data)).unwrap(), Some((0, &data[..])));
+        assert_eq!(ddp_pixels(&ddp_packet(0x41, 1, 300, &data)).unwrap(), Some((100, &data[..])));
+        assert_eq!(
+            ddp_pixels(&ddp_packet(0x41 | DDP_FLAG_TIMECODE, 1, 3, &data)).unwrap(),
+            Some((1, &data[..]))
+        );
+
+        // Data after the length in the header is ignored
+        let mut packet = ddp_packet(0x41, 1, 0, &data);
+        packet.extend_from_slice(&[9; 3]);
+        assert_eq!(ddp_pixels(&packet).unwrap(), Some((0, &data[..])));
+
+        // Not pixel data
+        assert_eq!(ddp_pixels(&ddp_packet(0x41 | DDP_FLAG_QUERY, 1, 0, &data)).unwrap(), None);
+        assert_eq!(ddp_pixels(&ddp_packet(0x41 | DDP_FLAG_REPLY, 1, 0, &data)).unwrap(), None);
+        assert_eq!(ddp_pixels(&ddp_packet(0x41, DDP_FIRST_RESERVED_ID, 0, &data)).unwrap(), None);
+    }
+
+    #[test]
+    fn ddp_bad_packets() {
+        let data = [1, 2, 3];
+        let packet = ddp_packet(0x41, 1, 0, &data);
+        assert!(ddp_pixels(&packet[..9]).is_err());
+        assert!(ddp_pixels(&packet[..12]).is_err());
+        assert!(ddp_pixels(&ddp_packet(0x41, 1, 4, &data)).is_err());
+        // Timecode flag set but no room for the timecode
+        let mut packet = ddp_packet(0x41, 1, 0, &[]);
+        packet[0] |= DDP_FLAG_TIMECODE;
+        assert!(ddp_pixels(&packet).is_err());
+    }
+
+    #[test]
+    fn write_pixels_clipped() {
+        let mut leds = LedUpdate::default();
+        write_pixels(&mut leds, LEDS_PER_SPINE - 1, &[1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(leds.spines[0][LEDS_PER_SPINE - 1], [1, 2, 3]);
+        assert_eq!(leds.spines[1][0], [4, 5, 6]);
+        assert_eq!(leds.spines[1][1], [0, 0, 0]);
+
+        // Pixels past the end are dropped
+        let last = SPINES * LEDS_PER_SPINE - 1;
+        write_pixels(&mut leds, last, &[8; 9]);
+        assert_eq!(leds.spines[SPINES - 1][LEDS_PER_SPINE - 1], [8, 8, 8]);
+        write_pixels(&mut leds, last + 1, &[9; 3]);
+        write_pixels(&mut leds, usize::MAX / 2, &[9; 3]);
+    }
+}
@@ -0,0 +1,290 @@
+//! Binary encodings of LED frames for the websocket visualiser stream.  See
+//! ws_server.rs for the header layout.  After the header, the payload depends
+//! on the frame type:
+//! * Raw (0): RGB bytes for every LED, spine by spine.
+//! * Run-length (1): a complete frame as a series of runs, each a count byte
+//!   (1-255) followed by the RGB value repeated that many times.
+//! * Delta (2): only the LEDs which changed since the previous frame sent to
+//!   this client, as a series of runs: a little-endian u16 LED index (counting
+//!   spine by spine), a count byte (1-255), then that many RGB values.
+//!
+//! Raw and run-length frames are keyframes which can be decoded on their
+//! own.  Delta frames only make sense applied on top of the last frame.
+
+use crate::{LEDS_PER_SPINE, SPINES};
+
+/// Version of the binary frame format, sent in the header of every frame
+pub const FRAME_FORMAT_VERSION: u8 = 1;
+
+/// Length of the binary frame header, in bytes
+pub const HEADER_LEN: usize = 16;
+
+/// Number of bytes of RGB data in a frame
+pub const PIXEL_BYTES: usize = SPINES * LEDS_PER_SPINE * 3;
+
+/// Send a keyframe at least this often, in frames sent to the client, so
+/// that any decoding glitch can't persist
+const KEYFRAME_INTERVAL: usize = 60;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum FrameType {
+    Raw = 0,
+    RunLength = 1,
+    Delta = 2,
+}
+
+/// Start a binary frame with the header
+fn header(frame_type: FrameType, frame_number: u32, timestamp: u64, capacity: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + capacity);
+    buf.push(FRAME_FORMAT_VERSION);
+    buf.push(frame_type as u8);
+    buf.push(SPINES as u8);
+    buf.push(LEDS_PER_SPINE as u8);
+    buf.extend_from_slice(&frame_number.to_le_bytes());
+    buf.extend_from_slice(&timestamp.to_le_bytes());
+    buf
+}
+
+/// Encode a complete frame of raw RGB bytes
+pub fn encode_raw(frame_number: u32, timestamp: u64, pixels: &[u8]) -> Vec<u8> {
+    let mut buf = header(FrameType::Raw, frame_number, timestamp, pixels.len());
+    buf.extend_from_slice(pixels);
+    buf
+}
+
+/// Encode a complete frame with run-length encoding
+fn encode_run_length(frame_number: u32, timestamp: u64, pixels: &[u8]) -> Vec<u8> {
+    let mut buf = header(FrameType::RunLength, frame_number, timestamp, 0);
+    let mut pixels = pixels.chunks_exact(3).peekable();
+    while let Some(pixel) = pixels.next() {
+        let mut count: u8 = 1;
+        while count < u8::MAX && pixels.peek() == Some(&pixel) {
+            pixels.next();
+            count += 1;
+        }
+        buf.push(count);
+        buf.extend_from_slice(pixel);
+    }
+    buf
+}
+
+/// Encode the LEDs which differ between two frames
+fn encode_delta(frame_number: u32, timestamp: u64, previous: &[u8], pixels: &[u8]) -> Vec<u8> {
+    let mut buf = header(FrameType::Delta, frame_number, timestamp, 0);
+    let changed: Vec<bool> = previous
+        .chunks_exact(3)
+        .zip(pixels.chunks_exact(3))
+        .map(|(a, b)| a != b)
+        .collect();
+
+    let mut i = 0;
+    while i < changed.len() {
+        if !changed[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < changed.len() && changed[i] && i - start < u8::MAX as usize {
+            i += 1;
+        }
+        buf.extend_from_slice(&(start as u16).to_le_bytes());
+        buf.push((i - start) as u8);
+        buf.extend_from_slice(&pixels[start * 3..i * 3]);
+    }
+    buf
+}
+
+/// Per-client encoder which sends keyframes and then deltas against the last
+/// frame it sent, picking whichever encoding is smallest.
+#[derive(Default)]
+pub struct DeltaEncoder {
+    /// The last frame sent to this client
+    previous: Option<Vec<u8>>,
+
+    /// Frames sent since the last keyframe
+    since_keyframe: usize,
+}
+
+impl DeltaEncoder {
+    pub fn encode(&mut self, frame_number: u32, timestamp: u64, pixels: &[u8]) -> Vec<u8> {
+        let keyframe = {
+            let run_length = encode_run_length(frame_number, timestamp, pixels);
+            if run_length.len() < HEADER_LEN + pixels.len() {
+                run_length
+            } else {
+                encode_raw(frame_number, timestamp, pixels)
+            }
+        };
+
+        let delta = match &self.previous {
+            Some(previous) if self.since_keyframe < KEYFRAME_INTERVAL => {
+                Some(encode_delta(frame_number, timestamp, previous, pixels))
+            }
+            _ => None,
+        };
+
+        self.previous = Some(pixels.to_vec());
+        match delta {
+            Some(delta) if delta.len() < keyframe.len() => {
+                self.since_keyframe += 1;
+                delta
+            }
+            _ => {
+                self.since_keyframe = 0;
+                keyframe
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Made-up pixels which change a little from frame to frame, like a
+    /// slow pattern would
+    fn frame(seed: u32) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2_654_435_761).max(1);
+        (0..PIXEL_BYTES)
+            .map(|i| {
+                // A few LEDs change each frame, the rest follow a gradient
+                if (i / 3) % 50 == seed as usize % 50 {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                } else {
+                    (i / 3 / LEDS_PER_SPINE) as u8 * 20
+                }
+            })
+            .collect()
+    }
+
+    /// Decode a frame the way the visualiser does, on top of the previous
+    /// frame for deltas.  Returns the frame type and number.
+    fn decode(buf: &[u8], pixels: &mut Vec<u8>) -> (u8, u32) {
+        assert!(buf.len() >= HEADER_LEN);
+        assert_eq!(buf[0], FRAME_FORMAT_VERSION);
+        assert_eq!(buf[2] as usize, SPINES);
+        assert_eq!(buf[3] as usize, LEDS_PER_SPINE);
+        let frame_number = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+        let payload = &buf[HEADER_LEN..];
+        match buf[1] {
+            0 => *pixels = payload.to_vec(),
+            1 => {
+                pixels.clear();
+                for run in payload.chunks_exact(4) {
+                    assert!(run[0] >= 1);
+                    for _ in 0..run[0] {
+                        pixels.extend_from_slice(&run[1..]);
+                    }
+                }
+                assert_eq!(payload.len() % 4, 0);
+            }
+            2 => {
+                let mut pos = 0;
+                while pos < payload.len() {
+                    let start = u16::from_le_bytes([payload[pos], payload[pos + 1]]) as usize;
+                    let count = payload[pos + 2] as usize;
+                    assert!(count >= 1);
+                    pos += 3;
+                    pixels[start * 3..(start + count) * 3].copy_from_slice(&payload[pos..pos + count * 3]);
+                    pos += count * 3;
+                }
+            }
+            x => panic!("unknown frame type {}", x),
+        }
+        assert_eq!(pixels.len(), PIXEL_BYTES);
+        (buf[1], frame_number)
+    }
+
+    #[test]
+    fn header_layout() {
+        let buf = encode_raw(0x0403_0201, 0x0c0b_0a09_0807_0605, &frame(1));
+        assert_eq!(
+            buf[..HEADER_LEN],
+            [FRAME_FORMAT_VERSION, 0, SPINES as u8, LEDS_PER_SPINE as u8, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]
+        );
+        assert_eq!(buf.len(), HEADER_LEN + PIXEL_BYTES);
+        assert_eq!(encode_run_length(0, 0, &frame(1))[1], FrameType::RunLength as u8);
+        assert_eq!(encode_delta(0, 0, &frame(1), &frame(2))[1], FrameType::Delta as u8);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let pixels = frame(3);
+        let mut decoded = Vec::new();
+        decode(&encode_raw(7, 0, &pixels), &mut decoded);
+        assert_eq!(decoded, pixels);
+    }
+
+    #[test]
+    fn run_length_round_trip() {
+        // All one colour needs several runs, since a run is at most 255
+        let black = vec![0; PIXEL_BYTES];
+        let buf = encode_run_length(0, 0, &black);
+        let runs = (SPINES * LEDS_PER_SPINE + 254) / 255;
+        assert_eq!(buf.len(), HEADER_LEN + runs * 4);
+
+        for pixels in [black, frame(4), frame(5)] {
+            let mut decoded = Vec::new();
+            decode(&encode_run_length(0, 0, &pixels), &mut decoded);
+            assert_eq!(decoded, pixels);
+        }
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let previous = frame(6);
+
+        // Nothing changed
+        assert_eq!(encode_delta(0, 0, &previous, &previous).len(), HEADER_LEN);
+
+        // A few LEDs changed, and then everything, which needs runs split
+        // at 255 LEDs
+        let inverted: Vec<u8> = previous.iter().map(|x| !x).collect();
+        for pixels in [frame(7), inverted] {
+            let mut decoded = previous.clone();
+            decode(&encode_delta(0, 0, &previous, &pixels), &mut decoded);
+            assert_eq!(decoded, pixels);
+        }
+    }
+
+    #[test]
+    fn delta_encoder_round_trip() {
+        let mut encoder = DeltaEncoder::default();
+        let mut decoded = Vec::new();
+        for n in 0..200 {
+            let pixels = frame(n / 2);
+            let (_, frame_number) = decode(&encoder.encode(n, 0, &pixels), &mut decoded);
+            assert_eq!(frame_number, n);
+            assert_eq!(decoded, pixels, "frame {}", n);
+        }
+    }
+
+    #[test]
+    fn keyframe_interval() {
+        let mut encoder = DeltaEncoder::default();
+        let mut decoded = Vec::new();
+        let types: Vec<u8> = (0..(2 * KEYFRAME_INTERVAL as u32 + 3))
+            .map(|n| decode(&encoder.encode(n, 0, &frame(n)), &mut decoded).0)
+            .collect();
+
+        // A keyframe first, then deltas until the interval is up
+        assert_ne!(types[0], FrameType::Delta as u8);
+        for (n, frame_type) in types.iter().enumerate() {
+            let expected_keyframe = n % (KEYFRAME_INTERVAL + 1) == 0;
+            assert_eq!(*frame_type != FrameType::Delta as u8, expected_keyframe, "frame {}", n);
+        }
+    }
+
+    #[test]
+    fn smallest_keyframe() {
+        // Noisy frames don't compress, so are sent raw
+        let noise: Vec<u8> = (0..PIXEL_BYTES).map(|i| (i * 7 + i / 3) as u8).collect();
+        assert_eq!(DeltaEncoder::default().encode(0, 0, &noise)[1], FrameType::Raw as u8);
+        let black = vec![0; PIXEL_BYTES];
+        assert_eq!(DeltaEncoder::default().encode(0, 0, &black)[1], FrameType::RunLength as u8);
+    }
+}
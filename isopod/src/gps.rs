@@ -1,14 +1,23 @@
 //! Polls the GPS periodically to retrieve location data.  Parses NEMA data
 //! and stores the useful data.
 
-use crate::common_structs::GpsFix;
+use crate::common_structs::{FixQuality, GpsFix};
+use crate::health;
 use anyhow::{anyhow, Result};
 use chrono::DateTime;
-use nmea::Nmea;
+use log::{debug, info};
+use nmea::{FixType, Nmea};
 use std::fs::File;
 use std::io::{self, BufRead};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// Name of the GPS thread for health monitoring
+const WORKER_NAME: &str = "GPS";
+
+/// The GPS module sends several sentences a second, so if we go this long
+/// without reading a line something has gone wrong with the UART
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Take a parsed NMEA packet from the NMEA library.  Print it if it contains
 /// useful info.  Return true if we printed anything, or false if it wasn't
@@ -36,7 +45,7 @@ fn print_nmea_packet(packet: &Nmea) -> bool {
         sats => sats,
     };
 
-    println!(
+    debug!(
         "{} - {},{} altitude {}.  {} satellites",
         time, latitude, longitude, altitude, sats,
     );
@@ -75,12 +84,26 @@ fn nmea_to_fix(packet: &Nmea) -> Option<GpsFix> {
     let naive_date_time = chrono::NaiveDateTime::new(date, time);
     let date_time = DateTime::from_utc(naive_date_time, chrono::Utc);
 
+    let fix_quality = packet.fix_type.map(|x| match x {
+        FixType::Invalid => FixQuality::Invalid,
+        FixType::Gps => FixQuality::Gps,
+        FixType::DGps => FixQuality::Dgps,
+        FixType::Pps => FixQuality::Pps,
+        FixType::Rtk => FixQuality::Rtk,
+        FixType::FloatRtk => FixQuality::FloatRtk,
+        FixType::Estimated => FixQuality::Estimated,
+        FixType::Manual => FixQuality::Manual,
+        FixType::Simulation => FixQuality::Simulation,
+    });
+
     Some(GpsFix {
         longitude,
         latitude,
         altitude,
         satellites,
         time: date_time,
+        hdop: packet.hdop,
+        fix_quality,
     })
 }
 
@@ -115,7 +138,7 @@ impl Gps {
             ));
         }
 
-        println!("Testing GPS...");
+        info!("Testing GPS...");
         let mut reader = self.reader.lock().unwrap();
         let mut nmea = Nmea::new();
         let mut lines_read = 0;
@@ -138,7 +161,7 @@ impl Gps {
                 Err(_) => continue, // Ignore malformed packets
             };
         }
-        println!("GPS ok.");
+        info!("GPS ok.");
 
         Ok(())
     }
@@ -146,29 +169,34 @@ impl Gps {
     pub fn start_thread(self: Arc<Self>) {
         let thread_started = self.internal.lock().unwrap().thread_started;
         if !thread_started {
-            thread::Builder::new()
-                .name("ISOPOD GPS".into())
-                .spawn(move || self.gps_thread())
-                .unwrap();
+            health::spawn_supervised(WORKER_NAME, Some(STALL_TIMEOUT), move || {
+                self.gps_thread()
+            });
         }
     }
 
-    fn gps_thread(&self) -> ! {
+    fn gps_thread(&self) -> Result<()> {
         {
             let mut internal = self.internal.lock().unwrap();
             internal.thread_started = true;
         }
 
-        println!("GPS thread running.");
+        info!("GPS thread running.");
 
-        let mut reader = self.reader.lock().unwrap();
+        // If a previous run of this thread panicked while holding the reader
+        // then the lock is poisoned, but the reader is fine to re-use.
+        let mut reader = self.reader.lock().unwrap_or_else(PoisonError::into_inner);
         let mut nmea = Nmea::new();
         let mut line_buf = String::new();
 
         loop {
             line_buf.clear();
             // Ignore reader errors, cross fingers that they are temporary
-            let _ = reader.read_line(&mut line_buf);
+            if let Ok(len) = reader.read_line(&mut line_buf) {
+                if len > 0 {
+                    health::heartbeat(WORKER_NAME);
+                }
+            }
             if line_buf.trim().is_empty() {
                 continue;
             }
@@ -0,0 +1,191 @@
+//! Keeps track of the health of the worker threads.  Workers are started
+//! under a supervisor which catches panics and errors and restarts them after
+//! a back-off, and workers which loop regularly report a heartbeat so we can
+//! tell when one has got stuck.
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Give up restarting a worker after this many failures in a row
+const MAX_RESTARTS: u32 = 10;
+
+/// How long to wait before restarting a worker, multiplied by the number of
+/// times it has failed
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Once a worker has run for this long without failing, its failure count is
+/// reset so the back-off starts from scratch
+const STABLE_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerState {
+    /// Running and, if it sends heartbeats, sending them on time
+    Running,
+    /// Running, but hasn't sent a heartbeat for longer than its timeout
+    Stalled,
+    /// Failed and waiting to be restarted
+    Restarting,
+    /// Failed too many times and won't be restarted
+    Failed,
+    /// Finished normally
+    Stopped,
+}
+
+struct Worker {
+    state: WorkerState,
+    stall_timeout: Option<Duration>,
+    last_heartbeat: Instant,
+    restarts: u32,
+    last_error: Option<String>,
+}
+
+/// Health of one worker, as reported by the /health endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerStatus {
+    pub state: WorkerState,
+    /// Seconds since the last heartbeat (or since the worker started)
+    pub last_heartbeat: f32,
+    /// Total number of times the worker has been restarted
+    pub restarts: u32,
+    /// Why the worker last failed, if it ever has
+    pub last_error: Option<String>,
+}
+
+lazy_static! {
+    static ref WORKERS: Mutex<BTreeMap<&'static str, Worker>> = Mutex::new(BTreeMap::new());
+}
+
+/// Start keeping track of a worker.  If `stall_timeout` is provided then the
+/// worker is expected to call heartbeat() at least that often, and is
+/// reported as stalled if it doesn't.
+pub fn register(name: &'static str, stall_timeout: Option<Duration>) {
+    WORKERS.lock().unwrap().insert(
+        name,
+        Worker {
+            state: WorkerState::Running,
+            stall_timeout,
+            last_heartbeat: Instant::now(),
+            restarts: 0,
+            last_error: None,
+        },
+    );
+}
+
+/// Report that a worker is alive and making progress
+pub fn heartbeat(name: &'static str) {
+    if let Some(worker) = WORKERS.lock().unwrap().get_mut(name) {
+        worker.last_heartbeat = Instant::now();
+    }
+}
+
+fn set_state(name: &'static str, state: WorkerState, error: Option<String>) {
+    if let Some(worker) = WORKERS.lock().unwrap().get_mut(name) {
+        worker.state = state;
+        worker.last_heartbeat = Instant::now();
+        if state == WorkerState::Restarting {
+            worker.restarts += 1;
+        }
+        if error.is_some() {
+            worker.last_error = error;
+        }
+    }
+}
+
+/// Spawn a worker thread which is restarted if it panics or returns an
+/// error.  The worker body is called again from scratch on each restart, so
+/// anything it needs to keep between restarts should be borrowed from the
+/// closure rather than moved out of it.
+pub fn spawn_supervised<F>(name: &'static str, stall_timeout: Option<Duration>, mut body: F)
+where
+    F: FnMut() -> Result<()> + Send + 'static,
+{
+    register(name, stall_timeout);
+    thread::Builder::new()
+        .name(format!("ISOPOD {}", name))
+        .spawn(move || {
+            let mut failures = 0;
+            loop {
+                let started = Instant::now();
+                let error = match panic::catch_unwind(AssertUnwindSafe(&mut body)) {
+                    Ok(Ok(())) => {
+                        info!("Worker {} finished.", name);
+                        set_state(name, WorkerState::Stopped, None);
+                        return;
+                    }
+                    Ok(Err(e)) => format!("{:#}", e),
+                    Err(panic) => panic
+                        .downcast_ref::<&str>()
+                        .map(|x| x.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "panicked".to_owned()),
+                };
+
+                if started.elapsed() > STABLE_PERIOD {
+                    failures = 0;
+                }
+                failures += 1;
+                if failures > MAX_RESTARTS {
+                    error!("Worker {} failed: {}.  Giving up.", name, error);
+                    set_state(name, WorkerState::Failed, Some(error));
+                    return;
+                }
+
+                error!("Worker {} failed: {}.  Restarting.", name, error);
+                set_state(name, WorkerState::Restarting, Some(error));
+                thread::sleep(RESTART_BACKOFF * failures);
+                set_state(name, WorkerState::Running, None);
+            }
+        })
+        .unwrap();
+}
+
+/// Is this worker anything other than running normally: stalled, failed,
+/// waiting to restart or stopped?  False for unknown workers.
+// Only used by the LED watchdog, which isn't built in simulator mode
+#[allow(dead_code)]
+pub fn is_down(name: &'static str) -> bool {
+    status()
+        .get(name)
+        .map(|x| x.state != WorkerState::Running)
+        .unwrap_or(false)
+}
+
+/// Get the health of all registered workers
+pub fn status() -> BTreeMap<&'static str, WorkerStatus> {
+    WORKERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, worker)| {
+            let since_heartbeat = worker.last_heartbeat.elapsed();
+            let state = match (worker.state, worker.stall_timeout) {
+                (WorkerState::Running, Some(timeout)) if since_heartbeat > timeout => {
+                    WorkerState::Stalled
+                }
+                (state, _) => state,
+            };
+            (
+                *name,
+                WorkerStatus {
+                    state,
+                    last_heartbeat: since_heartbeat.as_secs_f32(),
+                    restarts: worker.restarts,
+                    last_error: worker.last_error.clone(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Are all workers running normally?
+pub fn all_ok() -> bool {
+    status().values().all(|x| x.state == WorkerState::Running)
+}
@@ -2,14 +2,19 @@
 //! gauge).
 
 use crate::common_structs::{BatteryReadings, ImuReadings};
+use crate::health;
 use anyhow::{anyhow, Result};
 use linux_embedded_hal as hal;
+use log::{debug, info, trace};
 use max1720x::MAX1720x;
 use rppal::i2c::I2c;
 use std::ops::DerefMut;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::{thread, time};
 
+/// Name of the I2C thread for health monitoring
+const WORKER_NAME: &str = "I2C";
+
 struct I2cPeriphsInternal {
     thread_started: bool,
     imu: ImuReadings,
@@ -47,41 +52,47 @@ impl I2cPeriphs {
         }
         let mut i2c = self.i2c.lock().unwrap();
 
-        println!("Testing I2C and IMU...");
+        info!("Testing I2C and IMU...");
         let mut icm = icm20948::ICMI2C::<_, _, 0x69>::new(i2c.deref_mut())?;
         icm.init(i2c.deref_mut(), &mut hal::Delay).unwrap();
         for _ in 0..3 {
             let (xa, ya, za, xg, yg, zg) =
                 icm.scale_raw_accel_gyro(icm.get_values_accel_gyro(i2c.deref_mut()).unwrap());
-            println!(
+            debug!(
                 "Sensed, {:?}, {:?}, {:?}, {:?}, {:?}, {:?}",
                 xa, ya, za, xg, yg, zg
             );
             thread::sleep(time::Duration::from_millis(300));
         }
-        println!("I2C and IMU ok!");
+        info!("I2C and IMU ok!");
         Ok(())
     }
 
     pub fn start_thread(self: Arc<Self>) {
         if !self.internal.lock().unwrap().thread_started {
-            std::thread::Builder::new()
-                .name("ISOPOD I2C".into())
-                .spawn(move || self.i2c_thread())
-                .unwrap();
+            // The thread loops once a second, so give it plenty of slack
+            health::spawn_supervised(
+                WORKER_NAME,
+                Some(time::Duration::from_secs(5)),
+                move || self.i2c_thread(),
+            );
         }
     }
 
-    fn i2c_thread(&self) -> ! {
+    fn i2c_thread(&self) -> Result<()> {
         {
             let mut internal = self.internal.lock().unwrap();
             internal.thread_started = true;
         }
-        println!("I2C thread running.");
+        info!("I2C thread running.");
 
-        let mut i2c = self.i2c.lock().unwrap();
-        let mut icm = icm20948::ICMI2C::<_, _, 0x69>::new(i2c.deref_mut()).unwrap();
-        icm.init(i2c.deref_mut(), &mut hal::Delay).unwrap();
+        // If a previous run of this thread panicked while holding the bus
+        // then the lock is poisoned, but the bus itself is fine to re-use.
+        let mut i2c = self.i2c.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut icm = icm20948::ICMI2C::<_, _, 0x69>::new(i2c.deref_mut())
+            .map_err(|e| anyhow!("Failed to set up IMU: {:?}", e))?;
+        icm.init(i2c.deref_mut(), &mut hal::Delay)
+            .map_err(|e| anyhow!("Failed to initialise IMU: {:?}", e))?;
         let mut max17205 = MAX1720x::new(i2c.deref_mut());
 
         loop {
@@ -99,10 +110,10 @@ impl I2cPeriphs {
                     yg,
                     zg,
                 };
-                // println!(
-                //     "Sensed, {:?}, {:?}, {:?}, {:?}, {:?}, {:?}",
-                //     xa, ya, za, xg, yg, zg
-                // );
+                trace!(
+                    "Sensed, {:?}, {:?}, {:?}, {:?}, {:?}, {:?}",
+                    xa, ya, za, xg, yg, zg
+                );
             };
 
             // Fetch the current readings from the fuel gauge. Ignore any errors.
@@ -116,6 +127,7 @@ impl I2cPeriphs {
                 self.internal.lock().unwrap().battery.current = current;
             }
 
+            health::heartbeat(WORKER_NAME);
             thread::sleep(time::Duration::from_millis(1000));
         }
     }
@@ -1,15 +1,46 @@
 //! Controls the attached addressable LEDs using the PWM and GPIO peripherals.
 
-use crate::common_structs::LedUpdate;
-use crate::SETTINGS;
+use crate::common_structs::{LedUpdate, PowerLimiting};
+use crate::settings;
 use crate::control_server::CONTROLS;
+use crate::health;
+use crate::mapping_wizard;
+use crate::metrics;
+use crate::thermal;
 use crate::{LEDS_PER_SPINE, SPINES};
 use anyhow::{anyhow, Result};
-use rppal::gpio::Gpio;
+use log::{error, info, warn};
+use rppal::gpio::{Gpio, OutputPin};
 use rs_ws281x::{ChannelBuilder, Controller, ControllerBuilder, StripType};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
+/// Name of the LED thread for health monitoring
+const WORKER_NAME: &str = "LED";
+
+/// If the LED thread doesn't send a heartbeat for this long then it's
+/// assumed to be wedged and the watchdog cuts power to the LEDs
+const STALL_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+/// How long the LED thread waits for a frame before sending a heartbeat
+/// anyway.  If the main loop stops sending frames the LEDs stay on the last
+/// one; the main loop has its own health check.
+const FRAME_WAIT: time::Duration = time::Duration::from_millis(500);
+
+/// Set by the watchdog when it cuts power to the LEDs, so the LED thread
+/// knows to bring the controller back up from scratch if it recovers.
+static POWER_CUT: AtomicBool = AtomicBool::new(false);
+
+/// How much the power limiter has stepped in since take_power_limiting() was
+/// last called
+static POWER_LIMITING: Mutex<PowerLimiting> = Mutex::new(PowerLimiting {
+    frames: 0,
+    limited_frames: 0,
+    min_scale: None,
+});
+
 /// Abstraction for the LED peripheral control, including use of GPIO to
 /// switch master power to the LEDs and PWM to output data for the
 /// addressable LEDs.
@@ -24,9 +55,12 @@ pub struct Led {
     thread_started: bool,
 }
 
-/// Make a new controller
-fn get_controller() -> Result<Controller> {
-    let brightness: u8 = SETTINGS.get("led_brightness")?;
+/// Spine mapping which sends each logical spine to the PCB connector with the
+/// same number
+const IDENTITY_MAPPING: [usize; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+/// Make a new controller with the given brightness, 0-255
+fn get_controller(brightness: u8) -> Result<Controller> {
     Ok(ControllerBuilder::new()
         .freq(800_000)
         .dma(10)
@@ -62,49 +96,86 @@ impl Led {
         }
     }
 
-    /// Start up a new thread controlling this peripheral.
-    pub fn start_thread(&mut self) {
+    /// Start up a new thread controlling this peripheral, along with a
+    /// watchdog which turns the LEDs off if the thread gets stuck.
+    pub fn start_thread(&mut self) -> Result<()> {
         if !self.thread_started {
             self.thread_started = true;
             let gpio = self.gpio.take().unwrap();
             let rx = self.rx.take().unwrap();
+
+            // Setup a SIGTERM handler to turn off the LEDs before quitting
+            let (sigterm_tx, sigterm_rx) = channel();
+            ctrlc::set_handler(move || sigterm_tx.send(()).unwrap())?;
+
+            // Setup the LED enable pin and default LEDs to off.  The pin is
+            // shared with the watchdog so it can cut the power even if the
+            // LED thread is stuck.
+            let mut led_enable_pin = gpio.get(5)?.into_output();
+            led_enable_pin.set_low();
+            let led_enable_pin = Arc::new(Mutex::new(led_enable_pin));
+
+            let watchdog_pin = led_enable_pin.clone();
             thread::Builder::new()
-                .name("ISOPOD LED".into())
-                .spawn(move || Self::led_thread(gpio, rx))
-                .unwrap();
+                .name("ISOPOD LED watchdog".into())
+                .spawn(move || Self::watchdog_thread(watchdog_pin))?;
+
+            health::spawn_supervised(WORKER_NAME, Some(STALL_TIMEOUT), move || {
+                Self::led_thread(&gpio, &rx, &sigterm_rx, &led_enable_pin)
+            });
         }
+        Ok(())
     }
 
-    /// The main peripheral control thread
-    fn led_thread(gpio: Gpio, rx: Receiver<LedUpdate>) -> Result<()> {
+    /// Fail safe: if the LED thread gets stuck, or fails and is waiting to
+    /// be restarted or given up on, turn the LEDs off rather than leave them
+    /// stuck on the last frame at full power.
+    fn watchdog_thread(led_enable_pin: Arc<Mutex<OutputPin>>) -> ! {
+        loop {
+            thread::sleep(time::Duration::from_millis(500));
+            if health::is_down(WORKER_NAME) {
+                let mut pin = led_enable_pin.lock().unwrap();
+                if pin.is_set_high() {
+                    error!("LED thread is down!  Cutting power to the LEDs.");
+                    pin.set_low();
+                    POWER_CUT.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    /// The main peripheral control thread.  Restarted by the supervisor if it
+    /// fails, so anything which must outlive a restart is borrowed.
+    fn led_thread(
+        gpio: &Gpio,
+        rx: &Receiver<LedUpdate>,
+        sigterm_rx: &Receiver<()>,
+        led_enable_pin: &Mutex<OutputPin>,
+    ) -> Result<()> {
         // When LEDs are disabled we drop the controller so as to leave the
         // PWM lines idle and stop the LEDs being semi-powered through their
         // data->ground diode.
         let mut controller: Option<Controller> = None;
 
-        // Setup a SIGTERM handler to turn off the LEDs before quitting
-        let (sigterm_tx, sigterm_rx) = std::sync::mpsc::channel();
-        ctrlc::set_handler(move || sigterm_tx.send(()).unwrap())?;
-
-        // Setup the LED enable pin and default LEDs to off
-        let mut led_enable_pin = gpio.get(5)?.into_output();
-        led_enable_pin.set_low();
-
-        let map = Self::get_led_mapping()?;
+        // Make sure we start from a known state, in case this is a restart
+        led_enable_pin.lock().unwrap().set_low();
 
-        println!("LED thread running.");
+        info!("LED thread running.");
 
         // Count how many frames we see in a row where all LEDs are disabled.
         let mut black_frames: usize = 0;
 
+        // The led_brightness the controller was made with
+        let mut controller_brightness = settings::get().led_brightness;
+
         loop {
             // Exit handler:
             if sigterm_rx.try_recv().is_ok() {
                 // Turn off LEDs then quit
-                println!("LED thread handling SIGTERM.  Goodbye.");
+                info!("LED thread handling SIGTERM.  Goodbye.");
                 if let Some(ref mut controller) = controller {
                     Self::set_all_leds(controller, [0, 0, 0, 0]);
-                    led_enable_pin.set_low();
+                    led_enable_pin.lock().unwrap().set_low();
                 }
 
                 // Destroy the controller, then park the PWM pins at +VCC.
@@ -124,16 +195,30 @@ impl Led {
             // receive all of them and discard all but the last.  This means
             // if we are too slow then we will drop packets rather than
             // falling behind and letting the buffer grow indefinitely.
-            let mut led_update = rx.recv().unwrap();
+            let received = rx.recv_timeout(FRAME_WAIT);
+            health::heartbeat(WORKER_NAME);
+            let mut led_update = match received {
+                Ok(x) => x,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(e) => return Err(e.into()),
+            };
             while let Ok(further_update) = rx.try_recv() {
                 // Only print warnings if LEDs are actually enabled.  Our way
                 // of disabling the LEDs causes some spurious frame-drops.
                 if controller.is_some() {
-                    println!("Warning: LED update packet dropped!");
+                    warn!("LED update packet dropped");
+                    metrics::led_packet_dropped();
                 }
                 led_update = further_update;
             }
 
+            // While the mapping wizard is running it takes over the LEDs.
+            // Its frames are indexed by PCB connector so skip the mapping.
+            let (led_update, map) = match mapping_wizard::override_frame() {
+                Some(frame) => (frame, IDENTITY_MAPPING),
+                None => (led_update, Self::get_led_mapping()),
+            };
+
             // Decide whether LEDs should be enabled: cut power after a number
             // of frames in a row where all LEDs are off.
             if led_update
@@ -151,6 +236,20 @@ impl Led {
             }
             let leds_enabled = black_frames < 3;
 
+            // If the watchdog cut the power while we were stuck then start
+            // again with a fresh controller.
+            if POWER_CUT.swap(false, Ordering::SeqCst) {
+                controller.take();
+            }
+
+            // The brightness can only be set when making the controller, so
+            // make a new one if led_brightness has changed
+            let brightness = settings::get().led_brightness;
+            if brightness != controller_brightness {
+                controller_brightness = brightness;
+                controller.take();
+            }
+
             // If LEDs are newly enabled, bring up the controller and set the
             // LED enable pin.  If they are newly disabled, destroy the
             // controller and force the PWM pins high so the LEDs can't ground
@@ -158,8 +257,8 @@ impl Led {
 
             if leds_enabled && controller.is_none() {
                 // LEDs newly enabled
-                controller = Some(get_controller()?);
-                led_enable_pin.set_high();
+                controller = Some(get_controller(controller_brightness)?);
+                led_enable_pin.lock().unwrap().set_high();
             } else if !leds_enabled && controller.is_some() {
                 // LEDs newly disabled
 
@@ -174,7 +273,7 @@ impl Led {
                 // Destroy the controller so things don't get weird when we
                 // try to take its pins as GPIOs.
                 controller.take();
-                led_enable_pin.set_low();
+                led_enable_pin.lock().unwrap().set_low();
 
                 // Make the PWM pins constant-high.  After the level shifter
                 // this will force the data pins to both be 5V DC.
@@ -191,7 +290,10 @@ impl Led {
             // data doesn't come through this module).
             if let Some(ref mut controller) = controller {
                 // Work out what if any power limiting scaling is needed
-                let mut power_scale = Self::get_power_limit_scaling(&led_update);
+                let current = Self::estimate_current(&led_update);
+                let mut power_scale = Self::get_power_limit_scaling(current);
+                Self::record_power_limiting(power_scale);
+                metrics::set_led_power(current, power_scale);
 
                 // Apply scaling from control panle
                 let brightness = CONTROLS.read().unwrap().brightness;
@@ -199,6 +301,11 @@ impl Led {
                     power_scale = Some((brightness as f32) / 100.0);
                 }
 
+                // Cap the brightness if the Pi is getting too hot
+                if let Some(thermal_scale) = thermal::brightness_scale() {
+                    power_scale = Some(power_scale.map_or(thermal_scale, |x| x.min(thermal_scale)));
+                }
+
                 let leds = controller.leds_mut(0);
                 // spine_hard represents a physical LED connector on the PCB
                 for spine_hard in 0..(SPINES / 2) {
@@ -259,8 +366,8 @@ impl Led {
             ));
         }
 
-        println!("Testing WS2812b LED controller");
-        let mut controller = get_controller()?;
+        info!("Testing WS2812b LED controller");
+        let mut controller = get_controller(settings::get().led_brightness)?;
 
         Self::set_all_leds(&mut controller, [0, 0, 255, 0]); // red
         thread::sleep(time::Duration::from_millis(300));
@@ -268,7 +375,7 @@ impl Led {
         thread::sleep(time::Duration::from_millis(300));
         Self::set_all_leds(&mut controller, [255, 0, 0, 0]); // blue
 
-        println!("Finished testing WS2812b LED controller");
+        info!("Finished testing WS2812b LED controller");
 
         Ok(())
     }
@@ -298,23 +405,16 @@ impl Led {
     /// corresponds to a PCB connector (numebered 1-12 inclusive) and each
     /// value in the array is the spine position (numbered 1-12 inclusive).
     /// Spine positions are defined by the web visualiser (and also appear in
-    /// geometry.rs)
-    fn get_led_mapping() -> Result<[usize; 12]> {
-        let map: [usize; 12] = SETTINGS.get("led_spine_mapping")?;
-
-        // Check the mapping is valid
-        let mut map_sorted = map;
-        map_sorted.sort_unstable();
-        assert_eq!(map_sorted, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
-
-        Ok(map)
+    /// geometry.rs).  The mapping is checked when the settings are loaded so
+    /// it is always valid here, and is read every frame so changes from the
+    /// mapping wizard take effect immediately.
+    fn get_led_mapping() -> [usize; 12] {
+        settings::get().led_spine_mapping
     }
 
-    /// Work out how much current will be consumed by the LEDs in the
-    /// requested illuminations.  If this exceeds or nears the maximum
-    /// allowable value then work out a scaling to bring them back in range.
-    /// If no scaling is required then None is returned.
-    fn get_power_limit_scaling(leds: &LedUpdate) -> Option<f32> {
+    /// Work out how much current, in amps, will be consumed by the LEDs in
+    /// the requested illuminations.
+    fn estimate_current(leds: &LedUpdate) -> f32 {
         // We work out the current as follows:
         // - There is a constant offset current for powering the WS2812b
         //   on-chip controllers (assume no auto-off function)
@@ -350,7 +450,13 @@ impl Led {
                 total_current += led[2] as f32 * current_per_val_b;
             }
         }
+        total_current
+    }
 
+    /// If the estimated LED current exceeds or nears the maximum allowable
+    /// value then work out a scaling to bring it back in range.  If no
+    /// scaling is required then None is returned.
+    fn get_power_limit_scaling(total_current: f32) -> Option<f32> {
         // Our 5V DC-DC converter is limited to 5A output, let's limit to 4A
         // for safety. There are various nice smooth limiting curves we could
         // use, but for now just do a hard compressor: If the current would
@@ -363,6 +469,22 @@ impl Led {
         }
     }
 
+    /// Keep track of how often the power limiter steps in, for reporting
+    fn record_power_limiting(scale: Option<f32>) {
+        let mut stats = POWER_LIMITING.lock().unwrap();
+        stats.frames += 1;
+        if let Some(scale) = scale {
+            stats.limited_frames += 1;
+            stats.min_scale = Some(stats.min_scale.map_or(scale, |x| x.min(scale)));
+        }
+    }
+
+    /// Get how much the power limiter has stepped in since this was last
+    /// called, and start counting again
+    pub fn take_power_limiting(&self) -> PowerLimiting {
+        std::mem::take(&mut *POWER_LIMITING.lock().unwrap())
+    }
+
     /// Apply scaling to a subpixel value, if required
     fn scale_val(value: u8, scaling: Option<f32>) -> u8 {
         if let Some(scaling) = scaling {
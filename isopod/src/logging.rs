@@ -0,0 +1,148 @@
+//! Levelled logging through the `log` crate.  Lines are written to stdout,
+//! or stderr for warnings and errors, and the most recent are kept in memory
+//! so they can be viewed through the control server at `/logs` without
+//! needing a shell on the Pi.
+//!
+//! The level is set with `log_level` in settings.toml, and can be overridden
+//! for particular modules with `log_modules`.  Both are picked up when the
+//! settings are reloaded.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+
+use crate::settings::Settings;
+
+/// Which messages to log, copied from the settings so that logging never
+/// has to take the settings lock
+struct Filter {
+    level: LevelFilter,
+    /// Levels for module paths and everything under them
+    modules: BTreeMap<String, LevelFilter>,
+    buffer_lines: usize,
+}
+
+impl Filter {
+    /// The level for a target, from the longest module path which matches
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| module_matches(module, target))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.level, |(_, level)| *level)
+    }
+}
+
+/// Is `target` the module `module` or inside it
+fn module_matches(module: &str, target: &str) -> bool {
+    target
+        .strip_prefix(module)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+struct LogLine {
+    time: DateTime<Utc>,
+    level: Level,
+    target: String,
+    message: String,
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:<5} {}: {}",
+            self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.level,
+            self.target,
+            self.message
+        )
+    }
+}
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+static FILTER: RwLock<Filter> = RwLock::new(Filter {
+    level: LevelFilter::Info,
+    modules: BTreeMap::new(),
+    buffer_lines: 1000,
+});
+static RECENT: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::new());
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.read().unwrap().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = LogLine {
+            time: Utc::now(),
+            level: record.level(),
+            target: record.target().to_owned(),
+            message: record.args().to_string(),
+        };
+        if line.level <= Level::Warn {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+
+        let buffer_lines = FILTER.read().unwrap().buffer_lines;
+        let mut recent = RECENT.lock().unwrap();
+        while !recent.is_empty() && recent.len() >= buffer_lines {
+            recent.pop_front();
+        }
+        if buffer_lines > 0 {
+            recent.push_back(line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Start logging.  Until the settings are loaded everything at info level
+/// and above is logged.
+pub fn init() {
+    log::set_logger(&LOGGER).expect("logger already set");
+    log::set_max_level(LevelFilter::Info);
+}
+
+/// Apply the logging settings
+pub fn configure(settings: &Settings) {
+    // Let the log macros skip anything more verbose than we'll ever want
+    // without asking the logger, so trace messages in the render loop are
+    // close to free
+    let max_level = settings
+        .log_modules
+        .values()
+        .copied()
+        .fold(settings.log_level, Ord::max);
+    log::set_max_level(max_level);
+    *FILTER.write().unwrap() = Filter {
+        level: settings.log_level,
+        modules: settings.log_modules.clone(),
+        buffer_lines: settings.log_buffer_lines,
+    };
+}
+
+/// The most recent log lines, oldest first.  Only lines at `level` or above
+/// from within `module` (if given) are included, up to `limit` of them.
+pub fn recent(level: LevelFilter, module: Option<&str>, limit: usize) -> Vec<String> {
+    let recent = RECENT.lock().unwrap();
+    let mut lines: Vec<String> = recent
+        .iter()
+        .rev()
+        .filter(|line| line.level <= level)
+        .filter(|line| module.is_none_or(|module| module_matches(module, &line.target)))
+        .take(limit)
+        .map(|line| line.to_string())
+        .collect();
+    lines.reverse();
+    lines
+}
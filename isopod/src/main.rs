@@ -1,8 +1,7 @@
 //! Initialises and starts up worker threads to do the actual work.
 
 use anyhow::Result;
-use config::Config;
-use lazy_static::lazy_static;
+use log::info;
 #[cfg(feature = "hardware")]
 use rppal::gpio::Gpio;
 #[cfg(feature = "hardware")]
@@ -14,32 +13,42 @@ use std::sync::Arc;
 use std::thread;
 use std::time;
 
+#[cfg(feature = "hardware")]
+mod commands;
 mod common_structs;
+mod dmx_input;
+mod effects;
+mod external_input;
+mod frame_encoding;
+mod health;
 #[cfg(feature = "hardware")]
 mod gps;
 #[cfg(feature = "hardware")]
 mod i2c;
 #[cfg(feature = "hardware")]
 mod led;
+mod logging;
+mod mapping_wizard;
+mod metrics;
+mod motion;
+mod mqtt;
+mod osc;
 mod pattern_manager;
 mod patterns;
 #[cfg(feature = "hardware")]
 mod reporter;
+mod settings;
 mod temperature;
+mod thermal;
+mod wifi;
 mod control_server;
+mod ws_server;
 #[cfg(not(feature = "hardware"))]
 use common_structs::ImuReadings;
 
 pub const LEDS_PER_SPINE: usize = 59;
 pub const SPINES: usize = 12;
 
-lazy_static! {
-    static ref SETTINGS: Config = Config::builder()
-        .add_source(config::File::with_name("settings"))
-        .build()
-        .unwrap();
-}
-
 // If bluetooth is enabled then the raspberry pi serial port is
 // /dev/ttyS0.  If bluetooth is disabled then /dev/ttyAMA0 is used.
 #[cfg(feature = "hardware")]
@@ -47,100 +56,217 @@ const SERIAL_PORT: &str = "/dev/ttyS0";
 
 #[cfg(feature = "hardware")]
 fn main() -> Result<()> {
-    println!("Hello, world!");
+    logging::init();
+    info!("Hello, world!");
 
-    println!("Setting up raw peripherals...");
-    println!("Setting up GPIO...");
+    info!("Loading settings...");
+    settings::load()?;
+    settings::start_watcher();
+
+    info!("Setting up raw peripherals...");
+    info!("Setting up GPIO...");
     let gpio = Gpio::new()?;
-    println!("Setting up I2C...");
+    info!("Setting up I2C...");
     let i2c = I2c::new()?;
-    println!("Setting up GPS...");
+    info!("Setting up GPS...");
     let file = File::open(SERIAL_PORT)?;
     let reader = std::io::BufReader::new(file);
-    println!("Peripherals initialised okay!");
+    info!("Peripherals initialised okay!");
 
-    println!("Setting up peripheral controllers...");
-    println!("Setting up I2C peripherals controller...");
+    info!("Setting up peripheral controllers...");
+    info!("Setting up I2C peripherals controller...");
     let i2cperiphs = Arc::new(i2c::I2cPeriphs::new(i2c));
-    println!("Setting up LED controller...");
+    info!("Setting up LED controller...");
     let mut led = led::Led::new(gpio);
-    println!("Setting up GPS controller...");
+    info!("Setting up GPS controller...");
     let gps = Arc::new(gps::Gps::new(reader));
-    println!("Peripheral drivers initialised okay!");
+    info!("Peripheral drivers initialised okay!");
 
-    if SETTINGS.get("do_startup_tests")? {
-        println!("Doing start-up tests...");
+    if settings::get().do_startup_tests {
+        info!("Doing start-up tests...");
         gps.test()?;
         led.test()?;
         i2cperiphs.test()?;
-        println!("Start-up tests look good!");
+        info!("Start-up tests look good!");
     } else {
-        println!("Skipping start-up tests.");
+        info!("Skipping start-up tests.");
     }
 
-    println!("Starting worker threads...");
-    led.start_thread();
+    info!("Starting worker threads...");
+    led.start_thread()?;
     i2cperiphs.clone().start_thread();
     gps.clone().start_thread();
+    commands::start();
     let mut reporter = reporter::Reporter::new();
 
+    control_server::restore_state();
     control_server::start_server();
-    println!("Worker threads started.");
+    external_input::start();
+    dmx_input::start();
+    mqtt::start();
+    osc::start();
+    thermal::start();
 
-    let mut pattern_manager = pattern_manager::PatternManager::new();
+    // The websocket server is optional on hardware because it costs CPU
+    let ws = if settings::get().ws_server {
+        Some(ws_server::WsServer::start_server())
+    } else {
+        None
+    };
+    info!("Worker threads started.");
 
-    let delay_ms = 1000 / SETTINGS.get::<u64>("fps")?;
+    let mut pattern_manager = pattern_manager::PatternManager::new();
+    let mut effect_manager = effects::EffectManager::new();
 
     let mut last_report = time::Instant::now();
-    let report_interval = SETTINGS.get::<u64>("reporter_interval")?;
+    let mut motion = motion::MotionTracker::new();
+    let mut frames_since_report: u64 = 0;
+    let mut frame_overruns: u64 = 0;
+
+    // The main loop can't be restarted, but report its health too so we can
+    // see if it has got stuck.
+    health::register("main", Some(time::Duration::from_secs(2)));
 
+    let mut last_frame_start = time::Instant::now();
     loop {
+        health::heartbeat("main");
+        let frame_start = time::Instant::now();
+        let frame_interval = frame_start - last_frame_start;
+        last_frame_start = frame_start;
+
         // Read latest sensor values
         let gps_fix = gps.get();
         let imu_readings = i2cperiphs.get_imu();
         let battery_readings = i2cperiphs.get_battery();
+        mqtt::update_sensors(gps_fix, battery_readings);
+        metrics::set_sensors(Some(battery_readings), gps_fix.as_ref());
+        motion.update(&imu_readings);
 
         // Step pattern and update LEDs
+        let pattern_name = pattern_manager.pattern_name();
+        mqtt::update_pattern(pattern_name);
         let led_state = pattern_manager.step(&gps_fix, &imu_readings);
+        let led_state = effect_manager.apply(led_state);
         led.led_update(led_state)?;
+        if let Some(ref ws) = ws {
+            let telemetry = if ws.wants_telemetry() {
+                Some(ws_server::Telemetry {
+                    imu: imu_readings,
+                    gps: gps_fix,
+                    battery: Some(battery_readings),
+                    temperature: temperature::get_temperature(),
+                    pattern: pattern_name,
+                    frame_time: frame_start.elapsed().as_secs_f32() * 1000.0,
+                    fps: 1.0 / frame_interval.as_secs_f32(),
+                })
+            } else {
+                None
+            };
+            // The visualiser isn't essential, so don't let it stop the show
+            let _ = ws.led_update(led_state, telemetry);
+        }
 
-        // Send a report if necessary
+        // Settings may have been reloaded since the last frame
+        let settings = settings::get();
+
+        frames_since_report += 1;
+        let frame_time = frame_start.elapsed();
+        metrics::observe_frame_time(frame_time.as_secs_f64());
+        let fps = thermal::fps();
+        if frame_time > time::Duration::from_millis(1000 / fps) {
+            frame_overruns += 1;
+        }
+
+        // Send a report if necessary, more often in lost mode
+        let mut report_interval = settings.reporter_interval;
+        if report_interval > 0 && commands::lost_mode() {
+            report_interval = report_interval.min(settings.lost_mode_report_interval);
+        }
         let now = time::Instant::now();
-        if report_interval > 0 && (now - last_report).as_secs() > report_interval {
-            last_report = now;
+        let report_due = report_interval > 0 && (now - last_report).as_secs() > report_interval;
+        if report_due || commands::take_report_request() {
             // Ignore report errors
-            let _res = reporter.send(gps_fix, battery_readings);
+            let _res = reporter.send(reporter::Readings {
+                gps: gps_fix,
+                battery: battery_readings,
+                pattern: pattern_name,
+                fps: frames_since_report as f32 / (now - last_report).as_secs_f32(),
+                frame_overruns,
+                motion: motion.state(),
+                shocks: motion.take_shocks(),
+                power_limiting: led.take_power_limiting(),
+            });
+            last_report = now;
+            frames_since_report = 0;
+            frame_overruns = 0;
         }
 
         // Sleep until time for the next pattern step
-        thread::sleep(time::Duration::from_millis(delay_ms));
+        thread::sleep(time::Duration::from_millis(1000 / fps));
     }
 }
 
 #[cfg(not(feature = "hardware"))]
 fn main() -> Result<()> {
-    println!("Hello, world!");
-    println!("Simulator mode: skipping setup and self-tests");
+    logging::init();
+    info!("Hello, world!");
+    info!("Simulator mode: skipping setup and self-tests");
+
+    info!("Loading settings...");
+    settings::load()?;
+    settings::start_watcher();
 
-    println!("Starting worker threads...");
+    control_server::restore_state();
+
+    info!("Starting worker threads...");
+    external_input::start();
+    dmx_input::start();
+    mqtt::start();
+    osc::start();
+    thermal::start();
     // In simulator mode, always enable ws server regardless of config
     let ws = ws_server::WsServer::start_server();
-    println!("Worker threads started.");
+    info!("Worker threads started.");
 
     let mut pattern_manager = pattern_manager::PatternManager::new();
+    let mut effect_manager = effects::EffectManager::new();
 
-    let delay_ms = 1000 / SETTINGS.get::<u64>("fps")?;
+    health::register("main", Some(time::Duration::from_secs(2)));
 
+    let mut last_frame_start = time::Instant::now();
     loop {
+        health::heartbeat("main");
+        let frame_start = time::Instant::now();
+        let frame_interval = frame_start - last_frame_start;
+        last_frame_start = frame_start;
+
         // Mock up sensor values
         let gps_fix = None;
         let imu_readings = ImuReadings::default();
 
         // Step pattern and update LEDs
+        let pattern_name = pattern_manager.pattern_name();
+        mqtt::update_pattern(pattern_name);
         let led_state = pattern_manager.step(&gps_fix, &imu_readings);
-        ws.led_update(led_state)?;
+        let led_state = effect_manager.apply(led_state);
+        let telemetry = if ws.wants_telemetry() {
+            Some(ws_server::Telemetry {
+                imu: imu_readings,
+                gps: gps_fix,
+                battery: None,
+                temperature: temperature::get_temperature(),
+                pattern: pattern_name,
+                frame_time: frame_start.elapsed().as_secs_f32() * 1000.0,
+                fps: 1.0 / frame_interval.as_secs_f32(),
+            })
+        } else {
+            None
+        };
+        ws.led_update(led_state, telemetry)?;
+        metrics::set_sensors(None, gps_fix.as_ref());
+        metrics::observe_frame_time(frame_start.elapsed().as_secs_f64());
 
         // Sleep until time for the next pattern step
-        thread::sleep(time::Duration::from_millis(delay_ms));
+        thread::sleep(time::Duration::from_millis(1000 / thermal::fps()));
     }
 }
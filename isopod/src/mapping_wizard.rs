@@ -0,0 +1,108 @@
+//! Interactive wizard for working out the LED spine mapping.  Each PCB LED
+//! connector is lit up in turn and the operator says, through the control
+//! server, which spine position lit up.  Once every connector has been
+//! identified the resulting mapping is saved to settings.toml.
+
+use crate::common_structs::LedUpdate;
+use crate::settings;
+use crate::SPINES;
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::info;
+use serde::Serialize;
+use std::sync::Mutex;
+
+/// Colour used to light up the connector being identified.  Not full white to
+/// go easy on the power supply.
+#[allow(dead_code)]
+const IDENTIFY_COLOUR: [u8; 3] = [128, 128, 128];
+
+/// Progress through the wizard
+#[derive(Debug, Clone, Serialize)]
+pub struct WizardStatus {
+    /// PCB connector currently lit up, 1-based
+    pub connector: usize,
+
+    /// Spine positions identified so far, indexed by PCB connector
+    pub mapping: Vec<usize>,
+}
+
+lazy_static! {
+    /// None unless the wizard is in progress
+    static ref WIZARD: Mutex<Option<WizardStatus>> = Mutex::new(None);
+}
+
+/// Start (or restart) the wizard from the first connector
+pub fn start() {
+    info!("Started");
+    *WIZARD.lock().unwrap() = Some(WizardStatus {
+        connector: 1,
+        mapping: Vec::new(),
+    });
+}
+
+/// Abandon the wizard without changing the mapping
+pub fn cancel() {
+    info!("Cancelled");
+    *WIZARD.lock().unwrap() = None;
+}
+
+/// Get the wizard's progress, or None if it isn't running
+pub fn status() -> Option<WizardStatus> {
+    WIZARD.lock().unwrap().clone()
+}
+
+/// Record which spine position (1-based) lit up for the current connector and
+/// move on to the next one.  After the last connector the mapping is saved
+/// and the wizard finishes, in which case the new mapping is returned.
+pub fn identify(spine: usize) -> Result<Option<[usize; SPINES]>> {
+    let mut wizard = WIZARD.lock().unwrap();
+    let status = wizard
+        .as_mut()
+        .ok_or_else(|| anyhow!("the mapping wizard isn't running"))?;
+
+    if !(1..=SPINES).contains(&spine) {
+        return Err(anyhow!("spine position must be 1-{}", SPINES));
+    }
+    if let Some(connector) = status.mapping.iter().position(|x| *x == spine) {
+        return Err(anyhow!(
+            "spine position {} was already given for connector {}",
+            spine,
+            connector + 1
+        ));
+    }
+
+    info!(
+        "Connector {} is spine position {}",
+        status.connector, spine
+    );
+    status.mapping.push(spine);
+    status.connector += 1;
+    if status.mapping.len() < SPINES {
+        return Ok(None);
+    }
+
+    let mut map = [0; SPINES];
+    map.copy_from_slice(&status.mapping);
+    *wizard = None;
+    drop(wizard);
+
+    info!("Saving mapping {:?}", map);
+    settings::save_spine_mapping(map)?;
+    Ok(Some(map))
+}
+
+/// If the wizard is running, return the frame the LED driver should show
+/// instead of the pattern: only the current connector lit.  The frame is
+/// indexed by PCB connector, so must be sent to the LEDs without applying the
+/// spine mapping.
+// Only used by the LED driver, which isn't built in simulator mode
+#[allow(dead_code)]
+pub fn override_frame() -> Option<LedUpdate> {
+    let connector = WIZARD.lock().unwrap().as_ref()?.connector;
+    let mut leds = LedUpdate::default();
+    for led in leds.spines[connector - 1].iter_mut() {
+        *led = IDENTIFY_COLOUR;
+    }
+    Some(leds)
+}
@@ -0,0 +1,181 @@
+//! Metrics for graphing ISOPOD's health, served by the control server at
+//! `/metrics` in the Prometheus text format.  The main loop and the LED
+//! thread record their readings here as they go, and the rest are read when
+//! the metrics are requested.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::common_structs::{BatteryReadings, GpsFix};
+use crate::temperature::get_temperature;
+use crate::thermal;
+use crate::ws_server;
+
+/// Upper bounds of the frame time histogram buckets, in seconds
+const FRAME_TIME_BUCKETS: [f64; 8] = [0.002, 0.005, 0.01, 0.02, 0.033, 0.05, 0.1, 0.25];
+
+struct Histogram {
+    /// Number of observations in each bucket, not including smaller buckets
+    counts: [u64; FRAME_TIME_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// The latest sensor readings from the main loop
+struct Sensors {
+    battery: Option<BatteryReadings>,
+    /// Satellites used for the latest fix, or None without a fix
+    satellites: Option<usize>,
+}
+
+/// The LED thread's estimate of the current drawn by the latest frame
+struct LedPower {
+    /// Estimated current in amps before power limiting
+    current: f32,
+    /// Scale the power limiter applied, 1 if none
+    scale: f32,
+}
+
+static FRAME_TIMES: Mutex<Histogram> = Mutex::new(Histogram {
+    counts: [0; FRAME_TIME_BUCKETS.len()],
+    count: 0,
+    sum: 0.0,
+});
+static LED_PACKETS_DROPPED: AtomicU64 = AtomicU64::new(0);
+static LED_POWER: Mutex<Option<LedPower>> = Mutex::new(None);
+static SENSORS: Mutex<Sensors> = Mutex::new(Sensors {
+    battery: None,
+    satellites: None,
+});
+
+/// Record how long a frame took to render
+pub fn observe_frame_time(seconds: f64) {
+    let mut histogram = FRAME_TIMES.lock().unwrap();
+    if let Some(bucket) = FRAME_TIME_BUCKETS.iter().position(|&x| seconds <= x) {
+        histogram.counts[bucket] += 1;
+    }
+    histogram.count += 1;
+    histogram.sum += seconds;
+}
+
+/// Record that the LED thread fell behind and dropped a frame
+#[allow(dead_code)]
+pub fn led_packet_dropped() {
+    LED_PACKETS_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record the estimated LED current for a frame and the power limiter's
+/// scaling, if any
+#[allow(dead_code)]
+pub fn set_led_power(current: f32, scale: Option<f32>) {
+    *LED_POWER.lock().unwrap() = Some(LedPower {
+        current,
+        scale: scale.unwrap_or(1.0),
+    });
+}
+
+/// Record the latest sensor readings
+pub fn set_sensors(battery: Option<BatteryReadings>, gps: Option<&GpsFix>) {
+    let mut sensors = SENSORS.lock().unwrap();
+    sensors.battery = battery;
+    sensors.satellites = gps.map(|x| x.satellites);
+}
+
+/// Write one metric with its help and type.  Writing to a String can't fail.
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+}
+
+/// All the metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+
+    {
+        let name = "isopod_frame_time_seconds";
+        let histogram = FRAME_TIMES.lock().unwrap();
+        let _ = writeln!(out, "# HELP {} Time taken to render each frame", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        // Prometheus buckets count everything up to their bound
+        let mut cumulative = 0;
+        for (bound, count) in FRAME_TIME_BUCKETS.iter().zip(histogram.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count);
+        let _ = writeln!(out, "{}_sum {}", name, histogram.sum);
+        let _ = writeln!(out, "{}_count {}", name, histogram.count);
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP isopod_led_packets_dropped_total LED frames dropped because the LED thread fell behind\n\
+         # TYPE isopod_led_packets_dropped_total counter\n\
+         isopod_led_packets_dropped_total {}",
+        LED_PACKETS_DROPPED.load(Ordering::Relaxed)
+    );
+
+    // Readings we don't have yet are left out rather than made up
+    if let Some(ref power) = *LED_POWER.lock().unwrap() {
+        gauge(
+            &mut out,
+            "isopod_led_current_amps",
+            "Estimated LED current for the latest frame before power limiting",
+            power.current,
+        );
+        gauge(
+            &mut out,
+            "isopod_led_power_scale",
+            "Scaling applied by the LED power limiter, 1 if none",
+            power.scale,
+        );
+    }
+
+    let sensors = SENSORS.lock().unwrap();
+    if let Some(battery) = sensors.battery {
+        gauge(&mut out, "isopod_battery_voltage_volts", "Battery pack voltage", battery.voltage);
+        gauge(
+            &mut out,
+            "isopod_battery_current_amps",
+            "Battery pack current, negative when discharging",
+            battery.current,
+        );
+        gauge(&mut out, "isopod_battery_soc_percent", "Battery state of charge", battery.soc);
+    }
+    gauge(
+        &mut out,
+        "isopod_gps_fix",
+        "Whether the GPS has a fix",
+        sensors.satellites.is_some() as u8,
+    );
+    if let Some(satellites) = sensors.satellites {
+        gauge(&mut out, "isopod_gps_satellites", "Satellites used for the latest GPS fix", satellites);
+    }
+    drop(sensors);
+
+    if let Some(temperature) = get_temperature() {
+        gauge(&mut out, "isopod_cpu_temperature_celsius", "Pi CPU temperature", temperature);
+    }
+    let throttling = thermal::throttling();
+    gauge(
+        &mut out,
+        "isopod_thermal_level",
+        "Thermal throttling level reached, 0 if not throttling",
+        throttling.level,
+    );
+    gauge(&mut out, "isopod_thermal_fps", "Frame rate after thermal throttling", thermal::fps());
+    gauge(
+        &mut out,
+        "isopod_thermal_max_brightness_percent",
+        "LED brightness cap from thermal throttling, 100 if none",
+        throttling.max_brightness.unwrap_or(100),
+    );
+    gauge(
+        &mut out,
+        "isopod_ws_clients",
+        "Visualiser websocket clients connected",
+        ws_server::client_count(),
+    );
+
+    out
+}
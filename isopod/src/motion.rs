@@ -0,0 +1,131 @@
+//! Keeps track of how ISOPOD is moving, for the reporter: whether it's sitting
+//! still or being moved around, and any sharp knocks.  Like the shock
+//! pattern, this compares each accelerometer reading against a moving
+//! average, but with a much higher threshold so only real knocks count.
+
+#![allow(unused)]
+
+use crate::common_structs::ImuReadings;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Number of readings in the moving average, about a second at 60fps
+const MOVING_AVERAGE_LEN: usize = 60;
+
+/// If no reading in the moving average window differs from the average by
+/// more than this, in m/s/s, then we're sitting still
+const STILL_THRESH: f32 = 0.5;
+
+/// A reading which differs from the moving average by more than this, in
+/// m/s/s, is a shock
+const SHOCK_THRESH: f32 = 15.0;
+
+/// Readings over the threshold within this long of a shock are counted as
+/// part of the same shock
+const SHOCK_HOLDOFF: Duration = Duration::from_millis(500);
+
+/// Maximum number of shocks to remember between reports.  Once full, the
+/// oldest are thrown away.
+const MAX_SHOCKS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MotionState {
+    /// Not enough readings yet to tell
+    Unknown,
+    Still,
+    Moving,
+}
+
+/// A sharp knock
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Shock {
+    /// When the shock started
+    pub time: DateTime<Utc>,
+    /// The largest difference from the moving average during the shock, in
+    /// m/s/s
+    pub magnitude: f32,
+}
+
+pub struct MotionTracker {
+    /// The latest accelerometer readings, oldest first
+    readings: VecDeque<[f32; 3]>,
+    state: MotionState,
+    /// Shocks since take_shocks() was last called
+    shocks: VecDeque<Shock>,
+    /// When a reading was last over the shock threshold
+    last_over_thresh: Option<Instant>,
+}
+
+impl MotionTracker {
+    pub fn new() -> Self {
+        Self {
+            readings: VecDeque::with_capacity(MOVING_AVERAGE_LEN + 1),
+            state: MotionState::Unknown,
+            shocks: VecDeque::new(),
+            last_over_thresh: None,
+        }
+    }
+
+    /// Process the latest IMU readings.  Should be called every frame.
+    pub fn update(&mut self, imu: &ImuReadings) {
+        let reading = [imu.xa, imu.ya, imu.za];
+        self.readings.push_back(reading);
+        if self.readings.len() <= MOVING_AVERAGE_LEN {
+            return;
+        }
+        self.readings.pop_front();
+
+        let mut sum = [0f32; 3];
+        for x in &self.readings {
+            sum[0] += x[0];
+            sum[1] += x[1];
+            sum[2] += x[2];
+        }
+        let average = sum.map(|x| x / MOVING_AVERAGE_LEN as f32);
+        let deviation = |x: &[f32; 3]| {
+            ((x[0] - average[0]).powi(2) + (x[1] - average[1]).powi(2) + (x[2] - average[2]).powi(2))
+                .sqrt()
+        };
+
+        let max_deviation = self.readings.iter().map(deviation).fold(0.0, f32::max);
+        self.state = if max_deviation < STILL_THRESH {
+            MotionState::Still
+        } else {
+            MotionState::Moving
+        };
+
+        let magnitude = deviation(&reading);
+        if magnitude > SHOCK_THRESH {
+            let continuing = self
+                .last_over_thresh
+                .map(|x| x.elapsed() < SHOCK_HOLDOFF)
+                .unwrap_or(false);
+            match self.shocks.back_mut() {
+                Some(shock) if continuing => shock.magnitude = shock.magnitude.max(magnitude),
+                _ => {
+                    if self.shocks.len() >= MAX_SHOCKS {
+                        self.shocks.pop_front();
+                    }
+                    self.shocks.push_back(Shock {
+                        time: Utc::now(),
+                        magnitude,
+                    });
+                }
+            }
+            self.last_over_thresh = Some(Instant::now());
+        }
+    }
+
+    /// Whether we're currently sitting still or moving
+    pub fn state(&self) -> MotionState {
+        self.state
+    }
+
+    /// Get the shocks since this was last called, oldest first
+    pub fn take_shocks(&mut self) -> Vec<Shock> {
+        self.shocks.drain(..).collect()
+    }
+}
@@ -0,0 +1,264 @@
+//! Optional MQTT client for dashboards such as Home Assistant.  Every
+//! `mqtt_interval` seconds we publish our state under the `mqtt_topic`
+//! prefix, e.g. with the default prefix "isopod":
+//! * `isopod/status`: "online", or "offline" (sent by the broker as our Last
+//!   Will if we drop off).  Retained.
+//! * `isopod/battery`: battery readings as JSON
+//! * `isopod/gps`: the latest GPS fix as JSON, or null if there isn't one
+//! * `isopod/temperature`: Pi temperature in degrees C
+//! * `isopod/pattern`: name of the pattern playing
+//! * `isopod/brightness`: brightness set from the control panel, 0-100
+//! * `isopod/health`: worker thread health as JSON, like the /health endpoint
+//!
+//! Commands are taken from `isopod/set/<control>`, where `<control>` is
+//! anything the control panel can send to /command, e.g. publishing "glitch"
+//! to `isopod/set/pattern` or "50" to `isopod/set/brightness`.
+//!
+//! Messages published while the broker can't be reached are buffered, up to
+//! a limit, and sent when we reconnect.
+
+use crate::common_structs::{BatteryReadings, GpsFix};
+use crate::control_server::{self, CONTROLS};
+use crate::health;
+use crate::settings;
+use crate::temperature;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Maximum number of messages to keep while the broker can't be reached.
+/// Once full, the oldest are thrown away.
+const MAX_BUFFERED: usize = 100;
+
+/// How long to wait before trying to reconnect to the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A message waiting to be published
+struct Message {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+lazy_static! {
+    static ref CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+    static ref BUFFER: Mutex<VecDeque<Message>> = Mutex::new(VecDeque::new());
+    static ref SENSORS: Mutex<(Option<GpsFix>, Option<BatteryReadings>)> = Mutex::new((None, None));
+}
+
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// The pattern playing, which may differ from the one chosen in the controls
+/// during transitions or while external input has taken over
+static PATTERN: Mutex<&str> = Mutex::new("");
+
+/// Give the MQTT client the latest sensor readings to publish
+// Only used on hardware, the simulator has no sensors
+#[allow(dead_code)]
+pub fn update_sensors(gps: Option<GpsFix>, battery: BatteryReadings) {
+    *SENSORS.lock().unwrap() = (gps, Some(battery));
+}
+
+/// Tell the MQTT client which pattern is playing
+pub fn update_pattern(name: &'static str) {
+    *PATTERN.lock().unwrap() = name;
+}
+
+/// Publish a message, or buffer it if we're not connected
+fn publish(message: Message) {
+    let mut buffer = BUFFER.lock().unwrap();
+    if CONNECTED.load(Ordering::Relaxed) && buffer.is_empty() {
+        if let Some(client) = CLIENT.lock().unwrap().as_mut() {
+            let res = client.try_publish(
+                &message.topic,
+                QoS::AtLeastOnce,
+                message.retain,
+                message.payload.as_bytes(),
+            );
+            if res.is_ok() {
+                return;
+            }
+        }
+    }
+
+    if buffer.len() >= MAX_BUFFERED {
+        buffer.pop_front();
+    }
+    buffer.push_back(message);
+}
+
+/// Send everything that was buffered while we were offline
+fn flush() {
+    let mut buffer = BUFFER.lock().unwrap();
+    let mut client = CLIENT.lock().unwrap();
+    let client = match client.as_mut() {
+        Some(x) => x,
+        None => return,
+    };
+    if !buffer.is_empty() {
+        info!("Sending {} buffered messages", buffer.len());
+    }
+    while let Some(message) = buffer.front() {
+        let res = client.try_publish(
+            &message.topic,
+            QoS::AtLeastOnce,
+            message.retain,
+            message.payload.as_bytes(),
+        );
+        if res.is_err() {
+            // The client's queue is full, so try again next time
+            break;
+        }
+        buffer.pop_front();
+    }
+}
+
+/// Publish our current state
+fn publish_state(prefix: &str) -> Result<()> {
+    let (gps, battery) = *SENSORS.lock().unwrap();
+    let brightness = CONTROLS.read().unwrap().brightness;
+    let messages = [
+        ("battery", serde_json::to_string(&battery)?),
+        ("gps", serde_json::to_string(&gps)?),
+        ("temperature", serde_json::to_string(&temperature::get_temperature())?),
+        ("pattern", PATTERN.lock().unwrap().to_string()),
+        ("brightness", brightness.to_string()),
+        (
+            "health",
+            serde_json::to_string(&serde_json::json!({
+                "healthy": health::all_ok(),
+                "workers": health::status(),
+            }))?,
+        ),
+    ];
+    for (name, payload) in messages {
+        publish(Message {
+            topic: format!("{}/{}", prefix, name),
+            payload,
+            retain: false,
+        });
+    }
+    Ok(())
+}
+
+/// Work out the control and value from a message on one of the command
+/// topics, or None if it isn't a command
+fn parse_command(prefix: &str, topic: &str, payload: &[u8]) -> Option<(String, String)> {
+    let control = topic.strip_prefix(prefix)?.strip_prefix("/set/")?;
+    if control.is_empty() || control.contains('/') {
+        return None;
+    }
+    let value = String::from_utf8_lossy(payload).trim().to_owned();
+    Some((control.to_owned(), value))
+}
+
+/// Handle a message on one of the command topics
+fn handle_command(prefix: &str, topic: &str, payload: &[u8]) {
+    let (control, value) = match parse_command(prefix, topic, payload) {
+        Some(x) => x,
+        None => return,
+    };
+    info!("Command: {} = {}", control, value);
+    control_server::apply_command(&HashMap::from([(control, value)]));
+}
+
+/// Keep the connection to the broker going and handle incoming messages
+fn connection_worker() -> Result<()> {
+    let settings = settings::get();
+    let prefix = settings.mqtt_topic.clone();
+    let status_topic = format!("{}/status", prefix);
+
+    let mut options = MqttOptions::new(&settings.mqtt_client_id, &settings.mqtt_host, settings.mqtt_port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(&status_topic, "offline", QoS::AtLeastOnce, true));
+    if !settings.mqtt_username.is_empty() {
+        options.set_credentials(&settings.mqtt_username, &settings.mqtt_password);
+    }
+
+    let (client, mut connection) = Client::new(options, MAX_BUFFERED);
+    *CLIENT.lock().unwrap() = Some(client);
+
+    // Only report connection failures once, rather than on every retry
+    let mut reported = false;
+    for event in connection.iter() {
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to {}:{}", settings.mqtt_host, settings.mqtt_port);
+                reported = false;
+                if let Some(client) = CLIENT.lock().unwrap().as_mut() {
+                    client.try_subscribe(format!("{}/set/+", prefix), QoS::AtLeastOnce)?;
+                    client.try_publish(&status_topic, QoS::AtLeastOnce, true, "online")?;
+                }
+                CONNECTED.store(true, Ordering::Relaxed);
+                flush();
+            }
+            Ok(Event::Incoming(Packet::Publish(message))) => {
+                handle_command(&prefix, &message.topic, &message.payload);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                CONNECTED.store(false, Ordering::Relaxed);
+                if !reported {
+                    warn!("Can't reach broker, buffering messages: {}", e);
+                    reported = true;
+                }
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Publish our state regularly
+fn publisher_worker(prefix: &str) -> Result<()> {
+    loop {
+        publish_state(prefix)?;
+        thread::sleep(Duration::from_secs(settings::get().mqtt_interval));
+    }
+}
+
+/// Start the MQTT client, if enabled in the settings
+pub fn start() {
+    let settings = settings::get();
+    if !settings.mqtt {
+        return;
+    }
+
+    health::spawn_supervised("MQTT", None, connection_worker);
+    let prefix = settings.mqtt_topic.clone();
+    health::spawn_supervised("MQTT publisher", None, move || publisher_worker(&prefix));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(control: &str, value: &str) -> Option<(String, String)> {
+        Some((control.to_owned(), value.to_owned()))
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse_command("isopod", "isopod/set/pattern", b"glitch"), command("pattern", "glitch"));
+        assert_eq!(parse_command("isopod", "isopod/set/brightness", b" 50\n"), command("brightness", "50"));
+        assert_eq!(parse_command("a/b", "a/b/set/brightness", b"50"), command("brightness", "50"));
+        assert_eq!(parse_command("isopod", "isopod/set/pattern", b""), command("pattern", ""));
+        assert_eq!(parse_command("isopod", "isopod/set/pattern", b"gl\xffitch"), command("pattern", "gl\u{fffd}itch"));
+    }
+
+    #[test]
+    fn not_commands() {
+        assert_eq!(parse_command("isopod", "isopod/pattern", b"glitch"), None);
+        assert_eq!(parse_command("isopod", "isopod/set/", b"glitch"), None);
+        assert_eq!(parse_command("isopod", "isopod/set/a/b", b"glitch"), None);
+        assert_eq!(parse_command("isopod", "other/set/pattern", b"glitch"), None);
+        assert_eq!(parse_command("isopod", "isopodx/set/pattern", b"glitch"), None);
+        assert_eq!(parse_command("isopod", "set/pattern", b"glitch"), None);
+    }
+}
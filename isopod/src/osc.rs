@@ -0,0 +1,324 @@
+//! Listens for OSC over UDP so ISOPOD can be driven live by performers and VJ
+//! tools such as TouchOSC.  The address space is:
+//! * `/isopod/pattern <name>`: switch pattern
+//! * `/isopod/brightness <0-100>`: set brightness as a percentage
+//! * `/isopod/param/<name> <value>`: set a pattern parameter, e.g.
+//!   `/isopod/param/rainbow_swirl_speed -3.0`
+//! * `/isopod/trigger/<effect>`: trigger an effect.  A numeric argument of 0
+//!   is ignored, so buttons which send 1 on press and 0 on release trigger
+//!   once.
+//!
+//! These change the same controls as the control panel, with the same
+//! validation.  Since faders send lots of messages, the controls are only
+//! saved to the state file once messages stop arriving for a moment.
+
+use crate::control_server;
+use crate::effects;
+use crate::health;
+use crate::settings;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+/// Save the controls once no messages have arrived for this long
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+/// Prefix of all the OSC addresses we respond to
+const ADDRESS_PREFIX: &str = "/isopod/";
+
+/// An OSC argument, in the types we can make use of
+#[derive(Debug, Clone, PartialEq)]
+enum Arg {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+}
+
+impl Arg {
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Arg::Int(x) => Some(*x as f64),
+            Arg::Float(x) => Some(*x),
+            Arg::Bool(x) => Some(if *x { 1.0 } else { 0.0 }),
+            Arg::Str(x) => x.parse().ok(),
+        }
+    }
+
+    fn as_string(&self) -> String {
+        match self {
+            Arg::Str(x) => x.clone(),
+            x => x.as_f64().unwrap_or(0.0).to_string(),
+        }
+    }
+}
+
+/// Reads the 4-byte aligned pieces of an OSC packet
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or_else(|| anyhow!("OSC packet truncated"))?;
+        let data = self
+            .buf
+            .get(self.pos..end)
+            .ok_or_else(|| anyhow!("OSC packet truncated"))?;
+        self.pos = end;
+        Ok(data)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let rest = &self.buf[self.pos.min(self.buf.len())..];
+        let len = rest
+            .iter()
+            .position(|x| *x == 0)
+            .ok_or_else(|| anyhow!("OSC string not terminated"))?;
+        let s = String::from_utf8_lossy(&rest[..len]).into_owned();
+        // Strings are null terminated and padded to a multiple of 4 bytes
+        self.take((len + 4) & !3)?;
+        Ok(s)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+}
+
+/// Parse an OSC packet into (address, arguments) messages.  Bundles are
+/// flattened, and their time tags ignored.
+fn parse_packet(buf: &[u8], messages: &mut Vec<(String, Vec<Arg>)>) -> Result<()> {
+    let mut reader = Reader { buf, pos: 0 };
+    let address = reader.string()?;
+
+    if address == "#bundle" {
+        reader.take(8)?;
+        while reader.pos < buf.len() {
+            let len = usize::try_from(i32::from_be_bytes(reader.array()?))
+                .map_err(|_| anyhow!("negative OSC bundle element length"))?;
+            parse_packet(reader.take(len)?, messages)?;
+        }
+        return Ok(());
+    }
+
+    // Very old senders leave out the type tags, in which case there are no
+    // arguments we can understand
+    let tags = if reader.pos < buf.len() {
+        reader.string()?
+    } else {
+        String::new()
+    };
+    let mut args = Vec::new();
+    for tag in tags.chars().skip_while(|x| *x == ',') {
+        let arg = match tag {
+            'i' => Arg::Int(i32::from_be_bytes(reader.array()?) as i64),
+            'h' => Arg::Int(i64::from_be_bytes(reader.array()?)),
+            'f' => Arg::Float(f32::from_be_bytes(reader.array()?) as f64),
+            'd' => Arg::Float(f64::from_be_bytes(reader.array()?)),
+            's' | 'S' => Arg::Str(reader.string()?),
+            'T' => Arg::Bool(true),
+            'F' => Arg::Bool(false),
+            // Nil and impulse have no data
+            'N' | 'I' => continue,
+            x => return Err(anyhow!("unsupported OSC type tag {:?}", x)),
+        };
+        args.push(arg);
+    }
+
+    messages.push((address, args));
+    Ok(())
+}
+
+/// Act on an OSC message.  Returns true if the controls were changed.
+fn handle_message(address: &str, args: &[Arg]) -> bool {
+    let path = match address.strip_prefix(ADDRESS_PREFIX) {
+        Some(x) => x,
+        None => return false,
+    };
+
+    if let Some(effect) = path.strip_prefix("trigger/") {
+        let released = args.first().and_then(|x| x.as_f64()) == Some(0.0);
+        if !released && !effects::trigger(effect) {
+            warn!("Unknown effect {}", effect);
+        }
+        return false;
+    }
+
+    let arg = match args.first() {
+        Some(x) => x,
+        None => return false,
+    };
+    let (key, value) = if let Some(name) = path.strip_prefix("param/") {
+        (format!("param_{}", name), arg.as_string())
+    } else {
+        match path {
+            "pattern" => ("pattern".to_owned(), arg.as_string()),
+            "brightness" => match arg.as_f64() {
+                Some(x) => ("brightness".to_owned(), (x.round().clamp(0.0, 100.0) as u8).to_string()),
+                None => return false,
+            },
+            _ => return false,
+        }
+    };
+    control_server::update_controls(&HashMap::from([(key, value)]));
+    true
+}
+
+fn osc_server(port: u16) -> Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    socket.set_read_timeout(Some(SAVE_DELAY))?;
+    info!("Listening for OSC on port {}", port);
+
+    let mut buf = [0u8; 4096];
+    let mut unsaved = false;
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(x) => x,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                if unsaved {
+                    control_server::save_controls();
+                    unsaved = false;
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut messages = Vec::new();
+        if let Err(e) = parse_packet(&buf[..len], &mut messages) {
+            warn!("Bad OSC packet from {}: {:#}", peer, e);
+        }
+        for (address, args) in messages {
+            unsaved |= handle_message(&address, &args);
+        }
+    }
+}
+
+/// Start listening for OSC, if enabled in the settings
+pub fn start() {
+    let settings = settings::get();
+    if !settings.osc {
+        return;
+    }
+
+    let port = settings.osc_port;
+    health::spawn_supervised("OSC input", None, move || osc_server(port));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode an OSC string, null terminated and padded
+    fn osc_string(s: &str) -> Vec<u8> {
+        let mut buf = s.as_bytes().to_vec();
+        buf.resize((s.len() + 4) & !3, 0);
+        buf
+    }
+
+    fn message(address: &str, tags: &str, data: &[u8]) -> Vec<u8> {
+        let mut buf = osc_string(address);
+        buf.extend(osc_string(tags));
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = osc_string("#bundle");
+        buf.extend_from_slice(&1u64.to_be_bytes());
+        for element in elements {
+            buf.extend_from_slice(&(element.len() as i32).to_be_bytes());
+            buf.extend_from_slice(element);
+        }
+        buf
+    }
+
+    fn parse(buf: &[u8]) -> Result<Vec<(String, Vec<Arg>)>> {
+        let mut messages = Vec::new();
+        parse_packet(buf, &mut messages)?;
+        Ok(messages)
+    }
+
+    #[test]
+    fn messages() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(-3i32).to_be_bytes());
+        data.extend_from_slice(&1.5f32.to_be_bytes());
+        data.extend(osc_string("glitch"));
+        data.extend_from_slice(&7i64.to_be_bytes());
+        data.extend_from_slice(&0.25f64.to_be_bytes());
+        let buf = message("/isopod/test", ",ifshdTFNI", &data);
+        assert_eq!(
+            parse(&buf).unwrap(),
+            [(
+                "/isopod/test".to_owned(),
+                vec![
+                    Arg::Int(-3),
+                    Arg::Float(1.5),
+                    Arg::Str("glitch".to_owned()),
+                    Arg::Int(7),
+                    Arg::Float(0.25),
+                    Arg::Bool(true),
+                    Arg::Bool(false),
+                ]
+            )]
+        );
+
+        // No type tags at all
+        assert_eq!(parse(&osc_string("/isopod/x")).unwrap(), [("/isopod/x".to_owned(), vec![])]);
+    }
+
+    #[test]
+    fn bundles() {
+        let a = message("/a", ",i", &1i32.to_be_bytes());
+        let b = message("/b", ",", &[]);
+        let c = message("/c", ",T", &[]);
+        let buf = bundle(&[a, bundle(&[b, bundle(&[])]), c]);
+        let addresses: Vec<String> = parse(&buf).unwrap().into_iter().map(|(x, _)| x).collect();
+        assert_eq!(addresses, ["/a", "/b", "/c"]);
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = message("/a", ",if", &[0; 8]);
+        for len in [0, 2, 7, 12] {
+            assert!(parse(&buf[..len]).is_err(), "length {}", len);
+        }
+        assert!(parse(&buf[..buf.len() - 1]).is_err());
+
+        // Bundle elements longer than what's left
+        let mut buf = bundle(&[message("/a", ",", &[])]);
+        buf.pop();
+        assert!(parse(&buf).is_err());
+        let mut buf = bundle(&[]);
+        buf.extend_from_slice(&[0, 0]);
+        assert!(parse(&buf).is_err());
+    }
+
+    #[test]
+    fn bad_lengths() {
+        for len in [-1i32, i32::MIN, i32::MAX] {
+            let mut buf = bundle(&[]);
+            buf.extend_from_slice(&len.to_be_bytes());
+            buf.extend(message("/a", ",", &[]));
+            assert!(parse(&buf).is_err(), "length {}", len);
+        }
+
+        let mut reader = Reader { buf: &[0; 8], pos: 4 };
+        assert!(reader.take(usize::MAX).is_err());
+        assert_eq!(reader.pos, 4);
+    }
+
+    #[test]
+    fn bad_messages() {
+        assert!(parse(b"/a").is_err());
+        assert!(parse(&message("/a", ",x", &[])).is_err());
+    }
+}
@@ -3,8 +3,22 @@
 //! movement and orientation
 
 use crate::common_structs::{GpsFix, ImuReadings, LedUpdate};
-use crate::patterns::{pattern_by_name, Pattern, colourwipes::ColourWipes};
+use crate::patterns::{pattern_by_name, Pattern, colourwipes::ColourWipes, external::External};
 use crate::control_server::CONTROLS;
+use crate::external_input;
+use crate::settings;
+use log::info;
+use std::time::{Duration, Instant};
+
+/// The pattern which should be playing: the one chosen in the controls,
+/// unless external software is driving the LEDs
+fn wanted_pattern() -> String {
+    if external_input::taken_over() {
+        External::NAME.to_owned()
+    } else {
+        CONTROLS.read().unwrap().pattern.clone()
+    }
+}
 
 /// State machine for the pattern manager.  Some of the states have an associated pattern
 /// which is the one currently selected for playback.  The pattern can't change without
@@ -33,6 +47,10 @@ pub struct PatternManager {
     /// reasons so that we can return an LedUpdate reference with lifetime tied
     /// to the current pattern.
     next_state: Option<PatternManagerState>,
+
+    /// When the current pattern started playing, for stepping through the
+    /// playlist
+    pattern_started: Instant,
 }
 
 impl Default for PatternManager {
@@ -40,6 +58,7 @@ impl Default for PatternManager {
         Self {
             state: PatternManagerState::JukeboxTransition(LedUpdate::default(), 0),
             next_state: None,
+            pattern_started: Instant::now(),
         }
     }
 }
@@ -47,7 +66,7 @@ impl Default for PatternManager {
 impl PatternManager {
     /// Make a new pattern manager
     pub fn new() -> Self {
-        let pattern_name = CONTROLS.read().unwrap().pattern.clone();
+        let pattern_name = wanted_pattern();
         // Load pattern if possible, but if not found then just use
         // colour_wipes as default.
         let pattern = match pattern_by_name(&pattern_name) {
@@ -61,6 +80,15 @@ impl PatternManager {
         }
     }
 
+    /// Name of the pattern currently playing, or "transition" between
+    /// patterns
+    pub fn pattern_name(&self) -> &'static str {
+        match &self.state {
+            PatternManagerState::Jukebox(pattern) => pattern.get_name(),
+            PatternManagerState::JukeboxTransition(..) => "transition",
+        }
+    }
+
     /// Transition between patterns where
     /// necessary.  Run a step of whichever pattern is currently selected
     /// and return an updated set of LED states.
@@ -78,8 +106,29 @@ impl PatternManager {
 
                 let led_state = pattern.step(gps, imu);
 
+                // If there's a playlist and we've played this pattern for
+                // long enough, then move on to the next one in the list.
+                // Don't interrupt external software which is driving the LEDs.
+                let playlist_interval = Duration::from_secs(settings::get().playlist_interval);
+                if self.pattern_started.elapsed() >= playlist_interval
+                    && old_pattern_name != External::NAME
+                {
+                    let mut controls = CONTROLS.write().unwrap();
+                    if !controls.playlist.is_empty() {
+                        let next_idx = controls
+                            .playlist
+                            .iter()
+                            .position(|name| name == old_pattern_name)
+                            .map(|idx| (idx + 1) % controls.playlist.len())
+                            .unwrap_or(0);
+                        controls.pattern = controls.playlist[next_idx].clone();
+                    }
+                    drop(controls);
+                    self.pattern_started = Instant::now();
+                }
+
                 // Check if a pattern change is needed:
-                let new_pattern_name = CONTROLS.read().unwrap().pattern.clone();
+                let new_pattern_name = wanted_pattern();
                 if new_pattern_name != old_pattern_name {
                     // Pattern has changed, go to transitition
                     self.next_state = Some(PatternManagerState::JukeboxTransition(led_state.clone(), 0));
@@ -103,7 +152,7 @@ impl PatternManager {
 
                 // If we're at the end of the transition, then decide where to go next
                 if *frame_count == 60 {
-                    let next_pattern_name = CONTROLS.read().unwrap().pattern.clone();
+                    let next_pattern_name = wanted_pattern();
 
                     // Load pattern if possible, but if not found then just use
                     // colour_wipes as default.
@@ -112,7 +161,8 @@ impl PatternManager {
                         None => ColourWipes::new(),
                     };
 
-                    println!("Jukebox: transitioning to {}", next_pattern.get_name());
+                    info!("Jukebox: transitioning to {}", next_pattern.get_name());
+                    self.pattern_started = Instant::now();
                     self.next_state = Some(PatternManagerState::Jukebox(next_pattern));
                 }
 
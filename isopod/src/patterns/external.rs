@@ -0,0 +1,39 @@
+//! Plays pixels sent by external software over the network, see
+//! external_input.rs.  This isn't chosen from the control panel; the external
+//! input switches to it when a stream starts and back again when it stops.
+
+use crate::common_structs::GpsFix;
+use crate::common_structs::ImuReadings;
+use crate::common_structs::LedUpdate;
+use crate::external_input;
+use crate::patterns::Pattern;
+
+pub struct External {
+    leds: LedUpdate,
+}
+
+impl External {
+    pub const NAME: &'static str = "external";
+}
+
+impl Pattern for External {
+    fn new() -> Box<dyn Pattern> {
+        Box::new(Self {
+            leds: LedUpdate::default(),
+        })
+    }
+
+    fn step(&mut self, _gps: &Option<GpsFix>, _imu: &ImuReadings) -> &LedUpdate {
+        if !external_input::get_frame(&mut self.leds) {
+            // The stream has stopped, so hold the last frame while we
+            // transition back to the previous pattern
+            external_input::give_back();
+        }
+
+        &self.leds
+    }
+
+    fn get_name(&self) -> &'static str {
+        Self::NAME
+    }
+}
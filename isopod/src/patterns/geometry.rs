@@ -4,6 +4,7 @@
 #![allow(unused)]
 
 use lazy_static::lazy_static;
+use log::trace;
 
 /// General purpose 3d vector type
 #[derive(Clone, Debug, PartialEq)]
@@ -87,10 +88,14 @@ impl UnitVector3d {
                 z: -b.sin(),
             },
         };
-        // println!("angles {} {} {}, vec {:?} mag {}",
-        //         a, b, c,
-        //        result.as_vector3d(),
-        //     result.as_vector3d().magnitude());
+        trace!(
+            "angles {} {} {}, vec {:?} mag {}",
+            a,
+            b,
+            c,
+            result.as_vector3d(),
+            result.as_vector3d().magnitude()
+        );
 
         // Sanity-check the result:
         assert!(result.as_vector3d().magnitude() > 0.95);
@@ -4,6 +4,7 @@ use crate::common_structs::GpsFix;
 use crate::common_structs::ImuReadings;
 use crate::common_structs::LedUpdate;
 use crate::patterns::Pattern;
+use log::debug;
 
 pub struct IdSpines {
     leds: LedUpdate,
@@ -59,9 +60,9 @@ impl Pattern for IdSpines {
             }
         }
 
-        // Every second, print accelerometer acceleration readings:
+        // Every second, log accelerometer acceleration readings:
         if self.frame_counter % 60 == 0 {
-            println!("Acceleration: {} {} {}", imu.xa, imu.ya, imu.za)
+            debug!("Acceleration: {} {} {}", imu.xa, imu.ya, imu.za)
         }
         self.frame_counter += 1;
 
@@ -11,6 +11,7 @@ use std::collections::HashMap;
 pub mod beans;
 pub mod colourfield;
 pub mod colourwipes;
+pub mod external;
 pub mod glitch;
 pub mod id_spines;
 pub mod rainbow_swirl;
@@ -121,6 +122,12 @@ lazy_static! {
             rave::Rave::new as fn() -> Box<dyn Pattern>
         ),
 
+        // Driven over the network by external software
+        (
+            external::External::NAME,
+            external::External::new as fn() -> Box<dyn Pattern>
+        ),
+
         // Test patterns, please ignore
         (
             strip_test::StripTest::NAME,
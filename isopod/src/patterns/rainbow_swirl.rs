@@ -8,7 +8,8 @@ use crate::common_structs::GpsFix;
 use crate::common_structs::ImuReadings;
 use crate::common_structs::LedUpdate;
 use crate::patterns::Pattern;
-use crate::SETTINGS;
+use crate::control_server::get_param;
+use crate::settings;
 use crate::{LEDS_PER_SPINE, SPINES};
 
 use color_space::{Hsv, Rgb};
@@ -31,8 +32,10 @@ impl Pattern for RainbowSwirl {
     }
 
     fn step(&mut self, _gps: &Option<GpsFix>, _imu: &ImuReadings) -> &LedUpdate {
-        let radial_smear: f64 = SETTINGS.get("rainbow_swirl_radial_smear").unwrap();
-        let speed: f64 = SETTINGS.get("rainbow_swirl_speed").unwrap();
+        let settings = settings::get();
+        let radial_smear = get_param("rainbow_swirl_radial_smear")
+            .unwrap_or(settings.rainbow_swirl_radial_smear);
+        let speed = get_param("rainbow_swirl_speed").unwrap_or(settings.rainbow_swirl_speed);
 
         // Turn all LEDs off
         for (theta, spine) in self.leds.spines.iter_mut().enumerate() {
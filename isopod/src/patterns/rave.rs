@@ -5,7 +5,8 @@ use crate::common_structs::GpsFix;
 use crate::common_structs::ImuReadings;
 use crate::common_structs::LedUpdate;
 use crate::patterns::Pattern;
-use crate::SETTINGS;
+use crate::control_server::get_param;
+use crate::settings;
 
 use rand::Rng;
 
@@ -35,8 +36,13 @@ impl Pattern for Rave {
     }
 
     fn step(&mut self, _gps: &Option<GpsFix>, _imu: &ImuReadings) -> &LedUpdate {
-        let donk_rate: u32 = SETTINGS.get("donk_rate").unwrap();
-        let donk_len: u32 = SETTINGS.get("donk_len").unwrap();
+        let settings = settings::get();
+        let donk_rate: u32 = get_param("donk_rate")
+            .map(|x| x as u32)
+            .unwrap_or(settings.donk_rate);
+        let donk_len: u32 = get_param("donk_len")
+            .map(|x| x as u32)
+            .unwrap_or(settings.donk_len);
 
         let donk: bool = (self.t % donk_rate) < donk_len;
 
@@ -5,6 +5,7 @@ use crate::common_structs::ImuReadings;
 use crate::common_structs::LedUpdate;
 use crate::patterns::geometry;
 use crate::patterns::Pattern;
+use log::trace;
 
 pub struct Searchlight {
     // Cache this to save allocations even though we overwrite all the LEDs
@@ -39,12 +40,10 @@ impl Pattern for Searchlight {
             let angle = geometry::unit_vector_angle_with_dir(spine_direction, &light_direction);
 
             let colour = if angle > 0.0 {
-                // println!("Spine {} direction {:?} angle {} ON",
-                //     spine_num, spine_direction, angle);
+                trace!("Spine {} direction {:?} angle {} ON", spine_num, spine_direction, angle);
                 [255, 255, 255]
             } else {
-                // println!("Spine {} direction {:?} angle {} OFF",
-                //     spine_num, spine_direction, angle);
+                trace!("Spine {} direction {:?} angle {} OFF", spine_num, spine_direction, angle);
                 [0, 0, 0]
             };
 
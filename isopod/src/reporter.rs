@@ -1,74 +1,344 @@
-//! Reports location and other information to the backend server
+//! Reports location and other information to the backend server.
+//!
+//! Reports are kept in a queue on disk until the backend has accepted them,
+//! so nothing is lost while we're out of signal or the backend is down.  If
+//! an upload fails we back off exponentially before trying again, and once
+//! the backend can be reached the backlog is uploaded in batches, oldest
+//! first.  If the backend turns a batch down, e.g. because it's too big, we
+//! try smaller batches, and drop a single report it won't take so that it
+//! doesn't hold up the rest.  Each upload is a POST of a JSON array of reports to
+//! `reporter_url`, with `reporter_token` as a bearer token if set.  Each report
+//! carries our `device_id` so one backend can keep track of several isopods.
+//!
+//! `https://` URLs are verified against the usual web root certificates, so
+//! use HTTPS whenever a token is set.
+//!
+//! The backend replies with any commands queued for us, see commands.rs, and
+//! we acknowledge them in the next report.
+//!
+//! Reports follow the schema given by the Report struct, which is versioned
+//! with REPORT_VERSION.  Version 1 was a flat object of GPS, battery and
+//! temperature readings, with the temperature as a string like "45°C".
+//! Version 2 had no `device`, version 3 had no `lost_mode` or `acks`, and
+//! version 4 had no `thermal`.
+//! The backend's copy of the structs must be kept in step with these.
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io::Write;
 use std::sync::mpsc;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use ureq::Agent;
 
-use crate::common_structs::BatteryReadings;
-use crate::common_structs::GpsFix;
+use crate::commands::{self, Ack, UploadReply};
+use crate::common_structs::{BatteryReadings, GpsFix, PowerLimiting};
+use crate::control_server::CONTROLS;
+use crate::health::{self, WorkerStatus};
+use crate::motion::{MotionState, Shock};
+use crate::settings;
 use crate::temperature::get_temperature;
+use crate::thermal::{self, Throttling};
+use crate::wifi;
+
+/// Name of the reporter thread for health monitoring
+const WORKER_NAME: &str = "reporter";
+
+/// How long to wait before retrying after the first failed upload.  This
+/// doubles with each failure, up to MAX_BACKOFF.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Version of the report schema, to be bumped whenever Report changes in a
+/// way the backend needs to know about
+const REPORT_VERSION: u32 = 5;
+
+/// Readings gathered by the main loop since the last report
+pub struct Readings {
+    pub gps: Option<GpsFix>,
+    pub battery: BatteryReadings,
+    /// Name of the pattern playing
+    pub pattern: &'static str,
+    /// Average frame rate since the last report
+    pub fps: f32,
+    /// Number of frames since the last report which took longer than the
+    /// frame period to render
+    pub frame_overruns: u64,
+    pub motion: MotionState,
+    /// Shocks since the last report
+    pub shocks: Vec<Shock>,
+    /// How much the LED power limiter stepped in since the last report
+    pub power_limiting: PowerLimiting,
+}
+
+/// A report as sent to the backend
+#[derive(Serialize)]
+struct Report {
+    version: u32,
+    /// Which isopod this is
+    device: String,
+    /// When the report was made, by the system clock
+    time: DateTime<Utc>,
+    /// Seconds since ISOPOD started
+    uptime: f64,
+    /// The latest GPS fix, or None if we've never had one
+    gps: Option<GpsFix>,
+    battery: BatteryReadings,
+    /// Pi temperature in degrees C
+    temperature: Option<f32>,
+    /// How far we've throttled back to keep cool
+    thermal: Throttling,
+    /// Wi-Fi signal level in dBm
+    wifi_signal: Option<i32>,
+    pattern: &'static str,
+    /// Brightness set from the control panel, 0-100
+    brightness: u8,
+    fps: f32,
+    frame_overruns: u64,
+    /// Health of each worker thread, like the /health endpoint
+    health: BTreeMap<&'static str, WorkerStatus>,
+    motion: MotionState,
+    shocks: Vec<Shock>,
+    power_limiting: PowerLimiting,
+    lost_mode: bool,
+    /// Results of commands from the backend since the last report
+    acks: Vec<Ack>,
+}
+
+/// Reports which haven't been uploaded yet, mirrored in the queue file
+struct Queue {
+    reports: VecDeque<serde_json::Value>,
+    path: String,
+}
+
+impl Queue {
+    /// Load any reports left over from last time
+    fn load(path: &str) -> Self {
+        let mut reports = VecDeque::new();
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                // Skip lines we can't parse, e.g. if we lost power mid-write
+                reports.extend(contents.lines().filter_map(|x| serde_json::from_str(x).ok()));
+                if !reports.is_empty() {
+                    info!("{} unsent reports queued from last time", reports.len());
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("Can't read queue file {}: {}", path, e),
+        }
+        Self {
+            reports,
+            path: path.to_owned(),
+        }
+    }
+
+    /// Add a report to the end of the queue, dropping the oldest if it's full
+    fn push(&mut self, report: serde_json::Value, max_len: usize) -> Result<()> {
+        self.reports.push_back(report);
+        if self.reports.len() > max_len {
+            let excess = self.reports.len() - max_len;
+            self.reports.drain(..excess);
+            return self.save();
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path))?;
+        writeln!(file, "{}", self.reports.back().unwrap())?;
+        Ok(())
+    }
+
+    /// Remove reports which have been uploaded from the front of the queue
+    fn pop(&mut self, count: usize) -> Result<()> {
+        self.reports.drain(..count);
+        self.save()
+    }
+
+    /// Rewrite the queue file from scratch
+    fn save(&self) -> Result<()> {
+        let mut contents = String::new();
+        for report in &self.reports {
+            contents.push_str(&report.to_string());
+            contents.push('\n');
+        }
+        // Write to a temporary file first so we never leave a partial queue
+        let tmp_path = format!("{}.tmp", self.path);
+        fs::write(&tmp_path, contents).with_context(|| format!("Failed to write {}", tmp_path))?;
+        fs::rename(&tmp_path, &self.path).with_context(|| format!("Failed to replace {}", self.path))?;
+        Ok(())
+    }
+}
 
 pub struct Reporter {
-    tx: mpsc::Sender<(Option<GpsFix>, BatteryReadings)>,
+    tx: mpsc::Sender<Readings>,
 }
 
 impl Reporter {
     pub fn new() -> Self {
         let (tx, rx) = mpsc::channel();
-        thread::Builder::new()
-            .name("ISOPOD reporter".into())
-            .spawn(move || Self::reporter_thread(rx))
-            .unwrap();
+        let started = Instant::now();
+        // The reporter sits waiting for reports so doesn't send heartbeats
+        health::spawn_supervised(WORKER_NAME, None, move || Self::reporter_thread(&rx, started));
         Self { tx }
     }
 
-    fn reporter_thread(rx: mpsc::Receiver<(Option<GpsFix>, BatteryReadings)>) -> ! {
+    /// Build a report from the main loop's readings and our own
+    fn make_report(readings: Readings, started: Instant) -> Report {
+        Report {
+            version: REPORT_VERSION,
+            device: settings::get().device_id.clone(),
+            time: Utc::now(),
+            uptime: started.elapsed().as_secs_f64(),
+            gps: readings.gps,
+            battery: readings.battery,
+            temperature: get_temperature(),
+            thermal: thermal::throttling(),
+            wifi_signal: wifi::get_signal(),
+            pattern: readings.pattern,
+            brightness: CONTROLS.read().unwrap().brightness,
+            fps: readings.fps,
+            frame_overruns: readings.frame_overruns,
+            health: health::status(),
+            motion: readings.motion,
+            shocks: readings.shocks,
+            power_limiting: readings.power_limiting,
+            lost_mode: commands::lost_mode(),
+            acks: commands::take_acks(),
+        }
+    }
+
+    /// Upload a batch of reports from the front of the queue, and carry out
+    /// any commands in the reply
+    fn upload(agent: &Agent, queue: &Queue, count: usize) -> Result<()> {
+        let settings = settings::get();
+        let batch: Vec<&serde_json::Value> = queue.reports.iter().take(count).collect();
+
+        let mut request = agent.post(&settings.reporter_url);
+        if !settings.reporter_token.is_empty() {
+            request = request.set("Authorization", &format!("Bearer {}", settings.reporter_token));
+        }
+        let response = request
+            .send_json(serde_json::to_value(batch)?)
+            .context("Failed to upload reports")?;
+
+        // The reports have been accepted even if we can't understand the
+        // reply, e.g. from an older backend which doesn't send commands
+        let body = response.into_string().unwrap_or_default();
+        if !body.is_empty() {
+            match serde_json::from_str::<UploadReply>(&body) {
+                Ok(reply) => commands::handle(reply.commands),
+                Err(e) => warn!("Can't parse reply from backend: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the backend turned an upload down for good, so sending the
+    /// same reports again won't help.  Without the right key, or when it's
+    /// busy, it may take them later.
+    fn rejected(e: &anyhow::Error) -> bool {
+        match e.downcast_ref::<ureq::Error>() {
+            Some(ureq::Error::Status(code, _)) => (400..500).contains(code) && ![401, 408, 429].contains(code),
+            _ => false,
+        }
+    }
+
+    fn reporter_thread(rx: &mpsc::Receiver<Readings>, started: Instant) -> Result<()> {
+        // ureq checks HTTPS certificates against the bundled web roots
         let agent: Agent = ureq::AgentBuilder::new()
             .timeout_read(Duration::from_secs(5))
             .timeout_write(Duration::from_secs(5))
             .build();
 
-        println!("Reporter thread started up.");
+        let settings = settings::get();
+        if !settings.reporter_token.is_empty() && !settings.reporter_url.starts_with("https://") {
+            warn!("Sending token over plain HTTP, use an https:// reporter_url");
+        }
+        let mut queue = Queue::load(&settings.reporter_queue_file);
 
+        info!("Reporter thread started up.");
+
+        let mut backoff = MIN_BACKOFF;
+        let mut next_attempt = Instant::now();
+        // Smaller than reporter_batch_size after the backend turns a batch
+        // down, until the backlog has been uploaded
+        let mut batch_limit = usize::MAX;
         loop {
-            let (fix, battery_readings) = rx.recv().unwrap();
-            let fix = fix.unwrap_or_else(|| {
-                println!("Stubbing out fix because no GPS signal yet");
-                GpsFix::default()
-            });
-
-            let temperature = get_temperature()
-                .map(|degrees| format!("{}°C", degrees))
-                .unwrap_or_else(|| "unknown".to_owned());
-
-            // Ignore errors here, just try again next time.
-            let datetime = fix.time.format("%Y-%m-%d %H:%M:%S").to_string();
-            let _ = agent
-                .post("http://dwt27.co.uk:1309/isopod")
-                .send_json(ureq::json!({
-                    "lat": fix.latitude,
-                    "long": fix.longitude,
-                    "sats": fix.satellites,
-                    "alt": fix.altitude,
-                    "time": datetime,
-                    "voltage": battery_readings.voltage,
-                    "current": battery_readings.current,
-                    "soc": battery_readings.soc,
-                    "temp": temperature,
-                }));
-
-            println!(
-                "Reporter thread sending fix: {:#?} battery {:#?} temperature {}",
-                fix, battery_readings, temperature
-            );
+            // Wake up for new reports, or to retry the backlog
+            let timeout = if queue.reports.is_empty() {
+                Duration::MAX
+            } else {
+                next_attempt.saturating_duration_since(Instant::now())
+            };
+            match rx.recv_timeout(timeout) {
+                Ok(readings) => {
+                    health::heartbeat(WORKER_NAME);
+                    let report = serde_json::to_value(Self::make_report(readings, started))?;
+                    debug!("Queueing report: {}", report);
+                    let max_queued = settings::get().reporter_max_queued;
+                    if let Err(e) = queue.push(report, max_queued) {
+                        error!("{:#}", e);
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            if Instant::now() < next_attempt {
+                continue;
+            }
+            while !queue.reports.is_empty() {
+                let count = queue
+                    .reports
+                    .len()
+                    .min(settings::get().reporter_batch_size)
+                    .min(batch_limit);
+                match Self::upload(&agent, &queue, count) {
+                    Ok(()) => {
+                        backoff = MIN_BACKOFF;
+                        if let Err(e) = queue.pop(count) {
+                            error!("{:#}", e);
+                        }
+                        if queue.reports.is_empty() {
+                            batch_limit = usize::MAX;
+                        } else {
+                            info!("{} reports still to upload", queue.reports.len());
+                        }
+                    }
+                    Err(e) if Self::rejected(&e) && count > 1 => {
+                        warn!("{:#}, trying batches of {}", e, count / 2);
+                        batch_limit = count / 2;
+                    }
+                    Err(e) if Self::rejected(&e) => {
+                        error!("{:#}, dropping the report: {}", e, queue.reports[0]);
+                        if let Err(e) = queue.pop(1) {
+                            error!("{:#}", e);
+                        }
+                    }
+                    Err(e) => {
+                        warn!(
+                            "{:#}, {} reports queued, retrying in {}s",
+                            e,
+                            queue.reports.len(),
+                            backoff.as_secs()
+                        );
+                        next_attempt = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        break;
+                    }
+                }
+            }
         }
     }
 
     #[allow(dead_code)]
-    pub fn send(&mut self, fix: Option<GpsFix>, battery: BatteryReadings) -> Result<()> {
-        self.tx.send((fix, battery))?;
+    pub fn send(&mut self, readings: Readings) -> Result<()> {
+        self.tx.send(readings)?;
         Ok(())
     }
 }
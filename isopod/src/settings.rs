@@ -0,0 +1,618 @@
+//! Typed settings loaded from settings.toml.  Settings are validated when
+//! loaded so that mistakes are reported with a clear message up-front rather
+//! than panicking the render loop later.  The file is watched for changes and
+//! reloaded on the fly, and settings can also be inspected, edited and
+//! reloaded through the control server.  Since anyone on the network can use
+//! the control server, it isn't shown secrets and can't change credentials,
+//! where they're sent or which files are read and written; those can only
+//! be changed in settings.toml.
+
+use anyhow::{anyhow, Context, Result};
+use config::Config;
+use lazy_static::lazy_static;
+use log::{info, warn, LevelFilter};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use std::{fs, thread};
+
+/// The settings file, relative to the working directory
+const SETTINGS_FILE: &str = "settings.toml";
+
+/// How often to check whether the settings file has changed
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The highest universe number sACN allows
+const MAX_DMX_UNIVERSE: usize = 63999;
+
+/// Settings which are left out when the control server shows the settings
+const SECRETS: [&str; 2] = ["reporter_token", "mqtt_password"];
+
+/// Settings which the control server can't change: credentials, where
+/// they're sent, and the files the firmware reads and writes
+const PROTECTED: [&str; 9] = [
+    "reporter_url",
+    "reporter_token",
+    "mqtt_host",
+    "mqtt_username",
+    "mqtt_password",
+    "reporter_queue_file",
+    "remote_commands_file",
+    "state_file",
+    "thermal_path",
+];
+
+/// Settings which are only read at start-up, so changing them on the fly
+/// does nothing until the next restart
+const STARTUP_ONLY: [&str; 14] = [
+    "ws_server",
+    "do_startup_tests",
+    "reporter_queue_file",
+    "remote_commands_file",
+    "external_input",
+    "opc_port",
+    "ddp_port",
+    "dmx_input",
+    "osc",
+    "osc_port",
+    "mqtt",
+    "mqtt_port",
+    "mqtt_client_id",
+    "mqtt_topic",
+];
+
+/// What DMX input controls, see dmx_input.rs
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmxMode {
+    /// DMX channels are RGB values for the LEDs
+    Pixels,
+    /// A few DMX channels select the pattern, brightness and speed
+    Control,
+}
+
+/// Throttling to apply once the Pi reaches a temperature, see thermal.rs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThermalLevel {
+    /// Temperature in degrees C at which this level starts
+    pub temperature: f32,
+    /// Frame rate cap
+    #[serde(default)]
+    pub fps: Option<u64>,
+    /// Stop encoding frames for JSON websocket clients
+    #[serde(default)]
+    pub disable_ws_json: bool,
+    /// LED brightness cap as a percentage
+    #[serde(default)]
+    pub max_brightness: Option<u8>,
+}
+
+/// All the settings which can be provided in settings.toml.  Any missing from
+/// the file take the default values given in the Default impl.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Frames per second to run the patterns at
+    pub fps: u64,
+    /// Should the websocket server be enabled
+    pub ws_server: bool,
+    /// Maximum number of websocket clients connected at once
+    pub ws_max_clients: usize,
+    /// Run start-up tests when starting the app
+    pub do_startup_tests: bool,
+    /// Level to log at: off, error, warn, info, debug or trace
+    pub log_level: LevelFilter,
+    /// Log levels for particular modules, overriding log_level
+    pub log_modules: BTreeMap<String, LevelFilter>,
+    /// How many recent log lines to keep for the control server
+    pub log_buffer_lines: usize,
+    /// How often to report to the backend server, in seconds, or 0 to disable
+    pub reporter_interval: u64,
+    /// Identifies this isopod to the backend, for when several report to it
+    pub device_id: String,
+    /// Backend URL to post reports to
+    pub reporter_url: String,
+    /// This isopod's API key for the backend, sent as a bearer token if not
+    /// empty
+    pub reporter_token: String,
+    /// File in which to queue reports which haven't been uploaded yet
+    pub reporter_queue_file: String,
+    /// Maximum number of reports to queue, after which the oldest are dropped
+    pub reporter_max_queued: usize,
+    /// Maximum number of reports to upload in one request
+    pub reporter_batch_size: usize,
+    /// Carry out commands queued on the backend
+    pub remote_commands: bool,
+    /// Where to remember which remote commands have been carried out
+    pub remote_commands_file: String,
+    /// How often to report in lost mode, in seconds
+    pub lost_mode_report_interval: u64,
+    /// File in which to save the state selected from the control panel
+    pub state_file: String,
+    /// How long to play each playlist pattern for, in seconds
+    pub playlist_interval: u64,
+    /// How long each client must wait between audience triggers, in seconds
+    pub trigger_cooldown: u64,
+    /// Accept pixel data from external software over OPC and DDP
+    pub external_input: bool,
+    /// TCP port to listen for Open Pixel Control on
+    pub opc_port: u16,
+    /// UDP port to listen for DDP on
+    pub ddp_port: u16,
+    /// Go back to the normal pattern after this long without external pixel
+    /// data, in seconds
+    pub external_timeout: u64,
+    /// Accept DMX over sACN and Art-Net
+    pub dmx_input: bool,
+    pub dmx_mode: DmxMode,
+    /// First DMX universe to listen to
+    pub dmx_universe: u16,
+    /// First DMX channel to use in each universe, 1-based
+    pub dmx_channel: usize,
+    /// In pixels mode, give each spine position a universe of its own
+    pub dmx_universe_per_spine: bool,
+    /// Listen for OSC control messages
+    pub osc: bool,
+    /// UDP port to listen for OSC on
+    pub osc_port: u16,
+    /// Connect to an MQTT broker to publish our state and take commands
+    pub mqtt: bool,
+    pub mqtt_host: String,
+    pub mqtt_port: u16,
+    pub mqtt_client_id: String,
+    /// Leave empty to connect without logging in
+    pub mqtt_username: String,
+    pub mqtt_password: String,
+    /// Prefix for all the topics we publish and subscribe to
+    pub mqtt_topic: String,
+    /// How often to publish our state, in seconds
+    pub mqtt_interval: u64,
+    /// File to read the Pi's temperature from, in thousandths of a degree C
+    pub thermal_path: String,
+    /// How often to check the temperature, in seconds
+    pub thermal_interval: u64,
+    /// How far the temperature must fall below a level's threshold before
+    /// the level is lifted, in degrees C
+    pub thermal_hysteresis: f32,
+    /// Throttling levels in order of increasing temperature
+    pub thermal_levels: Vec<ThermalLevel>,
+    /// Maximum LED strip brightness, 0-255
+    pub led_brightness: u8,
+    /// Mapping from PCB LED connectors to spine positions, both 1-based
+    pub led_spine_mapping: [usize; 12],
+    pub rainbow_swirl_radial_smear: f64,
+    pub rainbow_swirl_speed: f64,
+    /// Period of the rave pattern's beat, in frames
+    pub donk_rate: u32,
+    /// How many frames of each beat the rave pattern lights up for
+    pub donk_len: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            fps: 60,
+            ws_server: false,
+            ws_max_clients: 4,
+            do_startup_tests: false,
+            log_level: LevelFilter::Info,
+            log_modules: BTreeMap::new(),
+            log_buffer_lines: 1000,
+            reporter_interval: 0,
+            device_id: "isopod".to_owned(),
+            reporter_url: "http://dwt27.co.uk:1309/isopod".to_owned(),
+            reporter_token: String::new(),
+            reporter_queue_file: "reporter_queue.jsonl".to_owned(),
+            reporter_max_queued: 10000,
+            reporter_batch_size: 50,
+            remote_commands: false,
+            remote_commands_file: "remote_commands_done.json".to_owned(),
+            lost_mode_report_interval: 10,
+            state_file: "isopod_state.json".to_owned(),
+            playlist_interval: 300,
+            trigger_cooldown: 10,
+            external_input: false,
+            opc_port: 7890,
+            ddp_port: 4048,
+            external_timeout: 5,
+            dmx_input: false,
+            dmx_mode: DmxMode::Pixels,
+            dmx_universe: 1,
+            dmx_channel: 1,
+            dmx_universe_per_spine: true,
+            osc: false,
+            osc_port: 8000,
+            mqtt: false,
+            mqtt_host: "localhost".to_owned(),
+            mqtt_port: 1883,
+            mqtt_client_id: "isopod".to_owned(),
+            mqtt_username: String::new(),
+            mqtt_password: String::new(),
+            mqtt_topic: "isopod".to_owned(),
+            mqtt_interval: 10,
+            thermal_path: "/sys/class/thermal/thermal_zone0/temp".to_owned(),
+            thermal_interval: 5,
+            thermal_hysteresis: 3.0,
+            thermal_levels: vec![
+                ThermalLevel {
+                    temperature: 70.0,
+                    fps: Some(40),
+                    disable_ws_json: true,
+                    max_brightness: None,
+                },
+                ThermalLevel {
+                    temperature: 75.0,
+                    fps: Some(30),
+                    disable_ws_json: true,
+                    max_brightness: Some(70),
+                },
+                ThermalLevel {
+                    temperature: 80.0,
+                    fps: Some(20),
+                    disable_ws_json: true,
+                    max_brightness: Some(40),
+                },
+            ],
+            led_brightness: 156,
+            led_spine_mapping: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+            rainbow_swirl_radial_smear: 10.0,
+            rainbow_swirl_speed: -6.0,
+            donk_rate: 30,
+            donk_len: 4,
+        }
+    }
+}
+
+impl Settings {
+    /// Check that all values are in range.  Returns a list of problems, which
+    /// is empty if the settings are valid.
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !(1..=120).contains(&self.fps) {
+            problems.push(format!("fps must be 1-120, got {}", self.fps));
+        }
+        if self.ws_max_clients == 0 {
+            problems.push("ws_max_clients must be at least 1".to_owned());
+        }
+        if let Some(module) = self
+            .log_modules
+            .keys()
+            .find(|module| module.is_empty() || module.split("::").any(str::is_empty))
+        {
+            problems.push(format!(
+                "log_modules keys must be module paths like \"isopod::gps\", got {:?}",
+                module
+            ));
+        }
+        if self.device_id.is_empty() {
+            problems.push("device_id must not be empty".to_owned());
+        }
+        if !self.reporter_url.starts_with("http://") && !self.reporter_url.starts_with("https://") {
+            problems.push(format!(
+                "reporter_url must be an http:// or https:// URL, got {:?}",
+                self.reporter_url
+            ));
+        }
+        // Commands change what the isopod does, so make sure they can only
+        // come from our backend
+        if self.remote_commands && (!self.reporter_url.starts_with("https://") || self.reporter_token.is_empty()) {
+            problems.push("remote_commands needs an https:// reporter_url and a reporter_token".to_owned());
+        }
+        if self.reporter_max_queued == 0 {
+            problems.push("reporter_max_queued must be at least 1".to_owned());
+        }
+        if self.reporter_batch_size == 0 {
+            problems.push("reporter_batch_size must be at least 1".to_owned());
+        }
+        if self.lost_mode_report_interval == 0 {
+            problems.push("lost_mode_report_interval must be at least 1 second".to_owned());
+        }
+        if self.playlist_interval == 0 {
+            problems.push("playlist_interval must be at least 1 second".to_owned());
+        }
+        if self.external_timeout == 0 {
+            problems.push("external_timeout must be at least 1 second".to_owned());
+        }
+        // Make sure everything we need fits in a universe after dmx_channel
+        let dmx_channels = match self.dmx_mode {
+            DmxMode::Pixels if self.dmx_universe_per_spine => crate::LEDS_PER_SPINE * 3,
+            _ => 3,
+        };
+        if !(1..=512 - dmx_channels + 1).contains(&self.dmx_channel) {
+            problems.push(format!(
+                "dmx_channel must be 1-{} to fit {} channels in a universe, got {}",
+                512 - dmx_channels + 1,
+                dmx_channels,
+                self.dmx_channel
+            ));
+        } else {
+            let universes = crate::dmx_input::universes(self);
+            if self.dmx_universe as usize + universes - 1 > MAX_DMX_UNIVERSE {
+                problems.push(format!(
+                    "dmx_universe must be at most {} to fit {} universes, got {}",
+                    MAX_DMX_UNIVERSE + 1 - universes,
+                    universes,
+                    self.dmx_universe
+                ));
+            }
+        }
+        if self.mqtt_interval == 0 {
+            problems.push("mqtt_interval must be at least 1 second".to_owned());
+        }
+        if self.mqtt_topic.is_empty() || self.mqtt_topic.contains(['+', '#']) {
+            problems.push(format!(
+                "mqtt_topic must be a topic name without wildcards, got {:?}",
+                self.mqtt_topic
+            ));
+        }
+        if self.thermal_path.is_empty() {
+            problems.push("thermal_path must not be empty".to_owned());
+        }
+        if self.thermal_interval == 0 {
+            problems.push("thermal_interval must be at least 1 second".to_owned());
+        }
+        if !(self.thermal_hysteresis >= 0.0 && self.thermal_hysteresis.is_finite()) {
+            problems.push(format!(
+                "thermal_hysteresis must be 0 or more, got {}",
+                self.thermal_hysteresis
+            ));
+        }
+        for (i, level) in self.thermal_levels.iter().enumerate() {
+            if !level.temperature.is_finite() {
+                problems.push(format!("thermal_levels[{}] temperature must be a number", i));
+            } else if i > 0 && level.temperature <= self.thermal_levels[i - 1].temperature {
+                problems.push(format!(
+                    "thermal_levels must be in order of increasing temperature, got {} after {}",
+                    level.temperature,
+                    self.thermal_levels[i - 1].temperature
+                ));
+            }
+            if let Some(fps) = level.fps {
+                if !(1..=120).contains(&fps) {
+                    problems.push(format!("thermal_levels[{}] fps must be 1-120, got {}", i, fps));
+                }
+            }
+            if let Some(max_brightness) = level.max_brightness {
+                if max_brightness > 100 {
+                    problems.push(format!(
+                        "thermal_levels[{}] max_brightness must be 0-100, got {}",
+                        i, max_brightness
+                    ));
+                }
+            }
+        }
+        if !self.rainbow_swirl_radial_smear.is_finite() {
+            problems.push("rainbow_swirl_radial_smear must be a number".to_owned());
+        }
+        if !self.rainbow_swirl_speed.is_finite() {
+            problems.push("rainbow_swirl_speed must be a number".to_owned());
+        }
+        if let Err(e) = check_spine_mapping(&self.led_spine_mapping) {
+            problems.push(e);
+        }
+        if self.donk_rate == 0 {
+            problems.push("donk_rate must be at least 1".to_owned());
+        }
+        if self.donk_len > self.donk_rate {
+            problems.push(format!(
+                "donk_len ({}) must not be longer than donk_rate ({})",
+                self.donk_len, self.donk_rate
+            ));
+        }
+
+        problems
+    }
+
+    /// Turn a set of raw key-value pairs into validated settings.  Returns
+    /// the settings along with warnings about any keys we didn't recognise,
+    /// or an error describing everything that's wrong with them.
+    fn from_values(values: serde_json::Value) -> Result<(Self, Vec<String>)> {
+        let known = serde_json::to_value(Settings::default())?;
+        let warnings = match (values.as_object(), known.as_object()) {
+            (Some(values), Some(known)) => values
+                .keys()
+                .filter(|key| !known.contains_key(*key))
+                .map(|key| format!("unknown setting {:?} ignored", key))
+                .collect(),
+            _ => return Err(anyhow!("settings must be a table of key-value pairs")),
+        };
+
+        let settings: Settings = serde_json::from_value(values)?;
+        let problems = settings.validate();
+        if !problems.is_empty() {
+            return Err(anyhow!("invalid settings: {}", problems.join("; ")));
+        }
+
+        Ok((settings, warnings))
+    }
+}
+
+/// Check that an LED spine mapping uses each spine position 1-12 exactly
+/// once.  Returns a description of the problem if not.
+pub fn check_spine_mapping(map: &[usize; 12]) -> Result<(), String> {
+    let out_of_range: Vec<usize> = map
+        .iter()
+        .copied()
+        .filter(|x| !(1..=12).contains(x))
+        .collect();
+    let missing: Vec<usize> = (1..=12).filter(|x| !map.contains(x)).collect();
+    let duplicated: Vec<usize> = (1..=12)
+        .filter(|x| map.iter().filter(|y| *y == x).count() > 1)
+        .collect();
+
+    if out_of_range.is_empty() && missing.is_empty() && duplicated.is_empty() {
+        return Ok(());
+    }
+
+    let mut details = Vec::new();
+    if !out_of_range.is_empty() {
+        details.push(format!("positions must be 1-12 but found {:?}", out_of_range));
+    }
+    if !duplicated.is_empty() {
+        details.push(format!("{:?} used more than once", duplicated));
+    }
+    if !missing.is_empty() {
+        details.push(format!("{:?} missing", missing));
+    }
+    Err(format!(
+        "led_spine_mapping {:?} must use each spine position 1-12 exactly once: {}",
+        map,
+        details.join(", ")
+    ))
+}
+
+lazy_static! {
+    static ref SETTINGS: RwLock<Arc<Settings>> = RwLock::new(Arc::new(Settings::default()));
+}
+
+/// Get the current settings.  This is cheap so patterns can call it every
+/// frame, and always reflects the latest reload.
+pub fn get() -> Arc<Settings> {
+    SETTINGS.read().unwrap().clone()
+}
+
+/// The current settings as shown by the control server, without secrets
+pub fn redacted() -> serde_json::Value {
+    let mut values = serde_json::to_value(&*get()).unwrap_or_default();
+    if let Some(values) = values.as_object_mut() {
+        for key in SECRETS {
+            values.remove(key);
+        }
+    }
+    values
+}
+
+/// Read and validate the settings file, without applying it
+fn read_file() -> Result<(Settings, Vec<String>)> {
+    let values: serde_json::Value = Config::builder()
+        .add_source(config::File::with_name(SETTINGS_FILE))
+        .build()
+        .and_then(|config| config.try_deserialize())
+        .with_context(|| format!("failed to read {}", SETTINGS_FILE))?;
+    Settings::from_values(values).with_context(|| format!("in {}", SETTINGS_FILE))
+}
+
+fn apply(settings: Settings, warnings: &[String]) {
+    crate::logging::configure(&settings);
+    *SETTINGS.write().unwrap() = Arc::new(settings);
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+}
+
+/// Load the settings file at startup.  Any error here is fatal, since we
+/// don't want to run with settings other than the ones asked for.
+pub fn load() -> Result<()> {
+    let (settings, warnings) = read_file()?;
+    apply(settings, &warnings);
+    Ok(())
+}
+
+/// Re-read the settings file.  If it's invalid then the error is returned and
+/// the current settings are left alone.  Returns any warnings on success.
+pub fn reload() -> Result<Vec<String>> {
+    let (settings, warnings) = read_file()?;
+    info!("Reloaded {}", SETTINGS_FILE);
+    apply(settings, &warnings);
+    Ok(warnings)
+}
+
+/// Change some settings on the fly.  `changes` is an object of key-value
+/// pairs which are applied on top of the current settings.  Changes are
+/// validated like the settings file, and last until the next reload; edit
+/// settings.toml to make them permanent.  Credentials, where they're sent
+/// and file paths can't be changed this way, though they can be given with
+/// their current values.
+pub fn update(changes: serde_json::Value) -> Result<Vec<String>> {
+    let mut values = serde_json::to_value(&*get())?;
+    let mut changed = Vec::new();
+    match (values.as_object_mut(), changes) {
+        (Some(values), serde_json::Value::Object(changes)) => {
+            for (key, value) in changes {
+                if values.get(&key) == Some(&value) {
+                    continue;
+                }
+                if PROTECTED.contains(&key.as_str()) {
+                    return Err(anyhow!("{} can only be changed in {}", key, SETTINGS_FILE));
+                }
+                values.insert(key.clone(), value);
+                changed.push(key);
+            }
+        }
+        _ => return Err(anyhow!("settings changes must be an object")),
+    }
+
+    let (settings, mut warnings) = Settings::from_values(values)?;
+    warnings.extend(
+        changed
+            .iter()
+            .filter(|key| STARTUP_ONLY.contains(&key.as_str()))
+            .map(|key| format!("{} only takes effect after a restart", key)),
+    );
+    info!("Settings changed: {}", changed.join(", "));
+    apply(settings, &warnings);
+    Ok(warnings)
+}
+
+/// Save a new LED spine mapping to the settings file and apply it.  Only the
+/// `led_spine_mapping` line is rewritten so the rest of the file, including
+/// comments, is left alone.
+pub fn save_spine_mapping(map: [usize; 12]) -> Result<()> {
+    check_spine_mapping(&map).map_err(|e| anyhow!(e))?;
+
+    let new_line = format!(
+        "led_spine_mapping = [{}]",
+        map.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")
+    );
+    let old = fs::read_to_string(SETTINGS_FILE)
+        .with_context(|| format!("failed to read {}", SETTINGS_FILE))?;
+    let mut replaced = false;
+    let mut lines: Vec<String> = old
+        .lines()
+        .map(|line| {
+            if !replaced && line.trim_start().starts_with("led_spine_mapping") {
+                replaced = true;
+                new_line.clone()
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect();
+    if !replaced {
+        lines.push(new_line);
+    }
+
+    // Write then rename so we can't be left with half a settings file
+    let tmp_path = format!("{}.tmp", SETTINGS_FILE);
+    fs::write(&tmp_path, lines.join("\n") + "\n")?;
+    fs::rename(&tmp_path, SETTINGS_FILE)?;
+
+    reload()?;
+    Ok(())
+}
+
+fn modified_time() -> Option<SystemTime> {
+    fs::metadata(SETTINGS_FILE).and_then(|x| x.modified()).ok()
+}
+
+/// Start a thread which reloads the settings whenever the file changes
+pub fn start_watcher() {
+    thread::Builder::new()
+        .name("ISOPOD settings".into())
+        .spawn(|| {
+            let mut last_modified = modified_time();
+            loop {
+                thread::sleep(WATCH_INTERVAL);
+                let modified = modified_time();
+                if modified != last_modified {
+                    last_modified = modified;
+                    if let Err(e) = reload() {
+                        warn!("Keeping previous settings: {:#}", e);
+                    }
+                }
+            }
+        })
+        .unwrap();
+}
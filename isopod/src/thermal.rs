@@ -0,0 +1,268 @@
+//! Keeps the Pi from overheating inside the sealed core.  The temperature is
+//! checked regularly, and as it passes each of the `thermal_levels` in the
+//! settings we throttle back: lowering the frame rate, stopping the
+//! websocket JSONifier and capping the LED brightness.  Each level adds to
+//! the throttling of the levels below it.  A level is only lifted once the
+//! temperature has fallen `thermal_hysteresis` degrees below it, so we don't
+//! flap around a threshold.
+//!
+//! Changes of level are logged, and the current throttling is included in
+//! reports to the backend and the metrics.
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::health;
+use crate::settings::{self, Settings};
+use crate::temperature::get_temperature;
+
+/// Name of the thermal manager thread for health monitoring
+const WORKER_NAME: &str = "thermal";
+
+/// Throttling currently in force
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Throttling {
+    /// How many of the thermal_levels the temperature has reached, 0 if
+    /// we're not throttling
+    pub level: usize,
+    /// Frame rate cap, if any
+    pub fps: Option<u64>,
+    /// Whether frames are no longer encoded for JSON websocket clients
+    pub ws_json_disabled: bool,
+    /// LED brightness cap as a percentage, if any
+    pub max_brightness: Option<u8>,
+}
+
+impl Throttling {
+    /// The combined throttling of the first `level` thermal_levels
+    fn at_level(settings: &Settings, level: usize) -> Self {
+        let levels = &settings.thermal_levels[..level];
+        Self {
+            level,
+            fps: levels.iter().filter_map(|x| x.fps).min(),
+            ws_json_disabled: levels.iter().any(|x| x.disable_ws_json),
+            max_brightness: levels.iter().filter_map(|x| x.max_brightness).min(),
+        }
+    }
+}
+
+static THROTTLING: Mutex<Throttling> = Mutex::new(Throttling {
+    level: 0,
+    fps: None,
+    ws_json_disabled: false,
+    max_brightness: None,
+});
+
+/// The throttling currently in force
+pub fn throttling() -> Throttling {
+    *THROTTLING.lock().unwrap()
+}
+
+/// Frames per second to run the patterns at, after any throttling
+pub fn fps() -> u64 {
+    let fps = settings::get().fps;
+    throttling().fps.map_or(fps, |x| x.min(fps))
+}
+
+/// Should frames be encoded for JSON websocket clients
+pub fn ws_json_allowed() -> bool {
+    !throttling().ws_json_disabled
+}
+
+/// Scale to apply to the LED brightness, or None if it isn't capped
+#[cfg(feature = "hardware")]
+pub fn brightness_scale() -> Option<f32> {
+    throttling().max_brightness.map(|x| x as f32 / 100.0)
+}
+
+/// Work out which level we should be at now, given the level we were at
+fn new_level(settings: &Settings, mut level: usize, temperature: f32) -> usize {
+    let levels = &settings.thermal_levels;
+    // The levels may have changed since last time
+    level = level.min(levels.len());
+    while level < levels.len() && temperature >= levels[level].temperature {
+        level += 1;
+    }
+    while level > 0 && temperature < levels[level - 1].temperature - settings.thermal_hysteresis {
+        level -= 1;
+    }
+    level
+}
+
+/// Check the temperature and update the throttling to suit
+fn check() {
+    let settings = settings::get();
+    let old = throttling();
+    let temperature = get_temperature();
+    // If we can't tell the temperature, e.g. when not running on a Pi, stay
+    // at the same level
+    let level = match temperature {
+        Some(temperature) => new_level(&settings, old.level, temperature),
+        None => old.level.min(settings.thermal_levels.len()),
+    };
+
+    // Recalculate even if the level hasn't changed, in case the levels have
+    let throttling = Throttling::at_level(&settings, level);
+    if throttling == old {
+        return;
+    }
+    *THROTTLING.lock().unwrap() = throttling;
+
+    let temperature = temperature.map_or("unknown".to_owned(), |x| format!("{:.1}C", x));
+    let levels = settings.thermal_levels.len();
+    if level > old.level {
+        warn!("Temperature {}, throttling to level {} of {}: {:?}", temperature, level, levels, throttling);
+    } else {
+        info!("Temperature {}, throttling at level {} of {}: {:?}", temperature, level, levels, throttling);
+    }
+}
+
+fn thermal_worker() -> Result<()> {
+    loop {
+        check();
+        thread::sleep(Duration::from_secs(settings::get().thermal_interval));
+    }
+}
+
+/// Start the thermal manager
+pub fn start() {
+    health::spawn_supervised(WORKER_NAME, None, thermal_worker);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ThermalLevel;
+
+    fn level(temperature: f32, fps: Option<u64>, disable_ws_json: bool, max_brightness: Option<u8>) -> ThermalLevel {
+        ThermalLevel {
+            temperature,
+            fps,
+            disable_ws_json,
+            max_brightness,
+        }
+    }
+
+    /// Levels at 70, 75 and 80C with 3C of hysteresis
+    fn settings() -> Settings {
+        Settings {
+            thermal_levels: vec![
+                level(70.0, Some(40), true, None),
+                level(75.0, Some(30), false, Some(70)),
+                level(80.0, Some(20), false, Some(40)),
+            ],
+            thermal_hysteresis: 3.0,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn step_up() {
+        let settings = settings();
+        assert_eq!(new_level(&settings, 0, 20.0), 0);
+        assert_eq!(new_level(&settings, 0, 69.9), 0);
+        assert_eq!(new_level(&settings, 0, 70.0), 1);
+        assert_eq!(new_level(&settings, 1, 75.0), 2);
+        // Several levels at once if it heats up quickly
+        assert_eq!(new_level(&settings, 0, 80.0), 3);
+        assert_eq!(new_level(&settings, 3, 95.0), 3);
+    }
+
+    #[test]
+    fn step_down() {
+        let settings = settings();
+        assert_eq!(new_level(&settings, 3, 79.0), 3);
+        assert_eq!(new_level(&settings, 3, 76.9), 2);
+        assert_eq!(new_level(&settings, 2, 71.9), 1);
+        // Several levels at once if it cools quickly
+        assert_eq!(new_level(&settings, 3, 40.0), 0);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let settings = settings();
+        // Anywhere in the hysteresis band keeps the level we're at
+        for temperature in [67.0, 68.5, 69.9] {
+            assert_eq!(new_level(&settings, 0, temperature), 0, "{}", temperature);
+            assert_eq!(new_level(&settings, 1, temperature), 1, "{}", temperature);
+        }
+        assert_eq!(new_level(&settings, 1, 66.9), 0);
+        assert_eq!(new_level(&settings, 2, 72.0), 2);
+        assert_eq!(new_level(&settings, 2, 71.9), 1);
+
+        // Bands can overlap the level below
+        let settings = Settings {
+            thermal_hysteresis: 10.0,
+            ..settings
+        };
+        assert_eq!(new_level(&settings, 3, 70.0), 3);
+        assert_eq!(new_level(&settings, 3, 66.0), 2);
+        assert_eq!(new_level(&settings, 3, 64.9), 1);
+        assert_eq!(new_level(&settings, 3, 59.9), 0);
+    }
+
+    #[test]
+    fn levels_removed() {
+        // If the settings are reloaded with fewer levels, we can't be above
+        // the last one
+        let mut settings = settings();
+        settings.thermal_levels.truncate(1);
+        assert_eq!(new_level(&settings, 3, 90.0), 1);
+        settings.thermal_levels.clear();
+        assert_eq!(new_level(&settings, 3, 90.0), 0);
+    }
+
+    #[test]
+    fn merging() {
+        let settings = settings();
+        let none = Throttling {
+            level: 0,
+            fps: None,
+            ws_json_disabled: false,
+            max_brightness: None,
+        };
+        assert_eq!(Throttling::at_level(&settings, 0), none);
+        assert_eq!(
+            Throttling::at_level(&settings, 1),
+            Throttling {
+                level: 1,
+                fps: Some(40),
+                ws_json_disabled: true,
+                ..none
+            }
+        );
+        // Levels only ever add to the throttling below them
+        assert_eq!(
+            Throttling::at_level(&settings, 3),
+            Throttling {
+                level: 3,
+                fps: Some(20),
+                ws_json_disabled: true,
+                max_brightness: Some(40),
+            }
+        );
+
+        // Even when a higher level is less strict about something
+        let settings = Settings {
+            thermal_levels: vec![
+                level(70.0, Some(20), false, Some(50)),
+                level(75.0, Some(30), false, None),
+                level(80.0, None, false, Some(60)),
+            ],
+            ..Settings::default()
+        };
+        assert_eq!(
+            Throttling::at_level(&settings, 3),
+            Throttling {
+                level: 3,
+                fps: Some(20),
+                ws_json_disabled: false,
+                max_brightness: Some(50),
+            }
+        );
+    }
+}
@@ -0,0 +1,21 @@
+//! Provides a basic function to read the Wi-Fi signal strength
+
+#![allow(unused)]
+
+use std::fs;
+
+/// Wireless interface statistics, as a table with two header lines and then
+/// one line per interface
+const WIRELESS_PATH: &str = "/proc/net/wireless";
+
+/// Get the signal level of the first wireless interface in dBm.  Returns None
+/// if there's no wireless interface, or we can't read its statistics.
+pub fn get_signal() -> Option<i32> {
+    // Lines look like:
+    // " wlan0: 0000   57.  -53.  -256        0      0      0      0     17        0"
+    // where the fourth column is the signal level.
+    let contents = fs::read_to_string(WIRELESS_PATH).ok()?;
+    let line = contents.lines().nth(2)?;
+    let level = line.split_whitespace().nth(3)?;
+    level.trim_end_matches('.').parse::<f32>().ok().map(|x| x as i32)
+}
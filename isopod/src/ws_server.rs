@@ -1,21 +1,214 @@
-use crate::common_structs::LedUpdate;
+//! Websocket server which streams LED frames to the web visualiser.
+//!
+//! Clients choose a frame format when they connect with the `format` query
+//! parameter, e.g. `ws://isopod:3030/ws?format=delta`:
+//! * `json` (the default): a text message per frame, `{"spines": [[[r, g, b],
+//!   ...], ...]}`
+//! * `binary`: a binary message per frame.  This is much cheaper to produce
+//!   than JSON, which matters on the Pi.  Every frame is a raw keyframe.
+//! * `delta`: binary frames as above, but after the first keyframe only the
+//!   LEDs which have changed are sent, with a run-length encoded keyframe
+//!   every so often.  This cuts the bandwidth needed over a weak WiFi link.
+//!
+//! Clients can also ask for fewer frames with the `fps` query parameter, e.g.
+//! `?format=delta&fps=15`.  Frames are then skipped so the client gets at
+//! most that many per second.
+//!
+//! At most `ws_max_clients` clients can be connected at once; any more are
+//! turned away with a 503.  Clients which can't keep up skip straight to the
+//! latest frame rather than falling further behind.
+//!
+//! With `telemetry=true`, clients are also sent the sensor readings, active
+//! pattern and frame timing as a text message `{"telemetry": {...}}` up to 10
+//! times a second, just before the frame they go with.
+//!
+//! Binary frames are a 16-byte header followed by the payload, with all
+//! integers little-endian.  The payload for each frame type is described in
+//! frame_encoding.rs.
+//!
+//! | Offset | Size | Field                                           |
+//! |--------|------|-------------------------------------------------|
+//! | 0      | 1    | Format version, currently 1                     |
+//! | 1      | 1    | Frame type, 0 = raw, 1 = run-length, 2 = delta  |
+//! | 2      | 1    | Number of spines (12)                           |
+//! | 3      | 1    | LEDs per spine (59)                             |
+//! | 4      | 4    | Frame number, wrapping                          |
+//! | 8      | 8    | Timestamp, milliseconds since the Unix epoch    |
+//! | 16     | ...  | Payload                                         |
+
+use crate::common_structs::{BatteryReadings, GpsFix, ImuReadings, LedUpdate};
+use crate::frame_encoding::{self, DeltaEncoder, HEADER_LEN, PIXEL_BYTES};
+use crate::health;
+use crate::settings;
+use crate::thermal;
 use anyhow::Result;
 use futures_util::SinkExt;
+use lazy_static::lazy_static;
+use log::{info, warn};
 use serde::Serialize;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
+use warp::http::StatusCode;
 use warp::{ws::WebSocket, Filter};
 
-/// The packet format we send to the websocket client
+/// The packet format we send to JSON websocket clients
 #[derive(Serialize, Clone)]
 struct SimPacket {
     spines: Vec<Vec<[u8; 3]>>,
 }
 
+/// How often to send telemetry to clients which want it
+const TELEMETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// State of the isopod sent alongside the frames, for debugging
+#[derive(Serialize, Debug, Clone)]
+pub struct Telemetry {
+    pub imu: ImuReadings,
+    pub gps: Option<GpsFix>,
+    pub battery: Option<BatteryReadings>,
+    /// Temperature of the Pi in degrees C
+    pub temperature: Option<f32>,
+    /// Name of the pattern currently playing
+    pub pattern: &'static str,
+    /// How long the last frame took to render, in milliseconds
+    pub frame_time: f32,
+    /// Frame rate actually being achieved
+    pub fps: f32,
+}
+
+/// The packet format we send telemetry in
+#[derive(Serialize)]
+struct TelemetryPacket<'a> {
+    telemetry: &'a Telemetry,
+}
+
+/// What the main thread sends to the encoder
+struct Update {
+    leds: LedUpdate,
+    telemetry: Option<Telemetry>,
+}
+
+/// Frame formats a client can ask for
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Binary,
+    Delta,
+}
+
+/// A frame encoded in each of the formats clients might want.  JSON is only
+/// produced when there are JSON clients connected, since it's expensive.
+/// Delta frames depend on what each client was last sent, so they're encoded
+/// by the client handlers from the raw frame.
+struct EncodedFrame {
+    frame_number: u32,
+    timestamp: u64,
+    /// Raw binary frame, header included
+    binary: Vec<u8>,
+    json: Option<String>,
+    /// Telemetry, if there was some to go with this frame
+    telemetry: Option<String>,
+}
+
+impl EncodedFrame {
+    /// The RGB bytes of the raw frame, without the header
+    fn pixels(&self) -> &[u8] {
+        &self.binary[HEADER_LEN..]
+    }
+}
+
+/// How many connected clients want JSON frames
+static JSON_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many connected clients want telemetry
+static TELEMETRY_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many clients are connected in total
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// ID to give the next client, so their stats can be told apart
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Statistics about a connected client
+#[derive(Serialize, Debug, Clone)]
+pub struct ClientStats {
+    pub address: Option<SocketAddr>,
+    pub format: Format,
+    /// Frame rate the client asked for, if it asked for a reduced rate
+    pub max_fps: Option<f64>,
+    pub telemetry: bool,
+    /// Seconds since the client connected
+    pub connected_for: f32,
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    /// Frames skipped to give the client the frame rate it asked for
+    pub frames_skipped: u64,
+    /// Frames dropped because the client wasn't keeping up
+    pub frames_lagged: u64,
+    #[serde(skip)]
+    connected_at: Instant,
+}
+
+lazy_static! {
+    static ref CLIENT_STATS: Mutex<BTreeMap<u64, ClientStats>> = Mutex::new(BTreeMap::new());
+}
+
+/// Get the statistics for all connected clients
+pub fn client_stats() -> Vec<ClientStats> {
+    CLIENT_STATS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|stats| ClientStats {
+            connected_for: stats.connected_at.elapsed().as_secs_f32(),
+            ..stats.clone()
+        })
+        .collect()
+}
+
+/// Number of clients connected
+pub fn client_count() -> usize {
+    CLIENTS.load(Ordering::Relaxed)
+}
+
+/// Add a new client's stats, if there's room for another client
+fn add_client(stats: ClientStats) -> Option<u64> {
+    let mut clients = CLIENT_STATS.lock().unwrap();
+    if clients.len() >= settings::get().ws_max_clients {
+        return None;
+    }
+    let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+    clients.insert(id, stats);
+    CLIENTS.store(clients.len(), Ordering::Relaxed);
+    Some(id)
+}
+
+fn remove_client(id: u64) {
+    let mut clients = CLIENT_STATS.lock().unwrap();
+    clients.remove(&id);
+    CLIENTS.store(clients.len(), Ordering::Relaxed);
+}
+
+fn update_client_stats(id: u64, f: impl FnOnce(&mut ClientStats)) {
+    if let Some(stats) = CLIENT_STATS.lock().unwrap().get_mut(&id) {
+        f(stats);
+    }
+}
+
 pub struct WsServer {
-    // This channel goes from the main thread to the JSONifier
-    tx: mpsc::Sender<LedUpdate>,
+    // This channel goes from the main thread to the encoder
+    tx: mpsc::Sender<Update>,
+
+    /// When telemetry was last sent
+    last_telemetry: Cell<Instant>,
 }
 
 impl WsServer {
@@ -23,81 +216,268 @@ impl WsServer {
         let (tx, _rx) = broadcast::channel(32);
 
         // Wrap up a tokio Sender so it can live forever
-        let wrapped_tx: Arc<Mutex<Sender<String>>> = Arc::new(Mutex::new(tx));
+        let wrapped_tx: Arc<Mutex<Sender<Arc<EncodedFrame>>>> = Arc::new(Mutex::new(tx));
         let wrapped_tx2 = wrapped_tx.clone();
         // Make a new warp filter which provides our state - a tokio sender from
         // which we can spawn more receivers.
         let wrapped_tx_filter = warp::any().map(move || wrapped_tx2.clone());
 
         std::thread::spawn(move || {
-            let routes = warp::path("ws").and(warp::ws()).and(wrapped_tx_filter).map(
-                |ws: warp::ws::Ws, tx: Arc<Mutex<Sender<String>>>| {
-                    ws.on_upgrade(move |socket| {
-                        user_connected(socket, tx.lock().unwrap().subscribe())
-                    })
-                },
-            );
-
-            println!("Starting websocket listener...");
+            let routes = warp::path("ws")
+                .and(warp::ws())
+                .and(warp::query::<HashMap<String, String>>())
+                .and(warp::addr::remote())
+                .and(wrapped_tx_filter)
+                .map(
+                    |ws: warp::ws::Ws,
+                     query: HashMap<String, String>,
+                     address: Option<SocketAddr>,
+                     tx: Arc<Mutex<Sender<Arc<EncodedFrame>>>>| {
+                        // Checked again once connected, but this lets us turn
+                        // clients away with a proper error
+                        if client_count() >= settings::get().ws_max_clients {
+                            warn!("Websocket from {:?} refused, too many clients.", address);
+                            return Box::new(warp::reply::with_status(
+                                "Too many visualiser clients connected",
+                                StatusCode::SERVICE_UNAVAILABLE,
+                            )) as Box<dyn warp::Reply>;
+                        }
+
+                        let format = match query.get("format").map(|x| x.as_str()) {
+                            Some("binary") => Format::Binary,
+                            Some("delta") => Format::Delta,
+                            _ => Format::Json,
+                        };
+                        let max_fps = query
+                            .get("fps")
+                            .and_then(|x| x.parse::<f64>().ok())
+                            .filter(|x| *x > 0.0);
+                        let telemetry = matches!(
+                            query.get("telemetry").map(|x| x.as_str()),
+                            Some("true") | Some("1")
+                        );
+                        let stats = ClientStats {
+                            address,
+                            format,
+                            max_fps,
+                            telemetry,
+                            connected_for: 0.0,
+                            frames_sent: 0,
+                            bytes_sent: 0,
+                            frames_skipped: 0,
+                            frames_lagged: 0,
+                            connected_at: Instant::now(),
+                        };
+                        Box::new(ws.on_upgrade(move |socket| {
+                            let rx = tx.lock().unwrap().subscribe();
+                            user_connected(socket, rx, stats)
+                        }))
+                    },
+                );
+
+            info!("Starting websocket listener...");
             let future = async move {
                 warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
             };
             tokio::runtime::Runtime::new().unwrap().block_on(future);
         });
 
-        // Spawn the JSONifier thread which receives LedUpdates and converts
-        // then to JSON messages.  This is CPU intensive so we don't want it
-        // duplicated in every websocket handler but also don't want to burden
-        // the main thread with it, so it's done by a dedicated thread.
-        let (jsonifier_tx, jsonifier_rx) = mpsc::channel::<LedUpdate>();
-        std::thread::Builder::new()
-            .name("ISOPOD JSONifier".into())
-            .spawn(move || {
-                loop {
-                    let leds = jsonifier_rx.recv().unwrap();
+        // Spawn the encoder thread which receives LedUpdates and converts
+        // them to websocket messages.  This is CPU intensive so we don't want
+        // it duplicated in every websocket handler but also don't want to
+        // burden the main thread with it, so it's done by a dedicated thread.
+        let (encoder_tx, encoder_rx) = mpsc::channel::<Update>();
+        health::spawn_supervised("JSONifier", None, move || {
+            let mut frame_number: u32 = 0;
+            loop {
+                let Update { leds, telemetry } = encoder_rx.recv()?;
+                // JSON is left out while the Pi is too hot, see thermal.rs
+                let json = if JSON_CLIENTS.load(Ordering::Relaxed) > 0 && thermal::ws_json_allowed() {
                     let packet = SimPacket {
                         spines: leds.spines.clone(),
                     };
-                    let packet_json = serde_json::to_string(&packet).unwrap();
-
-                    // We mysteriously get channel closed errors occasionally.
-                    // Not sure why, so just ignore any errors sending into the
-                    // channel.
-                    let _res = wrapped_tx.lock().unwrap().send(packet_json);
+                    Some(serde_json::to_string(&packet)?)
+                } else {
+                    None
+                };
+                let timestamp = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|x| x.as_millis() as u64)
+                    .unwrap_or(0);
+                let mut pixels = Vec::with_capacity(PIXEL_BYTES);
+                for spine in leds.spines.iter() {
+                    for led in spine.iter() {
+                        pixels.extend_from_slice(led);
+                    }
                 }
-            })
-            .unwrap();
+                let frame = EncodedFrame {
+                    frame_number,
+                    timestamp,
+                    binary: frame_encoding::encode_raw(frame_number, timestamp, &pixels),
+                    json,
+                    telemetry: match telemetry {
+                        Some(telemetry) => Some(serde_json::to_string(&TelemetryPacket {
+                            telemetry: &telemetry,
+                        })?),
+                        None => None,
+                    },
+                };
+                frame_number = frame_number.wrapping_add(1);
 
-        Self { tx: jsonifier_tx }
+                // Sending only fails if there are no clients left to receive
+                // the frame, in which case it doesn't matter.
+                let _res = wrapped_tx.lock().unwrap().send(Arc::new(frame));
+            }
+        });
+
+        Self {
+            tx: encoder_tx,
+            last_telemetry: Cell::new(Instant::now()),
+        }
     }
 
-    pub fn led_update(&self, leds: &LedUpdate) -> Result<()> {
-        // TODO: JSONifying the LED state at 60fps takes about 60% of a
-        // raspberry pi 3 core.  Is there a more computationally efficient
-        // way to send this data to the visualiser frontend?
+    /// Is it time to send telemetry?  Gathering it isn't free, so only do so
+    /// when this says it's wanted.
+    pub fn wants_telemetry(&self) -> bool {
+        TELEMETRY_CLIENTS.load(Ordering::Relaxed) > 0
+            && self.last_telemetry.get().elapsed() >= TELEMETRY_INTERVAL
+    }
 
-        self.tx.send(leds.clone())?;
+    pub fn led_update(&self, leds: &LedUpdate, telemetry: Option<Telemetry>) -> Result<()> {
+        // Don't spend any time encoding frames nobody will see
+        if client_count() == 0 {
+            return Ok(());
+        }
+        if telemetry.is_some() {
+            self.last_telemetry.set(Instant::now());
+        }
+        self.tx.send(Update {
+            leds: leds.clone(),
+            telemetry,
+        })?;
 
         Ok(())
     }
 }
 
-async fn user_connected(mut ws: WebSocket, mut rx: Receiver<String>) {
-    println!("Websocket connected.");
+/// Stream frames to a client.  If the client asked for a reduced frame rate,
+/// frames are skipped so that on average it gets at most that many per second.
+/// If it wants telemetry then the latest telemetry is sent before each frame,
+/// when it's changed.
+async fn user_connected(mut ws: WebSocket, mut rx: Receiver<Arc<EncodedFrame>>, stats: ClientStats) {
+    let address = stats.address;
+    let format = stats.format;
+    let telemetry = stats.telemetry;
+    let min_interval = stats.max_fps.map(|x| Duration::from_secs_f64(1.0 / x));
+    let id = match add_client(stats) {
+        Some(id) => id,
+        None => {
+            warn!("Websocket from {:?} refused, too many clients.", address);
+            let _ = ws.close().await;
+            return;
+        }
+    };
+    info!(
+        "Websocket {} connected from {:?}, format {:?}, interval {:?}, telemetry {}.",
+        id, address, format, min_interval, telemetry
+    );
+    if format == Format::Json {
+        JSON_CLIENTS.fetch_add(1, Ordering::Relaxed);
+    }
+    if telemetry {
+        TELEMETRY_CLIENTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Telemetry which hasn't been sent yet because its frame was skipped
+    let mut pending_telemetry: Option<String> = None;
 
+    let mut delta_encoder = DeltaEncoder::default();
+    let mut next_due = Instant::now();
     loop {
         // Wait for an LED state update.
-        let packet_json = rx.recv().await.unwrap();
-
-        let message = warp::ws::Message::text(&packet_json);
+        let frame = match rx.recv().await {
+            Ok(frame) => frame,
+            Err(RecvError::Lagged(missed)) => {
+                // The client isn't keeping up and has missed some frames.
+                // Rather than work through the backlog, skip straight to the
+                // latest frame.
+                let mut lagged = missed;
+                let mut latest = None;
+                while let Ok(frame) = rx.try_recv() {
+                    if let Some(skipped) = latest.replace(frame) {
+                        lagged += 1;
+                        if telemetry && skipped.telemetry.is_some() {
+                            pending_telemetry = skipped.telemetry.clone();
+                        }
+                    }
+                }
+                update_client_stats(id, |stats| stats.frames_lagged += lagged);
+                match latest {
+                    Some(frame) => frame,
+                    None => continue,
+                }
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if telemetry && frame.telemetry.is_some() {
+            pending_telemetry = frame.telemetry.clone();
+        }
 
-        // Send the WS packet to the client
-        match ws.send(message).await {
-            Ok(_) => {}
-            Err(_) => {
-                println!("Websocket disconnected.");
-                break;
+        if let Some(min_interval) = min_interval {
+            let now = Instant::now();
+            if now < next_due {
+                update_client_stats(id, |stats| stats.frames_skipped += 1);
+                continue;
             }
+            // Schedule from when this frame was due rather than now, so
+            // jitter in frame arrival doesn't lower the rate, but don't let
+            // a backlog build up if we've fallen behind.
+            next_due += min_interval;
+            if next_due < now {
+                next_due = now + min_interval;
+            }
+        }
+
+        let message = match format {
+            Format::Binary => warp::ws::Message::binary(frame.binary.clone()),
+            Format::Delta => warp::ws::Message::binary(delta_encoder.encode(
+                frame.frame_number,
+                frame.timestamp,
+                frame.pixels(),
+            )),
+            // JSON may be missing from frames encoded before we connected,
+            // or while the JSONifier is throttled
+            Format::Json => match &frame.json {
+                Some(json) => warp::ws::Message::text(json),
+                None => continue,
+            },
         };
+
+        // Send the WS packets to the client
+        let mut bytes = message.as_bytes().len();
+        let mut result = Ok(());
+        if let Some(telemetry) = pending_telemetry.take() {
+            bytes += telemetry.len();
+            result = ws.send(warp::ws::Message::text(telemetry)).await;
+        }
+        if result.is_ok() {
+            result = ws.send(message).await;
+        }
+        if result.is_err() {
+            break;
+        }
+        update_client_stats(id, |stats| {
+            stats.frames_sent += 1;
+            stats.bytes_sent += bytes as u64;
+        });
+    }
+
+    info!("Websocket {} disconnected.", id);
+    remove_client(id);
+    if format == Format::Json {
+        JSON_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+    }
+    if telemetry {
+        TELEMETRY_CLIENTS.fetch_sub(1, Ordering::Relaxed);
     }
 }
@@ -2,6 +2,13 @@ var scene, camera, renderer, clock, controls, composer, listener;
 
 var spines;
 
+// Everything which makes up the isopod itself, centred on its middle so it
+// can be rotated to match the real sculpture's orientation
+var body;
+// The orientation body is turning towards, from the gravity vector in the
+// telemetry
+var body_target = new THREE.Quaternion();
+
 // Camera presets:
 const camera1_pos = [0.8, -1.5, 1.1];
 const camera1_target = {x: 0, y: 0, z: 1.1};
@@ -73,9 +80,12 @@ function init_scene() {
         emissive: 0x666666,
         emissiveIntensity: 1,
     });
+    body = new THREE.Group();
+    body.position.set(0, 0, 1.1);
+    scene.add(body);
+
     var centre = new THREE.Mesh(centre_geo, centre_mat);
-    centre.position.set(0, 0, 1.1);
-    scene.add(centre);
+    body.add(centre);
 
     var led_geo = new THREE.SphereGeometry(0.007, 8, 8);
     var led_mat = new THREE.MeshLambertMaterial({
@@ -140,11 +150,11 @@ function init_scene() {
         // This position is the centre of the spine cylinder, so it wants to be half the vertex
         spine.position.set(vertex_locations[i][0][0] * scaling,
                            vertex_locations[i][0][1] * scaling,
-                           vertex_locations[i][0][2] * scaling + 1.1);
+                           vertex_locations[i][0][2] * scaling);
 
         light.position.set(0, -1.2, 0);
         spine.add(light);
-        scene.add(spine);
+        body.add(spine);
         spines[i] = spine;
 
         // Place LEDs along the spine
@@ -154,8 +164,8 @@ function init_scene() {
             const led_scaling = 1.1 / 1.9 * j / 60.0;
             led.position.set(vertex_locations[i][0][0] * led_scaling,
                              vertex_locations[i][0][1] * led_scaling,
-                             vertex_locations[i][0][2] * led_scaling + 1.1)
-            scene.add(led);
+                             vertex_locations[i][0][2] * led_scaling)
+            body.add(led);
             spines[i].leds[j] = led;
         }
     }
@@ -204,28 +214,131 @@ function render() {
 }
 
 function update() {
+    // Ease towards the latest orientation rather than jumping 10 times a
+    // second
+    body.quaternion.slerp(body_target, 0.1);
 }
 
 var ws;
-var ws_path = "ws://localhost:3030/ws";
-function init_ws() {
-    ws = new WebSocket(ws_path);
+var ws_host = "localhost";
+// Delta frames are much cheaper for ISOPOD to send and need far less
+// bandwidth.  We fall back to JSON if we get a binary frame in a format we
+// don't understand.
+var ws_format = "delta";
+// Ask for a reduced frame rate with ?fps=15 on the page URL
+var ws_fps = new URLSearchParams(window.location.search).get("fps");
+const FRAME_FORMAT_VERSION = 1;
+const FRAME_TYPE_RAW = 0;
+const FRAME_TYPE_RUN_LENGTH = 1;
+const FRAME_TYPE_DELTA = 2;
+const FRAME_HEADER_LEN = 16;
+
+// The current RGB value of every LED, spine by spine, which delta frames are
+// applied to.  Null until we've had a keyframe.
+var frame_pixels = null;
+
+function ws_path() {
+    var path = "ws://" + ws_host + ":3030/ws?telemetry=true&format=" + ws_format;
+    if (ws_fps) {
+        path += "&fps=" + ws_fps;
+    }
+    return path;
+}
+
+function open_ws() {
+    frame_pixels = null;
+    ws = new WebSocket(ws_path());
+    ws.binaryType = "arraybuffer";
     ws.onclose = retry_ws;
-    ws.onerror = retry_ws;
     ws.onmessage = handle_ws;
 }
 
+function init_ws() {
+    open_ws();
+    ws.onerror = retry_ws;
+}
+
 function changeHost(new_host) {
-    ws_path = "ws://" + new_host + ":3030/ws";
+    ws_host = new_host;
     ws.close();
     retry_ws();
 }
 
-function handle_ws(event) {
-    var status = document.getElementById('status');
-    status.style.color = 'green';
-    status.innerHTML = 'Connected';
-    var spineData = JSON.parse(event.data).spines;
+// Decode a binary frame into frame_pixels, see ws_server.rs and
+// frame_encoding.rs for the format.  Returns false if we don't understand it.
+function decode_binary_frame(buf) {
+    var view = new DataView(buf);
+    if (buf.byteLength < FRAME_HEADER_LEN ||
+            view.getUint8(0) != FRAME_FORMAT_VERSION) {
+        return false;
+    }
+    var num_leds = view.getUint8(2) * view.getUint8(3);
+    var payload = new Uint8Array(buf, FRAME_HEADER_LEN);
+
+    switch (view.getUint8(1)) {
+    case FRAME_TYPE_RAW:
+        if (payload.length < num_leds * 3) {
+            return false;
+        }
+        frame_pixels = payload.slice(0, num_leds * 3);
+        return true;
+
+    case FRAME_TYPE_RUN_LENGTH:
+        var pixels = new Uint8Array(num_leds * 3);
+        var led = 0;
+        for (var i = 0; i + 4 <= payload.length; i += 4) {
+            for (var n = 0; n < payload[i] && led < num_leds; n++, led++) {
+                pixels.set(payload.subarray(i + 1, i + 4), led * 3);
+            }
+        }
+        frame_pixels = pixels;
+        return true;
+
+    case FRAME_TYPE_DELTA:
+        // Nothing to apply it to, so wait for the next keyframe
+        if (frame_pixels === null || frame_pixels.length != num_leds * 3) {
+            return true;
+        }
+        var i = 0;
+        while (i + 3 <= payload.length) {
+            var start = payload[i] | (payload[i + 1] << 8);
+            var count = payload[i + 2];
+            i += 3;
+            if (start + count > num_leds || i + count * 3 > payload.length) {
+                return false;
+            }
+            frame_pixels.set(payload.subarray(i, i + count * 3), start * 3);
+            i += count * 3;
+        }
+        return true;
+
+    default:
+        return false;
+    }
+}
+
+function handle_binary_frame(buf) {
+    if (!decode_binary_frame(buf)) {
+        return false;
+    }
+    if (frame_pixels === null) {
+        return true;
+    }
+
+    var view = new DataView(buf);
+    var num_spines = view.getUint8(2);
+    var leds_per_spine = view.getUint8(3);
+    for(var spine = 0; spine < Math.min(num_spines, 12); spine++) {
+        for(var led = 0; led < Math.min(leds_per_spine, 59); led++) {
+            var i = (spine * leds_per_spine + led) * 3;
+            set_led(spine, led, frame_pixels.subarray(i, i + 3));
+        }
+    }
+    return true;
+}
+
+function handle_json_frame(packet) {
+    var spineData = packet.spines;
     for(var spine = 0; spine < 12; spine++) { // spine
         for(var led = 0; led < 59; led++) { // led
             set_led(spine, led, spineData[spine][led]);
@@ -233,6 +346,65 @@ function handle_ws(event) {
     }
 }
 
+function format_number(x, digits) {
+    return (x === null || x === undefined) ? "?" : x.toFixed(digits);
+}
+
+// Show the telemetry in the HUD and turn the model to match the sculpture
+function handle_telemetry(telemetry) {
+    var imu = telemetry.imu;
+    // The accelerometer reads upwards when at rest, so turn the model so that
+    // reading points up the world Z axis.  There's no reading at all in
+    // simulator mode, so leave the model alone then.
+    var accel = new THREE.Vector3(imu.xa, imu.ya, imu.za);
+    if (accel.length() > 1) {
+        body_target.setFromUnitVectors(accel.normalize(), new THREE.Vector3(0, 0, 1));
+    }
+
+    var lines = [
+        "Pattern: " + telemetry.pattern,
+        "Frame: " + format_number(telemetry.frame_time, 1) + " ms, " +
+            format_number(telemetry.fps, 1) + " fps",
+        "Accel: " + [imu.xa, imu.ya, imu.za].map(x => x.toFixed(2)).join(", "),
+        "Gyro: " + [imu.xg, imu.yg, imu.zg].map(x => x.toFixed(2)).join(", "),
+        "Pi temperature: " + format_number(telemetry.temperature, 1) + " &deg;C",
+    ];
+    if (telemetry.battery) {
+        var battery = telemetry.battery;
+        lines.push("Battery: " + battery.soc.toFixed(0) + "%, " +
+                   battery.voltage.toFixed(2) + " V, " +
+                   battery.current.toFixed(2) + " A");
+    } else {
+        lines.push("Battery: ?");
+    }
+    if (telemetry.gps) {
+        var gps = telemetry.gps;
+        lines.push("GPS: " + gps.latitude.toFixed(5) + ", " +
+                   gps.longitude.toFixed(5) + ", " + gps.satellites + " sats");
+    } else {
+        lines.push("GPS: no fix");
+    }
+    document.getElementById('hud').innerHTML = lines.join("<br>");
+}
+
+function handle_ws(event) {
+    var status = document.getElementById('status');
+    status.style.color = 'green';
+    status.innerHTML = 'Connected (' + ws_format + ')';
+    if (typeof event.data === "string") {
+        var packet = JSON.parse(event.data);
+        if (packet.telemetry) {
+            handle_telemetry(packet.telemetry);
+        } else {
+            handle_json_frame(packet);
+        }
+    } else if (!handle_binary_frame(event.data)) {
+        console.log("Unrecognised binary frame, falling back to JSON");
+        ws_format = "json";
+        ws.close();
+    }
+}
+
 function retry_ws() {
     ws.close();
     console.log("Websocket closed/error, retrying in 1s");
@@ -245,9 +417,7 @@ function retry_ws() {
         ws.onmessage = null;
         ws.close();
 
-        ws = new WebSocket(ws_path);
-        ws.onclose = retry_ws;
-        ws.onmessage = handle_ws;
+        open_ws();
     }, 1000);
 }
 